@@ -23,10 +23,10 @@ INSERT INTO vaulty_users (email, password, username, is_superuser, is_subscribed
     ('def@abc.com', 'test123', 'def123', FALSE, FALSE, FALSE, FALSE, '2020-02-09 19:38:12-05:00', '2020-02-09 19:38:12-05:00', 'James', 'John');
 
 INSERT INTO vaulty_addresses
-    (address, is_active, user_id, email_quota, num_received, max_email_size, storage_quota, storage_used, last_renewal_time, last_update_time, creation_time, storage_backend, storage_token, storage_path, whitelist, is_whitelist_enabled) VALUES
+    (address, is_active, user_id, email_quota, num_received, max_email_size, storage_quota, storage_used, last_renewal_time, last_update_time, creation_time, storage_backend, storage_token, storage_path, whitelist, is_whitelist_enabled, delivery_policy) VALUES
     ('test1@vaulty.net', TRUE, (SELECT id FROM vaulty_users WHERE email='abc@abc.com'), 1000, 0, 20000000,
-     20000000000, 0, '2020-02-09 19:38:12-05:00', '2020-02-09 19:38:12-05:00', '2020-02-09 19:38:12-05:00', 'dropbox', '{{ vaulty_dropbox_token }}', '/vaulty', '{"cyph0nik@gmail.com"}', true),
-    ('test2@vaulty.net', TRUE, (SELECT id FROM vaulty_users WHERE email='def@abc.com'), 100, 0, 20000000, 40000000, 0, '2020-02-09 19:38:12-05:00', '2020-02-09 19:38:12-05:00', '2020-02-09 19:38:12-05:00', 'gdrive', 'testabc', '/vaulty/', '{}', false);
+     20000000000, 0, '2020-02-09 19:38:12-05:00', '2020-02-09 19:38:12-05:00', '2020-02-09 19:38:12-05:00', 'dropbox', '{{ vaulty_dropbox_token }}', '/vaulty', '{"cyph0nik@gmail.com"}', true, 'all'),
+    ('test2@vaulty.net', TRUE, (SELECT id FROM vaulty_users WHERE email='def@abc.com'), 100, 0, 20000000, 40000000, 0, '2020-02-09 19:38:12-05:00', '2020-02-09 19:38:12-05:00', '2020-02-09 19:38:12-05:00', 'gdrive', 'testabc', '/vaulty/', '{}', false, 'all');
 
 INSERT INTO vaulty_mail (user_id, address_id, id, num_attachments,
                       total_size, status, error_msg, creation_time, last_update_time) VALUES
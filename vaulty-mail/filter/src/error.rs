@@ -1,6 +1,6 @@
 #[derive(Debug)]
 pub enum Error {
-    Server(vaulty::api::ServerResult),
+    Server(Box<vaulty::api::ServerResult>),
     Temporary,
     Unexpected,
 }
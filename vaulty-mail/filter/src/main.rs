@@ -43,6 +43,35 @@ struct Opt {
     recipients: Vec<String>,
 }
 
+/// Turn a failed server response into an error.
+///
+/// Errors the user can act on (e.g., an exceeded quota or a full storage
+/// account) are returned with status `UNPROCESSABLE_ENTITY`.
+fn check_status(
+    status: StatusCode,
+    result: ServerResult,
+    email: &vaulty::email::Email,
+) -> Result<ServerResult, Error> {
+    if status.is_success() {
+        return Ok(result);
+    }
+
+    // TODO: Handle all possible error codes
+    if status == StatusCode::UNPROCESSABLE_ENTITY {
+        // Reject the email gracefully
+        log::debug!("{:?}", result);
+        Err(Error::Server(Box::new(result)))
+    } else {
+        // Unexpected server error
+        log::debug!(
+            "Failed to process email {} with: \"{:?}\"",
+            email.uuid,
+            result
+        );
+        Err(Error::Unexpected)
+    }
+}
+
 fn send_attachment(
     remote_addr: &str,
     client: &reqwest::blocking::Client,
@@ -82,15 +111,55 @@ fn send_attachment(
     }
 
     let resp = resp.unwrap();
+    let status = resp.status();
     let result = resp.json::<ServerResult>()?;
 
     log::debug!("{:?}", result);
 
-    Ok(result)
+    check_status(status, result, email)
+}
+
+/// Send the original message for addresses that archive whole messages
+fn send_message(
+    remote_addr: &str,
+    client: &reqwest::blocking::Client,
+    email: &vaulty::email::Email,
+    message: &[u8],
+) -> Result<ServerResult, Error> {
+    log::debug!("Processing message for email: {}", email.uuid);
+
+    let req = client
+        .post(&format!("http://{}:7777/postfix/message", remote_addr))
+        .header(reqwest::header::CONTENT_TYPE, "message/rfc822")
+        .header(reqwest::header::CONTENT_LENGTH, message.len())
+        .header(vaulty::constants::VAULTY_EMAIL_ID, &email.uuid.to_string())
+        .basic_auth(VAULTY_USER.as_str(), Some(VAULTY_PASS.as_str()))
+        .body(message.to_vec());
+
+    let resp = req.send();
+    if let Err(e) = resp {
+        if e.is_timeout() {
+            log::error!("Request to server timed out...: {}", e);
+        }
+
+        return Err(Error::Temporary);
+    }
+
+    let resp = resp.unwrap();
+    let status = resp.status();
+    let result = resp.json::<ServerResult>()?;
+
+    log::debug!("{:?}", result);
+
+    check_status(status, result, email)
 }
 
 /// Transmit this email to the Vaulty processing server
-fn process(remote_addr: &str, mail: &mut vaulty::email::Email) -> Result<ServerResult, Error> {
+fn process(
+    remote_addr: &str,
+    mail: &mut vaulty::email::Email,
+    message: &[u8],
+) -> Result<ServerResult, Error> {
     let client = reqwest::blocking::Client::builder()
         .timeout(Duration::from_secs(REQUEST_TIMEOUT))
         .build()
@@ -114,37 +183,27 @@ fn process(remote_addr: &str, mail: &mut vaulty::email::Email) -> Result<ServerR
     let resp = resp.unwrap();
 
     let status = resp.status();
-    let is_success = status.is_success();
-
-    let mut result = resp.json::<ServerResult>()?;
-
-    if !is_success {
-        // TODO: Handle all possible error codes
-        if status == StatusCode::UNPROCESSABLE_ENTITY {
-            // Reject the email gracefully
-            log::debug!("{:?}", result);
-            return Err(Error::Server(result));
-        } else {
-            // Unexpected server error
-            log::debug!(
-                "Failed to process email {} with: \"{:?}\"",
-                mail.uuid,
-                result
-            );
-            return Err(Error::Unexpected);
-        }
-    }
+    let result = resp.json::<ServerResult>()?;
+    let mut result = check_status(status, result, mail)?;
 
-    let attachments = mail.attachments.take();
+    let wants_message = result.wants_message.unwrap_or(false);
+    let wants_attachments = result.wants_attachments.unwrap_or(true);
+
+    let attachments = mail.attachments.take().filter(|_| wants_attachments);
 
     // Send each attachment one at a time
     if let Some(attachments) = attachments {
         let num_attachments = attachments.len();
+        let mut shared_links = Vec::new();
 
         for (i, a) in attachments.into_iter().enumerate() {
             match send_attachment(&remote_addr, &client, &mail, a) {
                 Err(e) => return Err(e),
                 Ok(r) => {
+                    if let Some(links) = &r.shared_links {
+                        shared_links.extend(links.iter().cloned());
+                    }
+
                     if i == num_attachments - 1 {
                         // The last attachment gets the final result
                         result = r;
@@ -152,6 +211,19 @@ fn process(remote_addr: &str, mail: &mut vaulty::email::Email) -> Result<ServerR
                 }
             }
         }
+
+        if !shared_links.is_empty() {
+            result.shared_links = Some(shared_links);
+        }
+    }
+
+    // The original message goes last, for destinations that store whole
+    // messages. It already includes all attachments.
+    if wants_message {
+        let shared_links = result.shared_links.take();
+
+        result = send_message(remote_addr, &client, mail, message)?;
+        result.shared_links = shared_links;
     }
 
     Ok(result)
@@ -201,7 +273,7 @@ fn main() {
 
     // Process this email
     // If an error is encountered, we send a reply to the user
-    std::process::exit(match process(&remote_addr, &mut mail) {
+    std::process::exit(match process(&remote_addr, &mut mail, email_content.as_bytes()) {
         Err(e) => reply::reply_error(e),
         Ok(r) => {
             if reply_on_success {
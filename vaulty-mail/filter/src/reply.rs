@@ -78,6 +78,9 @@ pub fn reply_error(err: Error) -> i32 {
                 vaulty::Error::QuotaExceeded(_) => Some("5.2.3"),
                 vaulty::Error::SenderNotWhitelisted { .. } => Some("5.7.1"),
                 vaulty::Error::TokenExpired | vaulty::Error::Unauthorized => Some("5.7.8"),
+                vaulty::Error::Storage(vaulty::storage::Error::InsufficientSpace(_)) => {
+                    Some("5.2.2")
+                }
                 _ => Some("5.2.0"),
             },
             None => None,
@@ -95,12 +98,27 @@ pub fn reply_error(err: Error) -> i32 {
 }
 
 pub fn reply_success(mail: &vaulty::email::Email, result: ServerResult) -> i32 {
-    let body = format!(
+    // Older servers only send the backend name
+    let storage_backend = match (result.storage_backend_name, result.storage_backend) {
+        (Some(name), _) => name,
+        (None, backend) => backend.unwrap().to_string(),
+    };
+
+    let mut body = format!(
         "Vaulty successfully uploaded {} attachments to {}!",
         result.num_attachments.unwrap(),
-        result.storage_backend.unwrap()
+        storage_backend
     );
 
+    // List links to the uploaded files, if any were created
+    if let Some(links) = result.shared_links.filter(|l| !l.is_empty()) {
+        body.push_str("\n\nYour files:\n");
+
+        for link in links {
+            body.push_str(&format!("\n{}", link));
+        }
+    }
+
     reply(mail, body);
 
     return 0;
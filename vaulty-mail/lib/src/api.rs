@@ -13,6 +13,27 @@ pub struct ServerResult {
     pub success: bool,
     pub message: Option<String>,
     pub storage_backend: Option<crate::storage::Backend>,
+    /// Name of the storage backend shown to users
+    pub storage_backend_name: Option<String>,
     pub num_attachments: Option<i32>,
+    /// Whether the original message should be sent, for destinations that
+    /// store whole messages
+    pub wants_message: Option<bool>,
+    /// Whether each attachment should be sent, for destinations that store
+    /// them one by one
+    pub wants_attachments: Option<bool>,
+    /// Shared links for the attachments, for backends that create them
+    pub shared_links: Option<Vec<String>>,
+    /// Result for each storage destination of the address, primary first
+    pub destinations: Option<Vec<DestinationResult>>,
+    pub error: Option<crate::Error>,
+}
+
+/// Result of delivering to one storage destination
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DestinationResult {
+    pub storage_backend: crate::storage::Backend,
+    pub storage_path: String,
+    pub success: bool,
     pub error: Option<crate::Error>,
 }
@@ -12,6 +12,9 @@ pub const DEFAULT_VAULTY_PASS: &str = "test123";
 const DEFAULT_PORT: u16 = 7777;
 const DEFAULT_DB_NAME: &str = "vaulty";
 const DEFAULT_DB_USER: &str = "vaulty";
+const DEFAULT_S3_REGION: &str = "us-east-1";
+const DEFAULT_LOCAL_ROOT: &str = "/var/lib/vaulty/storage";
+const DEFAULT_GIT_ROOT: &str = "/var/lib/vaulty/git";
 
 #[derive(Clone, Debug, Default)]
 pub struct Config {
@@ -30,6 +33,41 @@ pub struct Config {
     pub db_name: String,
     pub db_user: String,
     pub db_password: Option<String>,
+
+    /// Dropbox app credentials
+    /// Used to renew short-lived access tokens with a refresh token
+    pub dropbox_app_key: Option<String>,
+    pub dropbox_app_secret: Option<String>,
+
+    /// Overrides the Dropbox API and content base URLs (e.g., for a local
+    /// mock)
+    pub dropbox_base_api: Option<String>,
+    pub dropbox_base_content: Option<String>,
+
+    /// S3 storage config
+    /// If no endpoint is set, AWS is used for the configured region.
+    pub s3_endpoint: Option<String>,
+    pub s3_region: String,
+
+    /// Google Drive storage config
+    /// Overrides the Drive API base URL (e.g., for a local mock)
+    pub gdrive_base_url: Option<String>,
+
+    /// Local storage config
+    /// All local storage paths are resolved under this directory
+    pub local_root: String,
+
+    /// Git storage config
+    /// Repositories of each address live in a folder under this directory
+    pub git_root: String,
+
+    /// OneDrive storage config
+    /// Overrides the Graph API base URL (e.g., for a local mock)
+    pub onedrive_base_url: Option<String>,
+
+    /// Notion destination config
+    /// Overrides the Notion API base URL (e.g., for a local mock)
+    pub notion_base_url: Option<String>,
 }
 
 impl Config {
@@ -90,6 +128,26 @@ impl From<HashMap<String, String>> for Config {
             .unwrap_or(&DEFAULT_DB_USER.to_string())
             .to_string();
         config.db_password = settings.get("db_password").map(String::from);
+        config.dropbox_app_key = settings.get("dropbox_app_key").map(String::from);
+        config.dropbox_app_secret = settings.get("dropbox_app_secret").map(String::from);
+        config.dropbox_base_api = settings.get("dropbox_base_api").map(String::from);
+        config.dropbox_base_content = settings.get("dropbox_base_content").map(String::from);
+        config.s3_endpoint = settings.get("s3_endpoint").map(String::from);
+        config.s3_region = settings
+            .get("s3_region")
+            .unwrap_or(&DEFAULT_S3_REGION.to_string())
+            .to_string();
+        config.gdrive_base_url = settings.get("gdrive_base_url").map(String::from);
+        config.local_root = settings
+            .get("local_root")
+            .unwrap_or(&DEFAULT_LOCAL_ROOT.to_string())
+            .to_string();
+        config.git_root = settings
+            .get("git_root")
+            .unwrap_or(&DEFAULT_GIT_ROOT.to_string())
+            .to_string();
+        config.onedrive_base_url = settings.get("onedrive_base_url").map(String::from);
+        config.notion_base_url = settings.get("notion_base_url").map(String::from);
 
         config
     }
@@ -1,8 +1,11 @@
+use std::convert::TryFrom;
+
 use crate::email::Email;
 
 use chrono::{DateTime, Utc};
 use sqlx::Row;
 
+use crate::delivery::DeliveryPolicy;
 use crate::storage;
 use crate::Error;
 
@@ -30,11 +33,25 @@ const USER_TABLE: &str = "vaulty_users";
 const ADDRESS_TABLE: &str = "vaulty_addresses";
 const MAIL_TABLE: &str = "vaulty_mail";
 const ATTACHMENT_TABLE: &str = "vaulty_attachments";
+const DESTINATION_TABLE: &str = "vaulty_destinations";
 const LOG_TABLE: &str = "vaulty_logs";
 
+/// A storage location that receives the attachments of an address
+#[derive(Clone)]
+pub struct Destination {
+    /// Row in the destinations table, or `None` for the storage of the
+    /// address itself
+    pub id: Option<i32>,
+    pub address_id: i32,
+    pub storage_token: String,
+    pub storage_backend: storage::Backend,
+    pub storage_path: String,
+}
+
 /// Single address row in DB
 #[derive(Clone)]
 pub struct Address {
+    pub id: i32,
     pub address: String,
     pub user_id: i32,
     pub email_quota: i32,
@@ -46,11 +63,42 @@ pub struct Address {
     pub storage_backend: storage::Backend,
     pub storage_path: String,
     pub last_renewal_time: DateTime<Utc>,
+
+    /// Other storage locations that also receive attachments
+    pub destinations: Vec<Destination>,
+    pub delivery_policy: DeliveryPolicy,
 }
 
 impl Address {
     const TABLE_NAME: &'static str = ADDRESS_TABLE;
 
+    /// All storage locations of this address, its own storage first
+    pub fn all_destinations(&self) -> Vec<Destination> {
+        let primary = Destination {
+            id: None,
+            address_id: self.id,
+            storage_token: self.storage_token.clone(),
+            storage_backend: self.storage_backend.clone(),
+            storage_path: self.storage_path.clone(),
+        };
+
+        std::iter::once(primary)
+            .chain(self.destinations.iter().cloned())
+            .collect()
+    }
+
+    /// Replace the storage token of a destination (see `Destination::id`)
+    pub fn set_storage_token(&mut self, destination_id: Option<i32>, token: String) {
+        match destination_id {
+            None => self.storage_token = token,
+            Some(id) => {
+                for destination in self.destinations.iter_mut().filter(|d| d.id == Some(id)) {
+                    destination.storage_token = token.clone();
+                }
+            }
+        }
+    }
+
     /// Validates sender address by checking that it is in the list of
     /// whitelisted senders for this recipient.
     pub async fn validate_sender(
@@ -160,7 +208,10 @@ impl<'a> Client<'a> {
         let row = sqlx::query(&query).fetch_optional(self.db).await?;
 
         if let Some(data) = row {
+            let id = data.get("id");
+
             let address = Address {
+                id,
                 address: data.get("address"),
                 user_id: data.get("user_id"),
                 email_quota: data.get("email_quota"),
@@ -169,9 +220,15 @@ impl<'a> Client<'a> {
                 storage_quota: data.get("storage_quota"),
                 storage_used: data.get("storage_used"),
                 storage_token: data.get("storage_token"),
-                storage_backend: data.get::<String, &str>("storage_backend").into(),
+                storage_backend: storage::Backend::try_from(
+                    data.get::<String, &str>("storage_backend"),
+                )?,
                 storage_path: data.get("storage_path"),
                 last_renewal_time: data.get("last_renewal_time"),
+                destinations: self.get_destinations(id).await?,
+                delivery_policy: DeliveryPolicy::try_from(
+                    data.get::<String, &str>("delivery_policy").as_str(),
+                )?,
             };
 
             Ok(Some(address))
@@ -181,6 +238,111 @@ impl<'a> Client<'a> {
         }
     }
 
+    /// Get the other storage destinations of an address, in the order they
+    /// were added
+    async fn get_destinations(&mut self, address_id: i32) -> Result<Vec<Destination>, Error> {
+        let query = format!(
+            "SELECT * FROM {} WHERE address_id = $1 ORDER BY id",
+            DESTINATION_TABLE
+        );
+
+        let rows = sqlx::query(&query)
+            .bind(address_id)
+            .fetch_all(self.db)
+            .await?;
+
+        let destinations = rows
+            .into_iter()
+            .map(|data| {
+                Ok(Destination {
+                    id: Some(data.get("id")),
+                    address_id,
+                    storage_token: data.get("storage_token"),
+                    storage_backend: storage::Backend::try_from(
+                        data.get::<String, &str>("storage_backend"),
+                    )?,
+                    storage_path: data.get("storage_path"),
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(destinations)
+    }
+
+    /// Replace the storage token of a destination of an address (see
+    /// `Destination::id`)
+    pub async fn update_destination_token(
+        &mut self,
+        address: &str,
+        destination_id: Option<i32>,
+        token: &str,
+    ) -> Result<(), Error> {
+        let id = match destination_id {
+            Some(id) => id,
+            None => return self.update_storage_token(address, token).await,
+        };
+
+        let query = format!(
+            "
+            UPDATE {}
+            SET storage_token = $1
+            WHERE id = $2",
+            DESTINATION_TABLE
+        );
+
+        let _num_rows = sqlx::query(&query)
+            .bind(token)
+            .bind(id)
+            .execute(self.db)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Replace the storage token for an address (e.g., after it was renewed)
+    pub async fn update_storage_token(&mut self, address: &str, token: &str) -> Result<(), Error> {
+        let query = format!(
+            "
+            UPDATE {}
+            SET storage_token = $1
+            WHERE address = $2",
+            ADDRESS_TABLE
+        );
+
+        let _num_rows = sqlx::query(&query)
+            .bind(token)
+            .bind(address)
+            .execute(self.db)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Set the shared link of an uploaded attachment
+    pub async fn update_attachment_shared_link(
+        &mut self,
+        email: &Email,
+        index: u16,
+        shared_link: &str,
+    ) -> Result<(), Error> {
+        let query = format!(
+            "
+            UPDATE {}
+            SET shared_link = $1
+            WHERE mail_id = $2 AND index = $3",
+            ATTACHMENT_TABLE
+        );
+
+        let _num_rows = sqlx::query(&query)
+            .bind(shared_link)
+            .bind(email.uuid)
+            .bind(index as i32)
+            .execute(self.db)
+            .await?;
+
+        Ok(())
+    }
+
     /// Log a message to the logs table
     ///
     /// If this fails, we just log an error internally and proceed.
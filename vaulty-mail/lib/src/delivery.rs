@@ -0,0 +1,155 @@
+use std::convert::TryFrom;
+use std::future::Future;
+
+use bytes::Bytes;
+use futures::channel::mpsc;
+use futures::sink::SinkExt;
+use futures::stream::{Stream, StreamExt};
+
+use crate::storage::client::ByteStream;
+use crate::Error;
+
+// Chunks buffered for each destination before the slowest one holds up the
+// others
+const TEE_BUFFER_SIZE: usize = 4;
+
+/// Decides whether an email was delivered, given the result for each of the
+/// storage destinations of its address
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DeliveryPolicy {
+    /// Every destination must succeed
+    #[default]
+    All,
+    /// At least one destination must succeed
+    Any,
+    /// Only the primary destination must succeed
+    Primary,
+}
+
+impl TryFrom<&str> for DeliveryPolicy {
+    type Error = Error;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        match s {
+            "all" => Ok(Self::All),
+            "any" => Ok(Self::Any),
+            "primary" => Ok(Self::Primary),
+            _ => Err(Error::Generic(format!("Unknown delivery policy: {}", s))),
+        }
+    }
+}
+
+impl DeliveryPolicy {
+    /// Overall result of a delivery, from the result for each destination,
+    /// primary destination first.
+    ///
+    /// On failure, the error of the first destination that failed is
+    /// returned.
+    pub fn outcome<T>(&self, results: &[Result<T, Error>]) -> Result<(), Error> {
+        let delivered = match self {
+            Self::All => !results.is_empty() && results.iter().all(Result::is_ok),
+            Self::Any => results.iter().any(Result::is_ok),
+            Self::Primary => results.first().is_some_and(Result::is_ok),
+        };
+
+        if delivered {
+            return Ok(());
+        }
+
+        let err = results.iter().find_map(|r| r.as_ref().err().cloned());
+        Err(err.unwrap_or_else(|| Error::Generic("No storage destinations".to_string())))
+    }
+}
+
+/// Split a stream into `n` streams that each yield every item.
+///
+/// Items are read from `data` while the returned future is polled, at the
+/// pace of the slowest stream. Streams that are dropped are skipped from
+/// then on.
+pub fn tee(
+    data: impl Stream<Item = Result<Bytes, Error>> + Send + 'static,
+    n: usize,
+) -> (impl Future<Output = ()> + Send, Vec<ByteStream>) {
+    let (senders, receivers): (Vec<_>, Vec<_>) =
+        (0..n).map(|_| mpsc::channel(TEE_BUFFER_SIZE)).unzip();
+
+    let pump = async move {
+        let mut data = Box::pin(data);
+        let mut senders = senders;
+
+        while let Some(item) = data.next().await {
+            let mut open = Vec::with_capacity(senders.len());
+
+            for mut sender in senders {
+                if sender.send(item.clone()).await.is_ok() {
+                    open.push(sender);
+                }
+            }
+
+            if open.is_empty() {
+                break;
+            }
+
+            senders = open;
+        }
+
+        // Dropping the senders ends the streams
+    };
+
+    let streams = receivers
+        .into_iter()
+        .map(|r| Box::pin(r) as ByteStream)
+        .collect();
+
+    (pump, streams)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outcome() {
+        let err = || Err(Error::Generic("failed".to_string()));
+        let results: Vec<Result<(), Error>> = vec![Ok(()), err()];
+
+        assert!(DeliveryPolicy::All.outcome(&results).is_err());
+        assert!(DeliveryPolicy::Any.outcome(&results).is_ok());
+        assert!(DeliveryPolicy::Primary.outcome(&results).is_ok());
+
+        let results: Vec<Result<(), Error>> = vec![err(), Ok(())];
+
+        assert!(DeliveryPolicy::Any.outcome(&results).is_ok());
+        assert!(DeliveryPolicy::Primary.outcome(&results).is_err());
+
+        let results: Vec<Result<(), Error>> = Vec::new();
+
+        assert!(DeliveryPolicy::All.outcome(&results).is_err());
+        assert!(DeliveryPolicy::try_from("some").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tee() {
+        let chunks = (0..10)
+            .map(|i| Ok(Bytes::from(vec![i as u8; 1024])))
+            .collect::<Vec<_>>();
+        let (pump, mut streams) = tee(futures::stream::iter(chunks), 3);
+
+        // One of the destinations gives up early
+        let partial = streams.pop().unwrap();
+        let partial = async move { partial.take(2).collect::<Vec<_>>().await.len() };
+
+        let full = streams.into_iter().map(|s| async move {
+            s.map(|c| c.unwrap().len())
+                .collect::<Vec<_>>()
+                .await
+                .iter()
+                .sum::<usize>()
+        });
+
+        let (_, partial, full) = futures::join!(pump, partial, futures::future::join_all(full));
+
+        assert_eq!(partial, 2);
+        assert_eq!(full, vec![10 * 1024, 10 * 1024]);
+    }
+}
@@ -39,6 +39,9 @@ pub struct Email {
 
     /// Message-ID for this email, if found
     pub message_id: Option<String>,
+
+    /// Date this email was sent, as a Unix timestamp, if found
+    pub date: Option<i64>,
 }
 
 /// A single attachment.
@@ -145,7 +148,7 @@ impl Email {
     }
 
     /// Extract relevant headers from email
-    /// For now, this is limited to Subject and Message-ID
+    /// For now, this is limited to Subject, Message-ID, and Date
     fn parse_headers(&mut self, part: &mailparse::ParsedMail) {
         // NOTE(aksiksi): Can header names be lowercase?
         let headers = part
@@ -153,7 +156,7 @@ impl Email {
             .iter()
             .filter(|h| {
                 let k = h.get_key().unwrap();
-                ["Subject", "Message-ID"].contains(&k.as_str())
+                ["Subject", "Message-ID", "Date"].contains(&k.as_str())
             })
             .map(|h| (h.get_key().unwrap(), h.get_value().ok()));
 
@@ -163,6 +166,8 @@ impl Email {
             } else if k == "Message-ID" {
                 // Extract message ID, if available
                 self.message_id = v.map(|s| s.replace("<", "").replace(">", ""));
+            } else if k == "Date" {
+                self.date = v.and_then(|s| mailparse::dateparse(&s).ok());
             }
         }
     }
@@ -426,6 +431,7 @@ mod test {
 
         assert_eq!(mail.body, "AAFAFAF\n\n");
         assert_eq!(mail.subject.unwrap(), "ABC");
+        assert_eq!(mail.date, Some(1580693736));
 
         // Verify the deterministic UUID
         assert_eq!(
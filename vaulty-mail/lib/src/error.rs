@@ -22,6 +22,12 @@ impl std::fmt::Display for Error {
         match *self {
             Error::Generic(ref msg) => write!(f, "{}", msg),
             Error::Database(ref msg) => write!(f, "{}", msg),
+            Error::Storage(storage::Error::InsufficientSpace(_)) =>
+                write!(f, "The storage account for this Vaulty address is out of space. Please free up some space and resend this email."),
+            Error::Storage(storage::Error::PathConflict(_)) =>
+                write!(f, "A file or folder with the same name already exists at the storage path for this Vaulty address."),
+            Error::Storage(storage::Error::MalformedPath(_)) =>
+                write!(f, "The storage path for this Vaulty address or the name of an attachment is not valid. Please check the storage path in Vaulty."),
             Error::Storage(ref e) => write!(f, "Storage error: {}", e.to_string()),
             Error::QuotaExceeded(ref msg) => write!(f, "{}", msg),
             Error::TokenExpired => write!(f, "The storage account token has expired for this Vaulty address. Please login to Vaulty to refresh the token."),
@@ -1,11 +1,15 @@
+use std::sync::Mutex;
+
 use bytes::Bytes;
 use chrono::offset::Utc;
+use futures::future;
 use futures::stream::Stream;
 
 pub mod api;
 pub mod config;
 pub mod constants;
 pub mod db;
+pub mod delivery;
 pub mod email;
 pub mod mailgun;
 pub mod storage;
@@ -13,41 +17,84 @@ pub mod storage;
 mod error;
 pub use error::Error;
 
-use storage::client::Client;
-use storage::dropbox::client::DropboxClient;
-use storage::Backend;
+use db::Destination;
+use delivery::DeliveryPolicy;
+use storage::client::{Client, Metadata};
+use storage::registry::{BoxedClient, Registry, Target};
 
 pub struct EmailHandler<'a> {
     date: String,
-    storage_token: &'a str,
-    storage_backend: &'a storage::Backend,
-    storage_path: &'a str,
+    registry: &'a Registry,
+    destinations: &'a [Destination],
+    policy: DeliveryPolicy,
+
+    /// State kept by the clients of each destination for earlier
+    /// attachments of the email, by destination ID
+    saved_states: &'a [(Option<i32>, String)],
+
+    /// Storage tokens renewed while handling an email, by destination ID
+    refreshed_tokens: Mutex<Vec<(Option<i32>, String)>>,
+
+    /// State kept by the clients of each destination while handling an
+    /// email, by destination ID
+    email_states: Mutex<Vec<(Option<i32>, String)>>,
+
+    /// Shared links for the files stored, in upload order. An entry is
+    /// `None` if no link was created for the file.
+    shared_links: Mutex<Vec<Option<String>>>,
+
+    /// Result for each destination of the last delivery
+    results: Mutex<Vec<api::DestinationResult>>,
 }
 
 impl<'a> EmailHandler<'a> {
-    pub fn new(token: &'a str, backend: &'a storage::Backend, path: &'a str) -> Self {
+    /// Build a handler delivering to `destinations`, the first of which is
+    /// the primary destination. There must be at least one.
+    pub fn new(
+        registry: &'a Registry,
+        destinations: &'a [Destination],
+        policy: DeliveryPolicy,
+    ) -> Self {
         Self {
-            storage_token: token,
-            storage_backend: backend,
-            storage_path: path,
+            registry,
+            destinations,
+            policy,
+            saved_states: &[],
+            refreshed_tokens: Mutex::new(Vec::new()),
+            email_states: Mutex::new(Vec::new()),
+            shared_links: Mutex::new(Vec::new()),
+            results: Mutex::new(Vec::new()),
 
             // TODO: Figure out user's date from email
             // Will be used for naming scrapbook entries
-            date: Utc::today().format("%F").to_string(),
+            date: Utc::now().format("%F").to_string(),
         }
     }
 
+    /// Resume an email with the state kept by the clients of its earlier
+    /// attachments (see `take_email_states`)
+    pub fn with_email_states(mut self, states: &'a [(Option<i32>, String)]) -> Self {
+        self.saved_states = states;
+        self
+    }
+
     pub async fn handle(
         &self,
         email: &email::Email,
         attachment: Option<impl Stream<Item = Result<Bytes, Error>> + Send + Sync + 'static>,
         attachment_name: String,
-        _attachment_size: usize,
+        attachment_size: usize,
     ) -> Result<(), Error> {
+        let backends = self
+            .destinations
+            .iter()
+            .map(|d| d.storage_backend.to_string())
+            .collect::<Vec<_>>();
+
         log::info!(
             "Handling mail for {} on {}",
             email.recipients[0],
-            self.storage_backend
+            backends.join(", ")
         );
         log::info!("Date in UTC: {}", self.date);
 
@@ -62,33 +109,509 @@ impl<'a> EmailHandler<'a> {
         // - Create a folder for each day
         // etc.
 
-        // 4. Write all attachments to folder via Dropbox API
+        // 4. Write all attachments to each destination at once
         if let Some(attachment) = attachment {
-            let file_path = format!("{}/{}", self.storage_path, attachment_name);
+            self.deliver(
+                email,
+                attachment,
+                &attachment_name,
+                attachment_size,
+                None,
+                false,
+            )
+            .await
+        } else {
+            // Just dump the email (scrapbook mode!)
+            self.handle_email(email).await
+        }
+    }
+
+    /// Handle one attachment of an email.
+    ///
+    /// If the email has several attachments, backends that store them
+    /// together stage it, and store all of them once `is_last` is set. The
+    /// staged attachments are carried over through `take_email_states`.
+    pub async fn handle_attachment(
+        &self,
+        email: &email::Email,
+        attachment: impl Stream<Item = Result<Bytes, Error>> + Send + Sync + 'static,
+        attachment_name: String,
+        attachment_size: usize,
+        is_last: bool,
+    ) -> Result<(), Error> {
+        let batch = if email.num_attachments > 1 {
+            Some(is_last)
+        } else {
+            None
+        };
+
+        self.deliver(
+            email,
+            attachment,
+            &attachment_name,
+            attachment_size,
+            batch,
+            false,
+        )
+        .await
+    }
+
+    /// Handle the original message of an email, for the destinations that
+    /// store whole messages (see `wants_message`)
+    pub async fn handle_message(
+        &self,
+        email: &email::Email,
+        message: impl Stream<Item = Result<Bytes, Error>> + Send + Sync + 'static,
+        message_name: String,
+        message_size: usize,
+    ) -> Result<(), Error> {
+        self.deliver(email, message, &message_name, message_size, None, true)
+            .await
+    }
+
+    /// Whether any destination stores the original message of the email,
+    /// rather than its attachments
+    pub fn wants_message(&self) -> bool {
+        self.stores_messages().any(|m| m)
+    }
+
+    /// Whether any destination stores the attachments of the email one by
+    /// one
+    pub fn wants_attachments(&self) -> bool {
+        self.stores_messages().any(|m| !m)
+    }
+
+    /// Space used in the storage account of the primary destination, if the
+    /// backend can tell
+    pub async fn space_usage(
+        &self,
+        email: &email::Email,
+    ) -> Result<Option<storage::client::SpaceUsage>, Error> {
+        let destination = self.primary();
+
+        let client = self.client(destination, email)?;
+        let result = client.space_usage().await;
+
+        self.keep_client_state(destination, &*client);
+
+        result.map_err(|e| e.into())
+    }
+
+    /// Returns the renewed storage tokens, if any, along with the ID of their
+    /// destination (see `db::Destination::id`).
+    /// The caller is responsible for persisting them for the address.
+    pub fn take_refreshed_tokens(&self) -> Vec<(Option<i32>, String)> {
+        std::mem::take(&mut *self.refreshed_tokens.lock().unwrap())
+    }
+
+    /// Returns the state kept by the clients of each destination, along with
+    /// the ID of the destination. The caller passes it back through
+    /// `with_email_states` for the rest of the email.
+    pub fn take_email_states(&self) -> Vec<(Option<i32>, String)> {
+        std::mem::take(&mut *self.email_states.lock().unwrap())
+    }
+
+    /// Returns a shared link for each file stored, if any, in upload order.
+    /// Storing staged attachments yields links for all of them.
+    pub fn take_shared_links(&self) -> Vec<Option<String>> {
+        std::mem::take(&mut *self.shared_links.lock().unwrap())
+    }
+
+    /// Returns the result for each destination of the last delivery,
+    /// primary destination first
+    pub fn take_destination_results(&self) -> Vec<api::DestinationResult> {
+        std::mem::take(&mut *self.results.lock().unwrap())
+    }
+
+    /// Store the email itself, for backends that keep more than attachments.
+    /// This is a no-op for file storage backends.
+    pub async fn handle_email(&self, email: &email::Email) -> Result<(), Error> {
+        let clients = self.clients(email);
+
+        let uploads = clients.iter().map(|client| async move {
+            match client {
+                Ok(client) => client.upload_email(email).await.map_err(Error::from),
+                Err(e) => Err(e.clone()),
+            }
+        });
+
+        let results = future::join_all(uploads).await;
+
+        self.keep_client_states(&clients);
+
+        self.finish(results.into_iter().map(Some).collect())
+    }
+
+    /// Write an attachment, or the original message if `message` is set, to
+    /// each destination that takes it at once.
+    ///
+    /// With `batch` set, the attachment is staged, and the staged attachments
+    /// are stored if it is `Some(true)`.
+    async fn deliver(
+        &self,
+        email: &email::Email,
+        attachment: impl Stream<Item = Result<Bytes, Error>> + Send + Sync + 'static,
+        attachment_name: &str,
+        attachment_size: usize,
+        batch: Option<bool>,
+        message: bool,
+    ) -> Result<(), Error> {
+        let metadata = Metadata {
+            size: Some(attachment_size as u64),
+            ..Metadata::from(email)
+        };
+        let clients = self.clients(email);
+        let (pump, streams) = delivery::tee(attachment, clients.len());
+
+        let uploads = self.destinations.iter().zip(&clients).zip(streams).map(
+            |((destination, client), data)| {
+                let file_path = format!("{}/{}", destination.storage_path, attachment_name);
+                let metadata = &metadata;
 
-            match self.storage_backend {
-                Backend::Dropbox => {
-                    // Build a Dropbox client
-                    let client = DropboxClient::from_token(self.storage_token);
-                    let result = client.upload_stream(&file_path, attachment).await;
+                async move {
+                    // Destinations get either the message or its attachments
+                    if self.registry.stores_messages(&destination.storage_backend) != message {
+                        return None;
+                    }
 
-                    result.map_err(|e| e.into())
+                    let client = match client {
+                        Ok(client) => client,
+                        Err(e) => return Some(Err(e.clone())),
+                    };
+
+                    Some(upload(&**client, &file_path, data, metadata, batch).await)
                 }
-                Backend::Gdrive => {
-                    // TODO
-                    Ok(())
+            },
+        );
+
+        let (_, results) = futures::join!(pump, future::join_all(uploads));
+
+        // Keep renewed tokens even if the uploads themselves failed
+        self.keep_client_states(&clients);
+
+        // A commit stores the earlier attachments of the email as well, so
+        // files are matched up across destinations from the last one
+        let count = results
+            .iter()
+            .filter_map(|r| r.as_ref().and_then(|r| r.as_ref().ok()))
+            .map(Vec::len)
+            .max()
+            .unwrap_or(0);
+
+        for i in (0..count).rev() {
+            let stored = clients
+                .iter()
+                .zip(&results)
+                .filter_map(|(client, result)| match (client, result) {
+                    (Ok(client), Some(Ok(objects))) if objects.len() > i => {
+                        let object = &objects[objects.len() - 1 - i];
+                        Some((&**client, object.path.clone()))
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+
+            self.create_shared_link(&stored).await;
+        }
+
+        self.finish(results)
+    }
+
+    fn primary(&self) -> &'a Destination {
+        &self.destinations[0]
+    }
+
+    /// Build a client for a destination
+    fn client<'b>(
+        &'b self,
+        destination: &'b Destination,
+        email: &'b email::Email,
+    ) -> Result<BoxedClient<'b>, Error> {
+        let target = Target {
+            address_id: destination.address_id,
+            token: &destination.storage_token,
+            email,
+            state: self
+                .saved_states
+                .iter()
+                .find(|(id, _)| *id == destination.id)
+                .map(|(_, state)| state.as_str()),
+        };
+
+        self.registry
+            .client(&destination.storage_backend, &target)
+            .map_err(|e| e.into())
+    }
+
+    /// Whether each destination stores whole messages, in order
+    fn stores_messages(&self) -> impl Iterator<Item = bool> + '_ {
+        self.destinations
+            .iter()
+            .map(move |d| self.registry.stores_messages(&d.storage_backend))
+    }
+
+    /// Build a client for each destination, in order
+    fn clients<'b>(&'b self, email: &'b email::Email) -> Vec<Result<BoxedClient<'b>, Error>> {
+        self.destinations
+            .iter()
+            .map(|d| self.client(d, email))
+            .collect()
+    }
+
+    /// Record the result for each destination, and apply the delivery
+    /// policy to them. Destinations that did not take part in the delivery
+    /// have no result.
+    fn finish<T>(&self, results: Vec<Option<Result<T, Error>>>) -> Result<(), Error> {
+        let results = self
+            .destinations
+            .iter()
+            .zip(results)
+            .map(|(destination, result)| {
+                if let Some(Err(e)) = &result {
+                    log::warn!(
+                        "Failed to deliver to {} at {}: {}",
+                        destination.storage_backend,
+                        destination.storage_path,
+                        e
+                    );
                 }
-                Backend::S3 => {
-                    // TODO
-                    Ok(())
+
+                result
+            })
+            .collect::<Vec<_>>();
+
+        *self.results.lock().unwrap() = self
+            .destinations
+            .iter()
+            .zip(&results)
+            .filter_map(|(destination, result)| {
+                result.as_ref().map(|result| api::DestinationResult {
+                    storage_backend: destination.storage_backend.clone(),
+                    storage_path: destination.storage_path.clone(),
+                    success: result.is_ok(),
+                    error: result.as_ref().err().cloned(),
+                })
+            })
+            .collect();
+
+        // When the primary destination takes the other part of the email (the
+        // message or its attachments), the first destination that took this
+        // part stands in for it
+        let results = results.into_iter().flatten().collect::<Vec<_>>();
+        self.policy.outcome(&results)
+    }
+
+    /// Create a shared link for an uploaded file, using the first of the
+    /// destinations it was stored in that has links enabled.
+    /// Failing to create a link does not fail the upload.
+    async fn create_shared_link(&self, stored: &[(&(dyn Client + Send + Sync), String)]) {
+        for (client, path) in stored {
+            match client.shared_link(path).await {
+                Ok(Some(url)) => {
+                    self.shared_links.lock().unwrap().push(Some(url));
+                    return;
                 }
+                // Not enabled for this destination
+                Ok(None) => (),
+                Err(e) => log::warn!("Failed to create shared link for {}: {}", path, e),
             }
-        } else {
-            // Just dump the email (scrapbook mode!)
-            Ok(())
         }
+
+        self.shared_links.lock().unwrap().push(None);
+    }
+
+    fn keep_client_states(&self, clients: &[Result<BoxedClient, Error>]) {
+        for (destination, client) in self.destinations.iter().zip(clients) {
+            if let Ok(client) = client {
+                self.keep_client_state(destination, &**client);
+            }
+        }
+    }
+
+    /// Keep a renewed token and the email state of a client, if any
+    fn keep_client_state(&self, destination: &Destination, client: &(dyn Client + Send + Sync)) {
+        if let Some(token) = client.refreshed_token() {
+            let mut tokens = self.refreshed_tokens.lock().unwrap();
+
+            tokens.retain(|(id, _)| *id != destination.id);
+            tokens.push((destination.id, token));
+        }
+
+        if let Some(state) = client.email_state() {
+            let mut states = self.email_states.lock().unwrap();
+
+            states.retain(|(id, _)| *id != destination.id);
+            states.push((destination.id, state));
+        }
+    }
+}
+
+/// Upload a file to a destination, staging it if `batch` is set, and
+/// storing the staged files if it is `Some(true)`.
+/// Returns the files stored.
+async fn upload(
+    client: &(dyn Client + Send + Sync),
+    path: &str,
+    data: storage::client::ByteStream,
+    metadata: &Metadata,
+    batch: Option<bool>,
+) -> Result<Vec<storage::client::StoredObject>, Error> {
+    let object = match batch {
+        Some(_) => client.stage_stream(path, data, metadata).await?,
+        None => client.upload_stream(path, data, metadata).await?,
+    };
+
+    let mut stored = object.into_iter().collect::<Vec<_>>();
+
+    if batch == Some(true) {
+        stored.extend(client.commit_staged().await?);
     }
+
+    Ok(stored)
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    use std::convert::TryFrom;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::config::Config;
+    use crate::storage::client::{ByteStream, ClientFuture, StoredObject};
+    use crate::storage::registry::Entry;
+    use crate::storage::Backend;
+
+    static FILES: AtomicUsize = AtomicUsize::new(0);
+    static MESSAGES: AtomicUsize = AtomicUsize::new(0);
+
+    /// Stores each file it is sent, like most backends
+    struct FileClient;
+
+    impl Client for FileClient {
+        fn upload_stream(
+            &self,
+            path: &str,
+            _data: ByteStream,
+            _metadata: &Metadata,
+        ) -> ClientFuture<'_, Option<StoredObject>> {
+            FILES.fetch_add(1, Ordering::SeqCst);
+
+            let object = StoredObject {
+                id: "file".to_string(),
+                path: path.to_string(),
+                size: 0,
+                hash: None,
+            };
+
+            Box::pin(async move { Ok(Some(object)) })
+        }
+    }
+
+    /// Stores whole messages, like IMAP
+    struct MessageClient;
+
+    impl Client for MessageClient {
+        fn upload_stream(
+            &self,
+            _path: &str,
+            _data: ByteStream,
+            _metadata: &Metadata,
+        ) -> ClientFuture<'_, Option<StoredObject>> {
+            MESSAGES.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async { Ok(None) })
+        }
+    }
+
+    fn files<'a>(_: &'a Config, _: &Target<'a>) -> Result<BoxedClient<'a>, storage::Error> {
+        Ok(Box::new(FileClient))
+    }
+
+    fn messages<'a>(_: &'a Config, _: &Target<'a>) -> Result<BoxedClient<'a>, storage::Error> {
+        Ok(Box::new(MessageClient))
+    }
+
+    fn unreachable<'a>(_: &'a Config, _: &Target<'a>) -> Result<BoxedClient<'a>, storage::Error> {
+        Err(storage::Error::BadEndpoint("unreachable".to_string()))
+    }
+
+    fn destination(id: Option<i32>, backend: &str) -> Destination {
+        Destination {
+            id,
+            address_id: 1,
+            storage_token: "abcd".to_string(),
+            storage_backend: Backend::try_from(backend).unwrap(),
+            storage_path: "/vaulty".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_messages_and_attachments() {
+        let mut registry = Registry::new(Config::default());
+        registry.register("local", Entry::new("Files", files));
+        registry.register("imap", Entry::new("Messages", messages).with_messages());
+
+        let destinations = vec![destination(None, "imap"), destination(Some(1), "local")];
+        let handler = EmailHandler::new(&registry, &destinations, DeliveryPolicy::All);
+
+        let mut email = email::Email::new();
+        email.num_attachments = 1;
+
+        assert!(handler.wants_message());
+        assert!(handler.wants_attachments());
+
+        let data = || futures::stream::iter(vec![Ok(Bytes::from("Hello there!"))]);
+
+        // Attachments only go to the destinations that store files
+        handler
+            .handle_attachment(&email, data(), "a.txt".to_string(), 12, true)
+            .await
+            .unwrap();
+
+        let results = handler.take_destination_results();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].storage_backend.name(), "local");
+        assert_eq!(FILES.load(Ordering::SeqCst), 1);
+        assert_eq!(MESSAGES.load(Ordering::SeqCst), 0);
+
+        // And the message only to the ones that store messages
+        handler
+            .handle_message(&email, data(), "a.eml".to_string(), 12)
+            .await
+            .unwrap();
+
+        let results = handler.take_destination_results();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].storage_backend.name(), "imap");
+        assert_eq!(FILES.load(Ordering::SeqCst), 1);
+        assert_eq!(MESSAGES.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_primary_takes_message() {
+        let mut registry = Registry::new(Config::default());
+        registry.register("imap", Entry::new("Messages", messages).with_messages());
+        registry.register("s3", Entry::new("Unreachable", unreachable));
+
+        let destinations = vec![destination(None, "imap"), destination(Some(1), "s3")];
+        let handler = EmailHandler::new(&registry, &destinations, DeliveryPolicy::Primary);
+        let email = email::Email::new();
+
+        let data = futures::stream::iter(vec![Ok(Bytes::from("Hello there!"))]);
+
+        // The primary destination only takes the message, so the attachment
+        // is lost if the only other destination fails
+        let result = handler
+            .handle_attachment(&email, data, "a.txt".to_string(), 12, true)
+            .await;
+
+        match result {
+            Err(Error::Storage(storage::Error::BadEndpoint(_))) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+
+        let results = handler.take_destination_results();
+        assert_eq!(results.len(), 1);
+        assert!(!results[0].success);
+    }
+}
@@ -0,0 +1,272 @@
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use sha2::Sha256;
+
+use crate::storage::client::Metadata;
+use crate::storage::xml::extract_tag;
+use crate::storage::Error;
+
+pub const AZURE_API_VERSION: &str = "2019-12-12";
+
+// Request timeout, in seconds
+pub(crate) const AZURE_REQUEST_TIMEOUT: u64 = 60;
+
+// Maximum length of a blob index tag value, in characters
+pub(crate) const AZURE_TAG_VALUE_LEN: usize = 256;
+
+// Max number of renamed candidates to try on a name collision
+pub(crate) const AZURE_MAX_RENAMES: usize = 1000;
+
+// Size of each staged block, in bytes
+// Streams smaller than this are uploaded with a single Put Blob.
+pub(crate) const AZURE_BLOCK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Storage account and credentials for an address.
+///
+/// Stored as JSON in the address storage token. `url` is the Blob service
+/// endpoint (`https://<account>.blob.core.windows.net` or, for Azurite,
+/// `http://127.0.0.1:10000/devstoreaccount1`). Exactly one of `key` (a
+/// base64 shared key) or `sas` (a SAS token query string) must be set.
+#[derive(Deserialize, Debug)]
+pub struct Credentials {
+    pub url: String,
+    pub account: String,
+    pub key: Option<String>,
+    pub sas: Option<String>,
+}
+
+pub enum Auth {
+    SharedKey(Vec<u8>),
+    Sas(String),
+}
+
+impl Credentials {
+    pub fn from_token(token: &str) -> Result<Self, Error> {
+        serde_json::from_str(token)
+            .map_err(|e| Error::BadInput(format!("Invalid Azure credentials: {}", e)))
+    }
+
+    pub fn auth(&self) -> Result<Auth, Error> {
+        match (&self.key, &self.sas) {
+            (Some(key), None) => base64::decode(key)
+                .map(Auth::SharedKey)
+                .map_err(|e| Error::BadInput(format!("Invalid Azure shared key: {}", e))),
+            (None, Some(sas)) => Ok(Auth::Sas(sas.trim_start_matches('?').to_string())),
+            _ => Err(Error::BadInput(
+                "Azure credentials require either a shared key or a SAS token".to_string(),
+            )),
+        }
+    }
+}
+
+/// Build a block ID for the `n`-th block.
+/// All block IDs in a blob must have the same length.
+pub fn block_id(n: usize) -> String {
+    base64::encode(&format!("{:08}", n))
+}
+
+pub fn block_list_xml(block_ids: &[String]) -> String {
+    let blocks = block_ids
+        .iter()
+        .map(|id| format!("<Latest>{}</Latest>", id))
+        .collect::<String>();
+
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?><BlockList>{}</BlockList>",
+        blocks
+    )
+}
+
+/// Sign a request with a storage account shared key.
+///
+/// `headers` are the `x-ms-*` headers sent with the request. Returns the
+/// value of the `Authorization` header.
+pub fn sign(
+    account: &str,
+    key: &[u8],
+    method: &str,
+    url: &reqwest::Url,
+    content_length: usize,
+    if_none_match: Option<&str>,
+    headers: &[(&str, String)],
+) -> String {
+    // Content-Length must be empty when zero
+    let content_length = if content_length == 0 {
+        String::new()
+    } else {
+        content_length.to_string()
+    };
+
+    let mut canonical_headers = headers
+        .iter()
+        .map(|(k, v)| (k.to_lowercase(), v.trim().to_string()))
+        .collect::<Vec<(String, String)>>();
+    canonical_headers.sort();
+
+    let canonical_headers = canonical_headers
+        .iter()
+        .map(|(k, v)| format!("{}:{}\n", k, v))
+        .collect::<String>();
+
+    let mut query = url
+        .query_pairs()
+        .map(|(k, v)| (k.to_lowercase(), v.to_string()))
+        .collect::<Vec<(String, String)>>();
+    query.sort();
+
+    let canonical_resource = format!(
+        "/{}{}{}",
+        account,
+        url.path(),
+        query
+            .iter()
+            .map(|(k, v)| format!("\n{}:{}", k, v))
+            .collect::<String>()
+    );
+
+    // Content-Encoding, Content-Language, Content-Length, Content-MD5,
+    // Content-Type, Date, If-Modified-Since, If-Match, If-None-Match,
+    // If-Unmodified-Since, Range
+    let string_to_sign = format!(
+        "{}\n\n\n{}\n\n\n\n\n\n{}\n\n\n{}{}",
+        method,
+        content_length,
+        if_none_match.unwrap_or(""),
+        canonical_headers,
+        canonical_resource
+    );
+
+    let mut mac = Hmac::<Sha256>::new_varkey(key).unwrap();
+    mac.input(string_to_sign.as_bytes());
+    let signature = base64::encode(&mac.result().code());
+
+    format!("SharedKey {}:{}", account, signature)
+}
+
+/// Headers to store email metadata with a new blob.
+///
+/// The modification time is kept as `mtime` metadata (in seconds), and the
+/// email fields as blob index tags.
+pub fn metadata_headers(metadata: &Metadata) -> Vec<(&'static str, String)> {
+    let mut headers = Vec::new();
+
+    if let Some(modified) = metadata.modified {
+        headers.push(("x-ms-meta-mtime", modified.timestamp().to_string()));
+    }
+
+    let tags = metadata
+        .fields()
+        .into_iter()
+        .map(|(k, v)| format!("{}={}", k, tag_value(v)))
+        .collect::<Vec<String>>();
+
+    if !tags.is_empty() {
+        headers.push(("x-ms-tags", tags.join("&")));
+    }
+
+    headers
+}
+
+/// URL-encode a tag value, replacing characters that are not allowed in
+/// tags and truncating it to the maximum length
+fn tag_value(value: &str) -> String {
+    value
+        .chars()
+        .take(AZURE_TAG_VALUE_LEN)
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() => c.to_string(),
+            '-' | '.' | '_' => c.to_string(),
+            ' ' | '+' | '/' | ':' | '=' => format!("%{:02X}", c as u8),
+            _ => "_".to_string(),
+        })
+        .collect()
+}
+
+/// Common headers sent with every request
+pub fn ms_headers(now: &DateTime<Utc>) -> Vec<(&'static str, String)> {
+    vec![
+        (
+            "x-ms-date",
+            now.format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+        ),
+        ("x-ms-version", AZURE_API_VERSION.to_string()),
+    ]
+}
+
+/// Map possible Blob service errors to generic storage backend error
+pub async fn map_status(resp: reqwest::Response) -> Result<reqwest::Response, Error> {
+    let status = resp.status();
+
+    if status.is_success() {
+        return Ok(resp);
+    }
+
+    let body = resp.text().await.unwrap_or_default();
+    let code = extract_tag(&body, "Code").unwrap_or_else(|| status.to_string());
+    let msg = match extract_tag(&body, "Message") {
+        Some(m) => format!("{}: {}", code, m),
+        None => code.clone(),
+    };
+
+    match status {
+        StatusCode::BAD_REQUEST => Err(Error::BadInput(msg)),
+        StatusCode::FORBIDDEN => Err(Error::TokenExpired(msg)),
+        // A conditional write lost to a blob created in the meantime
+        StatusCode::CONFLICT if code == "BlobAlreadyExists" => Err(Error::PathConflict(msg)),
+        StatusCode::PRECONDITION_FAILED => Err(Error::PathConflict(msg)),
+        StatusCode::NOT_FOUND | StatusCode::CONFLICT => Err(Error::BadEndpoint(msg)),
+        StatusCode::SERVICE_UNAVAILABLE => Err(Error::RateLimited(msg)),
+        StatusCode::INTERNAL_SERVER_ERROR if code == "OperationTimedOut" => {
+            Err(Error::RateLimited(msg))
+        }
+        _ => Err(Error::Internal(msg)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_id() {
+        // All IDs must have the same length
+        assert_eq!(block_id(0).len(), block_id(12345).len());
+        assert_eq!(block_id(1), "MDAwMDAwMDE=");
+    }
+
+    #[test]
+    fn test_metadata_headers() {
+        let metadata = Metadata {
+            sender: Some("a@b.com".to_string()),
+            subject: Some("Re: Invoice #1".to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            metadata_headers(&metadata),
+            vec![(
+                "x-ms-tags",
+                "sender=a_b.com&subject=Re%3A%20Invoice%20_1".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_credentials_auth() {
+        let token = r#"{"url": "http://127.0.0.1:10000/devstoreaccount1", "account": "devstoreaccount1", "sas": "?sv=2019-12-12&sig=abc"}"#;
+        let credentials = Credentials::from_token(token).unwrap();
+
+        match credentials.auth().unwrap() {
+            Auth::Sas(sas) => assert_eq!(sas, "sv=2019-12-12&sig=abc"),
+            Auth::SharedKey(_) => panic!("Expected a SAS token"),
+        }
+
+        let token =
+            r#"{"url": "http://127.0.0.1:10000/devstoreaccount1", "account": "devstoreaccount1"}"#;
+        let credentials = Credentials::from_token(token).unwrap();
+
+        assert!(credentials.auth().is_err());
+    }
+}
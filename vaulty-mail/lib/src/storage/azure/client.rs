@@ -0,0 +1,413 @@
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use chrono::Utc;
+use futures::stream::{Stream, StreamExt};
+use reqwest::header::{AUTHORIZATION, IF_NONE_MATCH};
+use reqwest::Method;
+
+use super::api;
+
+use crate::storage::client::{ByteStream, Client, ClientFuture, Metadata, StoredObject};
+use crate::storage::util::autorename;
+use crate::storage::Error;
+
+/// Client for Azure Blob Storage block blobs.
+///
+/// Paths have the form `/container/some/blob`.
+pub struct AzureClient {
+    account: String,
+    auth: api::Auth,
+    base_url: reqwest::Url,
+    client: reqwest::Client,
+}
+
+impl AzureClient {
+    /// Build a client from a JSON storage token (see `api::Credentials`)
+    pub fn from_token(token: &str) -> Result<Self, Error> {
+        let credentials = api::Credentials::from_token(token)?;
+        let auth = credentials.auth()?;
+        let base_url = reqwest::Url::parse(&credentials.url)?;
+
+        if base_url.cannot_be_a_base() {
+            return Err(Error::BadInput(format!(
+                "Invalid Azure URL: {}",
+                credentials.url
+            )));
+        }
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(api::AZURE_REQUEST_TIMEOUT))
+            .build()
+            .unwrap();
+
+        Ok(Self {
+            account: credentials.account,
+            auth,
+            base_url,
+            client,
+        })
+    }
+
+    /// Build the URL for a blob, with optional query parameters
+    fn blob_url(&self, path: &str, query: &[(&str, &str)]) -> Result<reqwest::Url, Error> {
+        let parts = path
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<&str>>();
+
+        // Need at least a container and a blob name
+        if parts.len() < 2 {
+            return Err(Error::BadInput(format!("Invalid Azure path: {}", path)));
+        }
+
+        let mut url = self.base_url.clone();
+
+        // Checked in `from_token`
+        url.path_segments_mut()
+            .unwrap()
+            .pop_if_empty()
+            .extend(parts);
+
+        if let api::Auth::Sas(sas) = &self.auth {
+            url.set_query(Some(sas.as_str()));
+        }
+
+        if !query.is_empty() {
+            url.query_pairs_mut().extend_pairs(query);
+        }
+
+        Ok(url)
+    }
+
+    /// Send a signed request.
+    ///
+    /// `headers` are the `x-ms-*` headers to send. If `create_only` is set,
+    /// the request fails with `PathConflict` if the blob already exists.
+    #[inline]
+    async fn request(
+        &self,
+        method: Method,
+        url: reqwest::Url,
+        body: Bytes,
+        headers: Vec<(&'static str, String)>,
+        create_only: bool,
+    ) -> Result<reqwest::Response, Error> {
+        let mut headers = headers;
+        headers.extend(api::ms_headers(&Utc::now()));
+
+        let if_none_match = if create_only { Some("*") } else { None };
+
+        let authorization = match &self.auth {
+            api::Auth::SharedKey(key) => Some(api::sign(
+                &self.account,
+                key,
+                method.as_str(),
+                &url,
+                body.len(),
+                if_none_match,
+                &headers,
+            )),
+            api::Auth::Sas(_) => None,
+        };
+
+        let mut req = self.client.request(method, url).body(body);
+
+        for (k, v) in headers {
+            req = req.header(k, v);
+        }
+
+        if let Some(authorization) = authorization {
+            req = req.header(AUTHORIZATION, authorization);
+        }
+
+        if let Some(if_none_match) = if_none_match {
+            req = req.header(IF_NONE_MATCH, if_none_match);
+        }
+
+        // Map response into an error if applicable
+        api::map_status(req.send().await?).await
+    }
+
+    /// Check whether a blob exists at `path`
+    pub async fn head_blob(&self, path: &str) -> Result<bool, Error> {
+        let url = self.blob_url(path, &[])?;
+
+        match self
+            .request(Method::HEAD, url, Bytes::new(), Vec::new(), false)
+            .await
+        {
+            Ok(_) => Ok(true),
+            // HEAD responses have no error document to tell a missing blob
+            // from a missing container; the upload itself will report the
+            // latter
+            Err(Error::BadEndpoint(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Find a free blob name for `path`, renaming on collision.
+    /// Candidates before `start` are skipped.
+    async fn free_path(&self, path: &str, start: usize) -> Result<(usize, String), Error> {
+        let (dir, name) = match path.rfind('/') {
+            Some(idx) => path.split_at(idx + 1),
+            None => ("", path),
+        };
+
+        for i in start..api::AZURE_MAX_RENAMES {
+            let candidate = format!("{}{}", dir, autorename(name, i));
+
+            if !self.head_blob(&candidate).await? {
+                return Ok((i, candidate));
+            }
+        }
+
+        Err(Error::PathConflict(format!(
+            "Too many files named {}",
+            path
+        )))
+    }
+
+    /// Upload a blob with a single request.
+    ///
+    /// Fails with `PathConflict` if a blob already exists at `path`.
+    pub async fn put_blob(
+        &self,
+        path: &str,
+        data: Bytes,
+        metadata: &Metadata,
+    ) -> Result<(), Error> {
+        let url = self.blob_url(path, &[])?;
+        let mut headers = vec![("x-ms-blob-type", "BlockBlob".to_string())];
+        headers.extend(api::metadata_headers(metadata));
+        let _resp = self.request(Method::PUT, url, data, headers, true).await?;
+        Ok(())
+    }
+
+    /// Stage a single uncommitted block
+    async fn put_block(&self, path: &str, block_id: &str, data: Bytes) -> Result<(), Error> {
+        let url = self.blob_url(path, &[("comp", "block"), ("blockid", block_id)])?;
+        let _resp = self
+            .request(Method::PUT, url, data, Vec::new(), false)
+            .await?;
+        Ok(())
+    }
+
+    /// Commit staged blocks as the content of the blob.
+    ///
+    /// Never replaces a blob created while the blocks were staged.
+    async fn put_block_list(
+        &self,
+        path: &str,
+        block_ids: &[String],
+        metadata: &Metadata,
+    ) -> Result<(), Error> {
+        let url = self.blob_url(path, &[("comp", "blocklist")])?;
+        let body = api::block_list_xml(block_ids);
+        let headers = api::metadata_headers(metadata);
+        let _resp = self
+            .request(Method::PUT, url, body.into(), headers, true)
+            .await?;
+        Ok(())
+    }
+
+    /// Stage the rest of a stream as blocks, then commit them.
+    /// Returns the size of the blob.
+    ///
+    /// `buf` holds any data already read from the stream.
+    async fn upload_blocks<S>(
+        &self,
+        path: &str,
+        mut buf: BytesMut,
+        mut data: S,
+        metadata: &Metadata,
+    ) -> Result<usize, Error>
+    where
+        S: Stream<Item = Result<Bytes, crate::Error>> + Send + Unpin,
+    {
+        let mut block_ids = Vec::new();
+        let mut size = 0;
+        let mut done = false;
+
+        loop {
+            while buf.len() < api::AZURE_BLOCK_SIZE && !done {
+                match data.next().await {
+                    Some(chunk) => buf.extend_from_slice(&chunk.map_err(map_stream_error)?),
+                    None => done = true,
+                }
+            }
+
+            if buf.is_empty() {
+                break;
+            }
+
+            let len = std::cmp::min(buf.len(), api::AZURE_BLOCK_SIZE);
+            let block = buf.split_to(len).freeze();
+            let block_id = api::block_id(block_ids.len());
+            size += block.len();
+
+            self.put_block(path, &block_id, block).await?;
+            block_ids.push(block_id);
+        }
+
+        self.put_block_list(path, &block_ids, metadata)
+            .await
+            .map(|_| size)
+    }
+}
+
+fn map_stream_error(err: crate::Error) -> Error {
+    Error::Internal(err.to_string())
+}
+
+fn stored_object(path: String, size: usize) -> StoredObject {
+    StoredObject {
+        id: path.clone(),
+        path,
+        size: size as u64,
+        hash: None,
+    }
+}
+
+impl Client for AzureClient {
+    /// Upload a stream as a block blob.
+    ///
+    /// Streams larger than a single block are staged block by block and
+    /// committed with a final block list, so at most one block is buffered
+    /// in memory at a time. Uncommitted blocks are garbage collected by
+    /// Azure if the upload fails.
+    ///
+    /// Existing blobs are never replaced: the file is renamed instead, like
+    /// Dropbox does.
+    fn upload_stream(
+        &self,
+        path: &str,
+        data: ByteStream,
+        metadata: &Metadata,
+    ) -> ClientFuture<'_, Option<StoredObject>> {
+        let path = path.to_string();
+        let metadata = metadata.clone();
+
+        Box::pin(async move {
+            let mut data = Box::pin(data);
+            let mut buf = BytesMut::with_capacity(api::AZURE_BLOCK_SIZE);
+
+            // Small attachments fit in a single block: just put the blob
+            while buf.len() < api::AZURE_BLOCK_SIZE {
+                match data.next().await {
+                    Some(chunk) => buf.extend_from_slice(&chunk.map_err(map_stream_error)?),
+                    None => {
+                        let data = buf.freeze();
+                        let mut start = 0;
+
+                        // Another upload may take the free name before we do
+                        loop {
+                            let (i, path) = self.free_path(&path, start).await?;
+
+                            match self.put_blob(&path, data.clone(), &metadata).await {
+                                Ok(_) => return Ok(Some(stored_object(path, data.len()))),
+                                Err(Error::PathConflict(_)) => start = i + 1,
+                                Err(e) => return Err(e),
+                            }
+                        }
+                    }
+                }
+            }
+
+            // Blocks are staged under the final name, so pick it first
+            let (_, path) = self.free_path(&path, 0).await?;
+
+            self.upload_blocks(&path, buf, data, &metadata)
+                .await
+                .map(|size| Some(stored_object(path, size)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Well-known Azurite development account
+    const AZURITE_TOKEN: &str = r#"{
+        "url": "http://127.0.0.1:10000/devstoreaccount1",
+        "account": "devstoreaccount1",
+        "key": "Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw=="
+    }"#;
+
+    fn get_client() -> AzureClient {
+        let token = std::env::var("AZURE_TOKEN").unwrap_or(AZURITE_TOKEN.to_string());
+        AzureClient::from_token(&token).unwrap()
+    }
+
+    #[test]
+    fn test_blob_url() {
+        let client = AzureClient::from_token(AZURITE_TOKEN).unwrap();
+        let url = client
+            .blob_url("/vaulty/my receipt.pdf", &[("comp", "block")])
+            .unwrap();
+
+        assert_eq!(url.path(), "/devstoreaccount1/vaulty/my%20receipt.pdf");
+        assert_eq!(url.query(), Some("comp=block"));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_upload_stream_collision() {
+        let client = get_client();
+        let mut paths = Vec::new();
+
+        for _ in 0..2 {
+            let data = futures::stream::iter(vec![Ok(Bytes::from("Hello there!"))]);
+            let object = client
+                .upload_stream(
+                    "/vaulty/vaulty_test.txt",
+                    Box::pin(data),
+                    &Metadata::default(),
+                )
+                .await
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(object.size, 12);
+            paths.push(object.path);
+        }
+
+        // The second upload did not replace the first
+        assert_ne!(paths[0], paths[1]);
+        assert!(client.head_blob(&paths[0]).await.unwrap());
+
+        let result = client
+            .put_blob(&paths[0], Bytes::from("Hello!"), &Metadata::default())
+            .await;
+
+        match result {
+            Err(Error::PathConflict(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_upload_stream_blocks() {
+        let client = get_client();
+
+        // Two and a half blocks
+        let chunk = Bytes::from(vec![0u8; api::AZURE_BLOCK_SIZE / 2]);
+        let data = futures::stream::iter((0..5).map(move |_| Ok(chunk.clone())));
+
+        let object = client
+            .upload_stream(
+                "/vaulty/vaulty_blocks.bin",
+                Box::pin(data),
+                &Metadata::default(),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(object.size, (api::AZURE_BLOCK_SIZE * 5 / 2) as u64);
+        assert!(object.path.starts_with("/vaulty/vaulty_blocks"));
+        assert!(client.head_blob(&object.path).await.unwrap());
+    }
+}
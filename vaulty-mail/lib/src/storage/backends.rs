@@ -1,43 +1,95 @@
+use std::convert::TryFrom;
+
 use serde::{Deserialize, Serialize};
 
-/// List of supported storage backends
-/// This enum needs to be kept in sync with the PGSQL enum defined in the
-/// schema
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub enum Backend {
-    Dropbox,
-    Gdrive,
-    S3,
+use super::Error;
+
+/// Names of the storage backends, as stored in the DB.
+/// This list needs to be kept in sync with the PGSQL enum defined in the
+/// schema.
+pub const BACKEND_NAMES: &[&str] = &[
+    "dropbox",
+    "gdrive",
+    "s3",
+    "local",
+    "webdav",
+    "sftp",
+    "onedrive",
+    "azure",
+    "git",
+    "imap",
+    "paperless",
+    "notion",
+];
+
+/// A storage backend, by the name stored in the DB (e.g., `dropbox`).
+///
+/// Names are checked against `BACKEND_NAMES` when parsed. Clients are built
+/// by the factory registered for the name (see `Registry`), which also holds
+/// the name shown to users.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[serde(try_from = "String")]
+pub struct Backend(String);
+
+impl Backend {
+    /// Name of the backend, as stored in the DB
+    pub fn name(&self) -> &str {
+        &self.0
+    }
 }
 
 impl std::fmt::Display for Backend {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match *self {
-            Self::Dropbox => write!(f, "Dropbox"),
-            Self::Gdrive => write!(f, "GDrive"),
-            Self::S3 => write!(f, "S3"),
-        }
+        f.write_str(self.name())
     }
 }
 
-impl From<&str> for Backend {
-    fn from(s: &str) -> Self {
-        if s == "dropbox" {
-            Self::Dropbox
-        } else if s == "gdrive" {
-            Self::Gdrive
-        } else if s == "s3" {
-            Self::S3
+impl TryFrom<&str> for Backend {
+    type Error = Error;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        if BACKEND_NAMES.contains(&s) {
+            Ok(Self(s.to_string()))
         } else {
-            // Default to Dropbox
-            log::error!("Unknown storage backend: {}", s);
-            Self::Dropbox
+            Err(Error::UnknownBackend(s.to_string()))
         }
     }
 }
 
-impl From<String> for Backend {
-    fn from(s: String) -> Self {
-        s.as_str().into()
+impl TryFrom<String> for Backend {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Self::try_from(s.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_try_from() {
+        for name in &["dropbox", "s3", "onedrive", "notion"] {
+            let backend = Backend::try_from(*name).unwrap();
+            assert_eq!(backend.name(), *name);
+        }
+
+        match Backend::try_from("dorpbox") {
+            Err(Error::UnknownBackend(name)) => assert_eq!(name, "dorpbox"),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_serde() {
+        let backend = Backend::try_from("onedrive").unwrap();
+        let json = serde_json::to_string(&backend).unwrap();
+
+        assert_eq!(json, r#""onedrive""#);
+        assert_eq!(serde_json::from_str::<Backend>(&json).unwrap(), backend);
+
+        // Unknown names are rejected when parsed
+        assert!(serde_json::from_str::<Backend>(r#""dorpbox""#).is_err());
     }
 }
@@ -2,17 +2,225 @@ use std::future::Future;
 use std::pin::Pin;
 
 use bytes::Bytes;
+use chrono::{DateTime, TimeZone, Utc};
 use futures::stream::Stream;
 
+use crate::email::Email;
 use crate::storage::Error;
 
 // Definition of future types for async use
 pub type ClientFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, Error>> + Send + 'a>>;
 
+/// Data to upload. Boxed so that clients can be used as trait objects.
+pub type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes, crate::Error>> + Send + Sync>>;
+
+/// Space used in a storage account, in bytes
+#[derive(Clone, Copy, Debug)]
+pub struct SpaceUsage {
+    pub used: u64,
+    /// `None` if the account has no fixed allocation
+    pub allocated: Option<u64>,
+}
+
+impl SpaceUsage {
+    /// Space left in the account, if it has a fixed allocation
+    pub fn available(&self) -> Option<u64> {
+        self.allocated.map(|a| a.saturating_sub(self.used))
+    }
+}
+
+/// Email metadata stored along with an uploaded file.
+///
+/// Backends set the file's modification time from `modified`, and keep the
+/// other fields as native tags or properties where they can.
+#[derive(Clone, Debug, Default)]
+pub struct Metadata {
+    pub modified: Option<DateTime<Utc>>,
+    pub sender: Option<String>,
+    pub subject: Option<String>,
+    pub message_id: Option<String>,
+    /// Size of the file, in bytes, for backends that need it before the
+    /// upload starts
+    pub size: Option<u64>,
+}
+
+impl Metadata {
+    /// Name and value of each field that is set, other than `modified`
+    pub fn fields(&self) -> Vec<(&'static str, &str)> {
+        vec![
+            ("sender", &self.sender),
+            ("subject", &self.subject),
+            ("message_id", &self.message_id),
+        ]
+        .into_iter()
+        .filter_map(|(k, v)| v.as_deref().map(|v| (k, v)))
+        .collect()
+    }
+}
+
+impl From<&Email> for Metadata {
+    fn from(email: &Email) -> Self {
+        Self {
+            modified: email.date.and_then(|t| Utc.timestamp_opt(t, 0).single()),
+            sender: Some(email.sender.clone()).filter(|s| !s.is_empty()),
+            subject: email.subject.clone().filter(|s| !s.is_empty()),
+            message_id: email.message_id.clone(),
+            size: None,
+        }
+    }
+}
+
+/// A file kept by a storage backend
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredObject {
+    /// Backend ID of the file
+    pub id: String,
+    /// Final path of the file, which may differ from the requested path if
+    /// the file was renamed on a collision
+    pub path: String,
+    /// Size, in bytes
+    pub size: u64,
+    /// Content hash, in the backend's own format
+    pub hash: Option<String>,
+}
+
+/// An entry in a storage folder
+#[derive(Clone, Debug, PartialEq)]
+pub enum Entry {
+    Folder { id: String, path: String },
+    File(StoredObject),
+}
+
+impl Entry {
+    pub fn path(&self) -> &str {
+        match self {
+            Entry::Folder { path, .. } => path,
+            Entry::File(object) => &object.path,
+        }
+    }
+}
+
+/// Future for an operation the backend does not support
+fn unsupported<'a, T: 'a>(operation: &str) -> ClientFuture<'a, T> {
+    let msg = format!("{} is not supported by this backend", operation);
+    Box::pin(async move { Err(Error::Unsupported(msg)) })
+}
+
+/// A storage backend.
+///
+/// Only uploads are required. Backends that can manage the files they store
+/// implement the other operations, which fail with `Error::Unsupported` by
+/// default.
 pub trait Client {
+    /// Upload a file.
+    /// Returns the stored file, if the backend reports it.
     fn upload_stream(
         &self,
         path: &str,
-        data: impl Stream<Item = Result<Bytes, crate::Error>> + Send + Sync + 'static,
-    ) -> ClientFuture<'_, ()>;
+        data: ByteStream,
+        metadata: &Metadata,
+    ) -> ClientFuture<'_, Option<StoredObject>>;
+
+    /// Upload one of several attachments of an email, for backends that
+    /// store them together once all are in (see `commit_staged`).
+    /// Uploads the file right away by default.
+    fn stage_stream(
+        &self,
+        path: &str,
+        data: ByteStream,
+        metadata: &Metadata,
+    ) -> ClientFuture<'_, Option<StoredObject>> {
+        self.upload_stream(path, data, metadata)
+    }
+
+    /// Store the attachments staged for the email, once the last one is in.
+    /// Returns all files stored this way for the email, in staging order.
+    /// Does nothing by default.
+    fn commit_staged(&self) -> ClientFuture<'_, Vec<StoredObject>> {
+        Box::pin(async { Ok(Vec::new()) })
+    }
+
+    /// Store the email itself, for backends that keep more than attachments.
+    /// Does nothing by default.
+    fn upload_email(&self, _email: &Email) -> ClientFuture<'_, ()> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Create a folder along with any missing parents
+    fn create_folder(&self, _path: &str) -> ClientFuture<'_, ()> {
+        unsupported("create_folder")
+    }
+
+    /// Get the file or folder at `path`, or `None` if there is none
+    fn stat(&self, _path: &str) -> ClientFuture<'_, Option<Entry>> {
+        unsupported("stat")
+    }
+
+    /// Whether a file or folder exists at `path`
+    fn exists(&self, path: &str) -> ClientFuture<'_, bool> {
+        let stat = self.stat(path);
+        Box::pin(async move { Ok(stat.await?.is_some()) })
+    }
+
+    /// List the entries in a folder
+    fn list(&self, _path: &str) -> ClientFuture<'_, Vec<Entry>> {
+        unsupported("list")
+    }
+
+    /// Delete a file or folder
+    fn delete(&self, _path: &str) -> ClientFuture<'_, ()> {
+        unsupported("delete")
+    }
+
+    /// Space used in the storage account, for backends that can tell.
+    /// Returns `None` by default.
+    fn space_usage(&self) -> ClientFuture<'_, Option<SpaceUsage>> {
+        Box::pin(async { Ok(None) })
+    }
+
+    /// Create a shared link for an uploaded file.
+    /// Returns `None` if the backend does not create links for this account.
+    fn shared_link(&self, _path: &str) -> ClientFuture<'_, Option<String>> {
+        Box::pin(async { Ok(None) })
+    }
+
+    /// The storage token to persist, if it was renewed while using the
+    /// client
+    fn refreshed_token(&self) -> Option<String> {
+        None
+    }
+
+    /// State to keep for the email being handled, if any (e.g., the ID of a
+    /// page created for it, or staged attachments). It is passed back through `Target::state` to
+    /// the clients built for the rest of the email.
+    fn email_state(&self) -> Option<String> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata_from_email() {
+        let email = Email {
+            sender: "a@b.com".to_string(),
+            subject: Some(String::new()),
+            message_id: Some("abcd@b.com".to_string()),
+            date: Some(1580693736),
+            ..Default::default()
+        };
+
+        let metadata = Metadata::from(&email);
+
+        assert_eq!(
+            metadata.modified.unwrap().to_rfc3339(),
+            "2020-02-03T01:35:36+00:00"
+        );
+        assert_eq!(
+            metadata.fields(),
+            vec![("sender", "a@b.com"), ("message_id", "abcd@b.com")]
+        );
+    }
 }
@@ -1,33 +1,266 @@
+use crate::storage::client::{Entry, Metadata, SpaceUsage, StoredObject};
 use crate::storage::Error;
 
 use reqwest::StatusCode;
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+
+use sha2::{Digest, Sha256};
+
+use chrono::{DateTime, Utc};
 
 pub const DROPBOX_ARG_HEADER: &str = "Dropbox-API-Arg";
+pub const DROPBOX_PATH_ROOT_HEADER: &str = "Dropbox-API-Path-Root";
+pub const DROPBOX_SELECT_USER_HEADER: &str = "Dropbox-API-Select-User";
 pub const DROPBOX_BASE_API: &str = "https://api.dropboxapi.com/2/";
 pub const DROPBOX_BASE_CONTENT: &str = "https://content.dropboxapi.com/2/";
+pub const DROPBOX_TOKEN_URL: &str = "https://api.dropboxapi.com/oauth2/token";
 
 // Request timeout, in seconds
 pub(crate) const DROPBOX_REQUEST_TIMEOUT: u64 = 30;
 
-/// Map possible Dropbox API errors to generic storage backend error
-pub fn map_status(resp: reqwest::Response) -> Result<reqwest::Response, Error> {
-    let err = resp.error_for_status_ref();
+// Size of each chunk in an upload session, in bytes
+// Streams larger than a single chunk are uploaded using a session, since
+// `files/upload` is capped at 150 MB.
+pub(crate) const DROPBOX_UPLOAD_CHUNK_SIZE: usize = 8 * 1024 * 1024;
 
-    if let Err(e) = err {
-        let status = e.status().unwrap();
-        let msg = e.to_string();
+// Batch commit job polling interval and deadline, in seconds
+// The deadline is kept well below the filter's request timeout; a job still
+// running by then is checked again when the request is retried.
+pub(crate) const DROPBOX_BATCH_POLL_INTERVAL: u64 = 1;
+pub(crate) const DROPBOX_BATCH_POLL_TIMEOUT: u64 = 5;
 
-        match status {
-            StatusCode::BAD_REQUEST => Err(Error::BadInput(msg)),
-            StatusCode::FORBIDDEN => Err(Error::TokenExpired(msg)),
-            StatusCode::CONFLICT => Err(Error::BadEndpoint(msg)),
-            StatusCode::TOO_MANY_REQUESTS => Err(Error::RateLimited(msg)),
-            _ => Err(Error::Internal(msg)),
+// How long space usage is cached for an account, in seconds
+pub(crate) const DROPBOX_SPACE_USAGE_TTL: u64 = 60;
+
+// Name of the file property template holding email fields
+pub(crate) const DROPBOX_PROPERTY_TEMPLATE: &str = "Vaulty";
+
+// Maximum length of a file property value, in characters
+pub(crate) const DROPBOX_PROPERTY_VALUE_LEN: usize = 1024;
+
+// Size of each block hashed for the content hash, in bytes
+pub(crate) const DROPBOX_HASH_BLOCK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Dropbox tokens for an address.
+///
+/// Stored as JSON in the address storage token. Addresses linked before
+/// refresh tokens were issued hold a bare (long-lived) access token.
+///
+/// For Dropbox Business accounts, `path_root` selects the namespace that
+/// paths are resolved against (e.g., a team space or team folder), and
+/// `select_user` the team member to act as when using a team token.
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct Token {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path_root: Option<PathRoot>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub select_user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shared_links: Option<SharedLinkSettings>,
+}
+
+/// Shared links to create for each uploaded file
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct SharedLinkSettings {
+    #[serde(default)]
+    pub visibility: Option<LinkVisibility>,
+    /// Links expire this many days after the upload, if set
+    #[serde(default)]
+    pub expires_in_days: Option<i64>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkVisibility {
+    Public,
+    TeamOnly,
+}
+
+impl SharedLinkSettings {
+    /// Build the `sharing/create_shared_link_with_settings` arguments for a
+    /// file uploaded at `now`
+    pub fn to_args(&self, path: &str, now: DateTime<Utc>) -> serde_json::Value {
+        let mut settings = serde_json::json!({});
+
+        if let Some(visibility) = &self.visibility {
+            settings["requested_visibility"] = serde_json::json!(visibility);
         }
+
+        if let Some(days) = self.expires_in_days {
+            let expires = now + chrono::Duration::days(days);
+            settings["expires"] =
+                serde_json::json!(expires.format("%Y-%m-%dT%H:%M:%SZ").to_string());
+        }
+
+        serde_json::json!({ "path": path, "settings": settings })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = ".tag", rename_all = "snake_case")]
+pub enum SpaceAllocation {
+    Individual {
+        allocated: u64,
+    },
+    /// Space is shared by all members of the team
+    Team {
+        used: u64,
+        allocated: u64,
+        /// Space each member may use, or 0 if members have no limit
+        #[serde(default)]
+        user_within_team_space_allocated: u64,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SpaceUsageResult {
+    pub used: u64,
+    pub allocation: SpaceAllocation,
+}
+
+impl SpaceUsageResult {
+    pub fn to_space_usage(&self) -> SpaceUsage {
+        match self.allocation {
+            SpaceAllocation::Individual { allocated } => SpaceUsage {
+                used: self.used,
+                allocated: Some(allocated),
+            },
+            SpaceAllocation::Team {
+                used,
+                allocated,
+                user_within_team_space_allocated: limit,
+            } => {
+                let team = SpaceUsage {
+                    used,
+                    allocated: Some(allocated),
+                };
+
+                // The member may run out of their own share first
+                let member = SpaceUsage {
+                    used: self.used,
+                    allocated: Some(limit),
+                };
+
+                if limit > 0 && member.available() < team.available() {
+                    member
+                } else {
+                    team
+                }
+            }
+            SpaceAllocation::Other => SpaceUsage {
+                used: self.used,
+                allocated: None,
+            },
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SharedLinkMetadata {
+    pub url: String,
+}
+
+/// Value of the `Dropbox-API-Path-Root` header
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+#[serde(tag = ".tag", rename_all = "snake_case")]
+pub enum PathRoot {
+    /// The user's home namespace
+    Home,
+    /// The root namespace of the user's team space
+    Root { root: String },
+    /// Any namespace the user has access to, such as a team folder
+    NamespaceId { namespace_id: String },
+}
+
+impl Token {
+    pub fn from_token(token: &str) -> Self {
+        if token.trim_start().starts_with('{') {
+            if let Ok(t) = serde_json::from_str(token) {
+                return t;
+            }
+
+            log::warn!("Invalid Dropbox token JSON, using it as an access token");
+        }
+
+        Self {
+            access_token: token.to_string(),
+            refresh_token: None,
+            path_root: None,
+            select_user: None,
+            shared_links: None,
+        }
+    }
+
+    /// Serialize back into the storage token format
+    pub fn to_token(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RefreshResult {
+    pub access_token: String,
+    pub expires_in: u64,
+}
+
+/// Error body returned by Dropbox API endpoints
+#[derive(Deserialize, Debug)]
+pub struct DropboxError {
+    pub error_summary: String,
+}
+
+/// Map a Dropbox error summary (e.g., `path/insufficient_space/..`) to a
+/// storage backend error, if it is specific enough
+fn map_error_summary(summary: &str, msg: String) -> Option<Error> {
+    let tags = summary.split('/').collect::<Vec<&str>>();
+    let has = |tag: &str| tags.contains(&tag);
+
+    if has("insufficient_space") {
+        Some(Error::InsufficientSpace(msg))
+    } else if has("conflict") {
+        Some(Error::PathConflict(msg))
+    } else if has("malformed_path") || has("disallowed_name") {
+        Some(Error::MalformedPath(msg))
+    } else if has("too_many_write_operations") || has("too_many_requests") {
+        Some(Error::RateLimited(msg))
+    } else if has("not_found") {
+        Some(Error::BadEndpoint(msg))
+    } else if has("expired_access_token") || has("invalid_access_token") {
+        Some(Error::TokenExpired(msg))
     } else {
-        Ok(resp)
+        None
+    }
+}
+
+/// Map possible Dropbox API errors to generic storage backend error
+pub async fn map_status(resp: reqwest::Response) -> Result<reqwest::Response, Error> {
+    let status = resp.status();
+
+    if status.is_success() {
+        return Ok(resp);
+    }
+
+    let body = resp.bytes().await.unwrap_or_default();
+    let summary = serde_json::from_slice::<DropboxError>(&body)
+        .map(|e| e.error_summary)
+        .unwrap_or_default();
+    let msg = format!("{}: {}", status, summary);
+
+    if let Some(err) = map_error_summary(&summary, msg.clone()) {
+        return Err(err);
+    }
+
+    match status {
+        StatusCode::BAD_REQUEST => Err(Error::BadInput(msg)),
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(Error::TokenExpired(msg)),
+        StatusCode::CONFLICT => Err(Error::BadEndpoint(msg)),
+        StatusCode::TOO_MANY_REQUESTS => Err(Error::RateLimited(msg)),
+        _ => Err(Error::Internal(msg)),
     }
 }
 
@@ -35,7 +268,20 @@ pub enum Endpoint {
     ListFolder,
     CreateFolder,
     FileUpload,
+    ListFolderContinue,
     Search,
+    UploadSessionStart,
+    UploadSessionAppend,
+    UploadSessionFinish,
+    UploadSessionFinishBatch,
+    UploadSessionFinishBatchCheck,
+    CreateSharedLink,
+    GetSpaceUsage,
+    ListPropertyTemplates,
+    GetPropertyTemplate,
+    AddPropertyTemplate,
+    GetMetadata,
+    Delete,
 }
 
 #[derive(Deserialize, Debug)]
@@ -60,45 +306,602 @@ pub enum SearchResultEntry {
     },
 }
 
+impl SearchResultEntry {
+    pub fn into_entry(self) -> Entry {
+        match self {
+            SearchResultEntry::Folder {
+                id, path_display, ..
+            } => Entry::Folder {
+                id,
+                path: path_display,
+            },
+            SearchResultEntry::File {
+                id,
+                size,
+                path_display,
+                content_hash,
+                ..
+            } => Entry::File(StoredObject {
+                id,
+                path: path_display,
+                size: size as u64,
+                hash: Some(content_hash),
+            }),
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct SearchResultSingle {
-    metadata: SearchResultEntry,
+    pub metadata: SearchResultEntry,
 }
 
+/// A page of search results.
+/// If `more` is set, the next page starts at `start`.
 #[derive(Deserialize, Debug)]
 pub struct SearchResult {
     pub matches: Vec<SearchResultSingle>,
     pub more: bool,
+    pub start: usize,
 }
 
+/// A page of folder entries.
+/// If `has_more` is set, `cursor` is used to fetch the next page.
 #[derive(Deserialize, Debug)]
 pub struct ListFolderResult {
     pub entries: Vec<SearchResultEntry>,
+    pub cursor: String,
     pub has_more: bool,
 }
 
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct FileUploadResult {
+    pub name: String,
+    pub id: String,
+    pub size: usize,
+    pub server_modified: String,
+    pub path_lower: String,
+    pub path_display: String,
+    pub content_hash: String,
+}
+
+impl FileUploadResult {
+    pub fn into_stored_object(self) -> StoredObject {
+        StoredObject {
+            id: self.id,
+            path: self.path_display,
+            size: self.size as u64,
+            hash: Some(self.content_hash),
+        }
+    }
+
+    /// Check the hash Dropbox computed for the file against ours
+    pub fn verify(&self, content_hash: &str) -> Result<(), Error> {
+        if self.content_hash == content_hash {
+            Ok(())
+        } else {
+            Err(Error::IntegrityError(format!(
+                "Content hash mismatch for {}: expected {}, got {}",
+                self.path_display, content_hash, self.content_hash
+            )))
+        }
+    }
+}
+
+/// Incrementally computes the Dropbox content hash of a file.
+///
+/// The file is split into 4 MB blocks, and the hash is the SHA-256 of the
+/// concatenated SHA-256 digests of each block.
+#[derive(Default)]
+pub struct ContentHasher {
+    overall: Sha256,
+    block: Sha256,
+    block_len: usize,
+}
+
+impl ContentHasher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let n = std::cmp::min(DROPBOX_HASH_BLOCK_SIZE - self.block_len, data.len());
+
+            self.block.input(&data[..n]);
+            self.block_len += n;
+            data = &data[n..];
+
+            if self.block_len == DROPBOX_HASH_BLOCK_SIZE {
+                self.overall.input(self.block.result_reset());
+                self.block_len = 0;
+            }
+        }
+    }
+
+    /// Returns the hex-encoded content hash
+    pub fn finish(mut self) -> String {
+        if self.block_len > 0 {
+            self.overall.input(self.block.result());
+        }
+
+        hex::encode(self.overall.result())
+    }
+}
+
+/// Compute the Dropbox content hash of a buffer
+pub fn content_hash(data: &[u8]) -> String {
+    let mut hasher = ContentHasher::new();
+    hasher.update(data);
+    hasher.finish()
+}
+
 #[derive(Deserialize, Debug)]
-pub struct CreateFolderResult {
+pub struct UploadSessionStartResult {
+    pub session_id: String,
+}
+
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct UploadSessionCursor {
+    pub session_id: String,
+    pub offset: usize,
+}
+
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct CommitInfo {
+    pub path: String,
+    pub mode: String,
+    pub autorename: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_modified: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub property_groups: Vec<PropertyGroup>,
+}
+
+impl CommitInfo {
+    /// Commit a new file at `path`, auto-renaming it if it exists.
+    ///
+    /// The email fields are only attached if a property template is given.
+    pub fn new(path: &str, metadata: &Metadata, template_id: Option<&str>) -> Self {
+        let property_groups = match template_id {
+            Some(template_id) => vec![PropertyGroup {
+                template_id: template_id.to_string(),
+                fields: metadata
+                    .fields()
+                    .into_iter()
+                    .map(|(name, value)| PropertyField {
+                        name: name.to_string(),
+                        value: value.chars().take(DROPBOX_PROPERTY_VALUE_LEN).collect(),
+                    })
+                    .collect(),
+            }],
+            None => Vec::new(),
+        };
+
+        Self {
+            path: path.to_string(),
+            mode: "add".to_string(),
+            autorename: true,
+            client_modified: metadata
+                .modified
+                .map(|t| t.format("%Y-%m-%dT%H:%M:%SZ").to_string()),
+            property_groups,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct PropertyGroup {
+    pub template_id: String,
+    pub fields: Vec<PropertyField>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct PropertyField {
     pub name: String,
+    pub value: String,
 }
 
 #[derive(Deserialize, Debug)]
-pub struct FileUploadResult {
-    name: String,
-    id: String,
-    size: usize,
-    server_modified: String,
-    path_lower: String,
-    path_display: String,
-    content_hash: String,
+pub struct ListTemplateResult {
+    pub template_ids: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GetTemplateResult {
+    pub name: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AddTemplateResult {
+    pub template_id: String,
+}
+
+/// Escape non-ASCII characters in JSON arguments sent in a header.
+/// Header values must be ASCII, so Dropbox expects `\uXXXX` escapes.
+pub fn escape_header_arg(arg: &str) -> String {
+    let mut escaped = String::with_capacity(arg.len());
+
+    for c in arg.chars() {
+        if c.is_ascii() {
+            escaped.push(c);
+        } else {
+            let mut buf = [0u16; 2];
+
+            for unit in c.encode_utf16(&mut buf) {
+                escaped.push_str(&format!("\\u{:04x}", unit));
+            }
+        }
+    }
+
+    escaped
+}
+
+/// Arguments to create the property template holding email fields
+pub fn property_template_args() -> serde_json::Value {
+    let fields = ["sender", "subject", "message_id"]
+        .iter()
+        .map(|name| {
+            serde_json::json!({
+                "name": name,
+                "description": format!("Email {}", name.replace('_', " ")),
+                "type": { ".tag": "string" },
+            })
+        })
+        .collect::<Vec<_>>();
+
+    serde_json::json!({
+        "name": DROPBOX_PROPERTY_TEMPLATE,
+        "description": "Email that a file was attached to",
+        "fields": fields,
+    })
+}
+
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct UploadSessionFinishArg {
+    pub cursor: UploadSessionCursor,
+    pub commit: CommitInfo,
+}
+
+/// A file uploaded to a closed session, waiting to be committed in a batch
+/// along with other files.
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct StagedUpload {
+    pub entry: UploadSessionFinishArg,
+    pub content_hash: String,
+}
+
+/// A batch commit job, along with the files sent with it
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct BatchJob {
+    pub async_job_id: String,
+    pub uploads: Vec<StagedUpload>,
+}
+
+/// Files of an email committed together, kept between the requests for
+/// each of its attachments
+#[derive(Clone, Default, Deserialize, Serialize, Debug)]
+pub struct Batch {
+    /// Files uploaded to closed sessions, not yet sent for commit
+    pub staged: Vec<StagedUpload>,
+    /// Commit job that was still running when last checked
+    pub job: Option<BatchJob>,
+    /// Files committed so far, by requested path
+    pub committed: Vec<(String, FileUploadResult)>,
+}
+
+impl Batch {
+    pub fn is_empty(&self) -> bool {
+        self.staged.is_empty() && self.job.is_none() && self.committed.is_empty()
+    }
+
+    /// Whether a file was committed at the requested path
+    pub fn is_committed(&self, path: &str) -> bool {
+        self.committed.iter().any(|(p, _)| p == path)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = ".tag", rename_all = "snake_case")]
+pub enum FinishBatchEntry {
+    Success(FileUploadResult),
+    Failure { failure: serde_json::Value },
+}
+
+/// Result of launching or checking a batch commit job
+#[derive(Deserialize, Debug)]
+#[serde(tag = ".tag", rename_all = "snake_case")]
+pub enum FinishBatchStatus {
+    AsyncJobId {
+        async_job_id: String,
+    },
+    InProgress,
+    Complete {
+        entries: Vec<FinishBatchEntry>,
+    },
+    #[serde(other)]
+    Failed,
+}
+
+impl FinishBatchEntry {
+    /// Check that the file was committed with the expected content hash
+    pub fn verify(self, content_hash: &str) -> Result<FileUploadResult, Error> {
+        match self {
+            FinishBatchEntry::Success(result) => result.verify(content_hash).map(|_| result),
+            FinishBatchEntry::Failure { failure } => {
+                let summary = tag_summary(&failure);
+                let msg = format!("Batch commit failed: {}", summary);
+
+                Err(map_error_summary(&summary, msg.clone()).unwrap_or(Error::Internal(msg)))
+            }
+        }
+    }
+}
+
+/// Build an error summary from nested union tags, e.g.,
+/// `{".tag": "path", "path": {".tag": "insufficient_space"}}` ->
+/// `path/insufficient_space`
+fn tag_summary(value: &serde_json::Value) -> String {
+    let mut tags = Vec::new();
+    let mut value = value;
+
+    while let Some(tag) = value[".tag"].as_str() {
+        tags.push(tag);
+        value = &value[tag];
+    }
+
+    tags.join("/")
 }
 
 #[inline]
-pub fn build_endpoint_url(endpoint: Endpoint) -> String {
+pub fn build_endpoint_url(base_api: &str, base_content: &str, endpoint: Endpoint) -> String {
     match endpoint {
-        Endpoint::ListFolder => format!("{}{}", DROPBOX_BASE_API, "files/list_folder"),
-        Endpoint::CreateFolder => format!("{}{}", DROPBOX_BASE_API, "files/create_folder_v2"),
-        Endpoint::FileUpload => format!("{}{}", DROPBOX_BASE_CONTENT, "files/upload"),
-        Endpoint::Search => format!("{}{}", DROPBOX_BASE_API, "files/search"),
+        Endpoint::ListFolder => format!("{}{}", base_api, "files/list_folder"),
+        Endpoint::ListFolderContinue => format!("{}{}", base_api, "files/list_folder/continue"),
+        Endpoint::CreateFolder => format!("{}{}", base_api, "files/create_folder_v2"),
+        Endpoint::FileUpload => format!("{}{}", base_content, "files/upload"),
+        Endpoint::Search => format!("{}{}", base_api, "files/search"),
+        Endpoint::UploadSessionStart => {
+            format!("{}{}", base_content, "files/upload_session/start")
+        }
+        Endpoint::UploadSessionAppend => {
+            format!("{}{}", base_content, "files/upload_session/append_v2")
+        }
+        Endpoint::UploadSessionFinish => {
+            format!("{}{}", base_content, "files/upload_session/finish")
+        }
+        Endpoint::UploadSessionFinishBatch => {
+            format!("{}{}", base_api, "files/upload_session/finish_batch")
+        }
+        Endpoint::UploadSessionFinishBatchCheck => {
+            format!("{}{}", base_api, "files/upload_session/finish_batch/check")
+        }
+        Endpoint::CreateSharedLink => {
+            format!("{}{}", base_api, "sharing/create_shared_link_with_settings")
+        }
+        Endpoint::GetSpaceUsage => format!("{}{}", base_api, "users/get_space_usage"),
+        Endpoint::ListPropertyTemplates => {
+            format!("{}{}", base_api, "file_properties/templates/list_for_user")
+        }
+        Endpoint::GetPropertyTemplate => {
+            format!("{}{}", base_api, "file_properties/templates/get_for_user")
+        }
+        Endpoint::AddPropertyTemplate => {
+            format!("{}{}", base_api, "file_properties/templates/add_for_user")
+        }
+        Endpoint::GetMetadata => format!("{}{}", base_api, "files/get_metadata"),
+        Endpoint::Delete => format!("{}{}", base_api, "files/delete_v2"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_token_from_token() {
+        let token = Token::from_token("sl.abcd");
+        assert_eq!(token.access_token, "sl.abcd");
+        assert!(token.refresh_token.is_none());
+
+        let token = Token::from_token(r#"{"access_token": "sl.abcd", "refresh_token": "efgh"}"#);
+        assert_eq!(token.refresh_token.as_deref(), Some("efgh"));
+        assert_eq!(Token::from_token(&token.to_token()).access_token, "sl.abcd");
+    }
+
+    #[test]
+    fn test_token_path_root() {
+        let token = Token::from_token(
+            r#"{"access_token": "sl.abcd", "refresh_token": null, "path_root": {".tag": "namespace_id", "namespace_id": "1234"}, "select_user": "dbmid:abcd"}"#,
+        );
+
+        assert_eq!(
+            token.path_root,
+            Some(PathRoot::NamespaceId {
+                namespace_id: "1234".to_string()
+            })
+        );
+        assert_eq!(token.select_user.as_deref(), Some("dbmid:abcd"));
+
+        // The header value is the serialized path root
+        let header = serde_json::to_string(&token.path_root.unwrap()).unwrap();
+        assert_eq!(header, r#"{".tag":"namespace_id","namespace_id":"1234"}"#);
+
+        // Unset fields are left out of the storage token
+        assert!(!Token::from_token("sl.abcd")
+            .to_token()
+            .contains("path_root"));
+    }
+
+    #[test]
+    fn test_shared_link_args() {
+        let settings: SharedLinkSettings =
+            serde_json::from_str(r#"{"visibility": "team_only", "expires_in_days": 7}"#).unwrap();
+        let now = Utc.with_ymd_and_hms(2020, 8, 1, 12, 0, 0).unwrap();
+
+        let args = settings.to_args("/vaulty/a.txt", now);
+
+        assert_eq!(args["path"], "/vaulty/a.txt");
+        assert_eq!(args["settings"]["requested_visibility"], "team_only");
+        assert_eq!(args["settings"]["expires"], "2020-08-08T12:00:00Z");
+
+        // Nothing is requested by default
+        let settings: SharedLinkSettings = serde_json::from_str("{}").unwrap();
+        assert_eq!(
+            settings.to_args("/a", now)["settings"],
+            serde_json::json!({})
+        );
+    }
+
+    #[test]
+    fn test_commit_info() {
+        let metadata = Metadata {
+            modified: Some(Utc.with_ymd_and_hms(2020, 8, 1, 12, 0, 0).unwrap()),
+            sender: Some("a@b.com".to_string()),
+            ..Default::default()
+        };
+
+        let commit =
+            serde_json::to_value(CommitInfo::new("/a.txt", &metadata, Some("ptid:1"))).unwrap();
+
+        assert_eq!(commit["client_modified"], "2020-08-01T12:00:00Z");
+        assert_eq!(
+            commit["property_groups"],
+            serde_json::json!([{"template_id": "ptid:1", "fields": [{"name": "sender", "value": "a@b.com"}]}])
+        );
+
+        // No template, no properties
+        let commit =
+            serde_json::to_value(CommitInfo::new("/a.txt", &Default::default(), None)).unwrap();
+        assert_eq!(
+            commit,
+            serde_json::json!({"path": "/a.txt", "mode": "add", "autorename": true})
+        );
+    }
+
+    #[test]
+    fn test_escape_header_arg() {
+        let arg = serde_json::json!({ "path": "/vaulty/résumé 📎.pdf" }).to_string();
+        let escaped = escape_header_arg(&arg);
+
+        assert_eq!(
+            escaped,
+            r#"{"path":"/vaulty/r\u00e9sum\u00e9 \ud83d\udcce.pdf"}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&escaped).unwrap(),
+            serde_json::from_str::<serde_json::Value>(&arg).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_space_usage() {
+        let result: SpaceUsageResult = serde_json::from_str(
+            r#"{"used": 100, "allocation": {".tag": "team", "used": 900, "allocated": 1000, "user_within_team_space_allocated": 0}}"#,
+        )
+        .unwrap();
+
+        // Team space is shared, so the team total counts
+        assert_eq!(result.to_space_usage().available(), Some(100));
+
+        // Unless the member's own limit is reached first
+        let result: SpaceUsageResult = serde_json::from_str(
+            r#"{"used": 100, "allocation": {".tag": "team", "used": 500, "allocated": 1000, "user_within_team_space_allocated": 150}}"#,
+        )
+        .unwrap();
+
+        let usage = result.to_space_usage();
+        assert_eq!(usage.used, 100);
+        assert_eq!(usage.available(), Some(50));
+
+        let result: SpaceUsageResult = serde_json::from_str(
+            r#"{"used": 100, "allocation": {".tag": "team", "used": 900, "allocated": 1000, "user_within_team_space_allocated": 500}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(result.to_space_usage().available(), Some(100));
+
+        let result: SpaceUsageResult = serde_json::from_str(
+            r#"{"used": 100, "allocation": {".tag": "individual", "allocated": 1000}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(result.to_space_usage().available(), Some(900));
+    }
+
+    #[test]
+    fn test_map_error_summary() {
+        let map = |s: &str| map_error_summary(s, String::new());
+
+        match map("path/insufficient_space/..") {
+            Some(Error::InsufficientSpace(_)) => (),
+            e => panic!("Unexpected error: {:?}", e),
+        }
+
+        match map("path/conflict/file/...") {
+            Some(Error::PathConflict(_)) => (),
+            e => panic!("Unexpected error: {:?}", e),
+        }
+
+        match map("path/malformed_path/.") {
+            Some(Error::MalformedPath(_)) => (),
+            e => panic!("Unexpected error: {:?}", e),
+        }
+
+        match map("too_many_write_operations/..") {
+            Some(Error::RateLimited(_)) => (),
+            e => panic!("Unexpected error: {:?}", e),
+        }
+
+        assert!(map("other/...").is_none());
+        assert!(map("").is_none());
+    }
+
+    #[test]
+    fn test_finish_batch_entry() {
+        let status: FinishBatchStatus = serde_json::from_str(
+            r#"{".tag": "complete", "entries": [{".tag": "failure", "failure": {".tag": "path", "path": {".tag": "insufficient_space"}}}]}"#,
+        )
+        .unwrap();
+
+        match status {
+            FinishBatchStatus::Complete { entries } => {
+                match entries.into_iter().next().unwrap().verify("") {
+                    Err(Error::InsufficientSpace(_)) => (),
+                    r => panic!("Unexpected result: {:?}", r),
+                }
+            }
+            s => panic!("Unexpected status: {:?}", s),
+        }
+
+        let status: FinishBatchStatus = serde_json::from_str(r#"{".tag": "failed"}"#).unwrap();
+        match status {
+            FinishBatchStatus::Failed => (),
+            s => panic!("Unexpected status: {:?}", s),
+        }
+    }
+
+    #[test]
+    fn test_content_hash() {
+        let data = vec![7u8; DROPBOX_HASH_BLOCK_SIZE + 10];
+
+        let mut block_hashes = Vec::new();
+        block_hashes.extend_from_slice(&Sha256::digest(&data[..DROPBOX_HASH_BLOCK_SIZE]));
+        block_hashes.extend_from_slice(&Sha256::digest(&data[DROPBOX_HASH_BLOCK_SIZE..]));
+        let expected = hex::encode(Sha256::digest(&block_hashes));
+
+        assert_eq!(content_hash(&data), expected);
+
+        // Chunks that straddle block boundaries give the same hash
+        let mut hasher = ContentHasher::new();
+        for chunk in data.chunks(3 * 1024 * 1024) {
+            hasher.update(chunk);
+        }
+        assert_eq!(hasher.finish(), expected);
+
+        // No blocks at all for an empty file
+        assert_eq!(content_hash(b""), hex::encode(Sha256::digest(b"")));
     }
 }
@@ -1,58 +1,245 @@
-use std::time::Duration;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use bytes::Bytes;
-use futures::stream::Stream;
+use bytes::{Bytes, BytesMut};
+use chrono::Utc;
+use futures::stream::{self, BoxStream, Stream, StreamExt, TryStreamExt};
+use lazy_static::lazy_static;
 use reqwest::header::CONTENT_TYPE;
+use sha2::{Digest, Sha256};
 
 use super::api;
 
-use crate::storage::client::{Client, ClientFuture};
+use crate::storage::client::{
+    ByteStream, Client, ClientFuture, Entry, Metadata, SpaceUsage, StoredObject,
+};
 use crate::storage::Error;
 
+lazy_static! {
+    /// Recently fetched space usage, by cache key (see `cache_key`)
+    static ref SPACE_USAGE_CACHE: Mutex<HashMap<String, (Instant, SpaceUsage)>> =
+        Mutex::new(HashMap::new());
+
+    /// ID of the property template holding email fields, by cache key
+    static ref PROPERTY_TEMPLATE_CACHE: Mutex<HashMap<String, String>> =
+        Mutex::new(HashMap::new());
+}
+
 pub struct DropboxClient<'a> {
-    token: &'a str,
+    token: Mutex<api::Token>,
+    app_key: Option<&'a str>,
+    app_secret: Option<&'a str>,
+    refreshed: AtomicBool,
+    base_api: String,
+    base_content: String,
+    token_url: String,
     client: reqwest::Client,
+
+    /// Files of the email committed together (see `stage_upload`)
+    batch: Mutex<api::Batch>,
+    batch_timeout: Duration,
 }
 
 impl<'a> DropboxClient<'a> {
-    pub fn from_token(token: &'a str) -> Self {
+    pub fn from_token(token: &str) -> Self {
         let client = reqwest::Client::builder()
             .timeout(Duration::from_secs(api::DROPBOX_REQUEST_TIMEOUT))
             .build()
             .unwrap();
         Self {
-            token: token,
+            token: Mutex::new(api::Token::from_token(token)),
+            app_key: None,
+            app_secret: None,
+            refreshed: AtomicBool::new(false),
+            base_api: api::DROPBOX_BASE_API.to_string(),
+            base_content: api::DROPBOX_BASE_CONTENT.to_string(),
+            token_url: api::DROPBOX_TOKEN_URL.to_string(),
             client: client,
+            batch: Mutex::new(api::Batch::default()),
+            batch_timeout: Duration::from_secs(api::DROPBOX_BATCH_POLL_TIMEOUT),
+        }
+    }
+
+    /// Resume the batch of an email, as returned by `email_state`
+    pub fn with_batch(self, state: &str) -> Result<Self, Error> {
+        *self.batch.lock().unwrap() = serde_json::from_str(state)?;
+        Ok(self)
+    }
+
+    /// Override the API and content base URLs (e.g., to point at a mock
+    /// server).
+    ///
+    /// The OAuth2 token endpoint is resolved against the API host.
+    pub fn with_base_urls(mut self, base_api: &str, base_content: &str) -> Result<Self, Error> {
+        let with_slash = |url: &str| {
+            if url.ends_with('/') {
+                url.to_string()
+            } else {
+                format!("{}/", url)
+            }
+        };
+
+        self.base_api = with_slash(base_api);
+        self.base_content = with_slash(base_content);
+        self.token_url = reqwest::Url::parse(&self.base_api)?
+            .join("/oauth2/token")?
+            .to_string();
+
+        // Catch bad content URLs early rather than on upload
+        reqwest::Url::parse(&self.base_content)?;
+
+        Ok(self)
+    }
+
+    /// Set the app key and secret used to renew expired access tokens
+    pub fn with_app_credentials(mut self, app_key: &'a str, app_secret: &'a str) -> Self {
+        self.app_key = Some(app_key);
+        self.app_secret = Some(app_secret);
+        self
+    }
+
+    /// Returns the storage token to persist if the access token was renewed
+    pub fn refreshed_token(&self) -> Option<String> {
+        if self.refreshed.load(Ordering::SeqCst) {
+            Some(self.token.lock().unwrap().to_token())
+        } else {
+            None
         }
     }
 
     #[inline]
-    async fn request(
+    fn access_token(&self) -> String {
+        self.token.lock().unwrap().access_token.clone()
+    }
+
+    /// Key for data cached across clients of the same account.
+    ///
+    /// A team token acts as different members and namespaces depending on
+    /// `select_user` and `path_root`, so those are part of the key. The
+    /// access token is hashed rather than kept in the cache as is.
+    fn cache_key(&self) -> Result<String, Error> {
+        let token = self.token.lock().unwrap();
+
+        let mut hasher = Sha256::new();
+        hasher.input(self.base_api.as_bytes());
+        hasher.input(b"\0");
+        hasher.input(token.access_token.as_bytes());
+        hasher.input(b"\0");
+        hasher.input(serde_json::to_string(&token.path_root)?.as_bytes());
+        hasher.input(b"\0");
+        hasher.input(serde_json::to_string(&token.select_user)?.as_bytes());
+
+        Ok(hex::encode(hasher.result()))
+    }
+
+    /// Get a new access token using the refresh token
+    pub async fn refresh(&self) -> Result<(), Error> {
+        let refresh_token = self.token.lock().unwrap().refresh_token.clone();
+
+        let (refresh_token, app_key, app_secret) =
+            match (refresh_token, self.app_key, self.app_secret) {
+                (Some(t), Some(k), Some(s)) => (t, k, s),
+                _ => {
+                    return Err(Error::TokenExpired(
+                        "No Dropbox refresh token or app credentials".to_string(),
+                    ))
+                }
+            };
+
+        let params = [
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token.as_str()),
+            ("client_id", app_key),
+            ("client_secret", app_secret),
+        ];
+
+        let req = self
+            .client
+            .post(reqwest::Url::parse(&self.token_url)?)
+            .form(&params);
+
+        // A rejected refresh token means the user has to link Dropbox again
+        let resp = match api::map_status(req.send().await?).await {
+            Err(Error::BadInput(msg)) => return Err(Error::TokenExpired(msg)),
+            r => r?,
+        };
+
+        let result: api::RefreshResult = serde_json::from_slice(&resp.bytes().await?)?;
+
+        log::info!(
+            "Renewed Dropbox access token (expires in {}s)",
+            result.expires_in
+        );
+
+        self.token.lock().unwrap().access_token = result.access_token;
+        self.refreshed.store(true, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    async fn send(
         &self,
-        endpoint: api::Endpoint,
-        body: reqwest::Body,
+        url: &str,
+        body: Bytes,
         args: Option<&str>,
         content_type: Option<&str>,
     ) -> Result<bytes::Bytes, Error> {
-        let url = api::build_endpoint_url(endpoint);
-
         let mut req = self
             .client
-            .post(reqwest::Url::parse(&url)?)
-            .bearer_auth(&self.token)
+            .post(reqwest::Url::parse(url)?)
+            .bearer_auth(self.access_token())
             .header(CONTENT_TYPE, content_type.unwrap_or("application/json"))
             .body(body);
 
         if let Some(v) = args {
-            req = req.header(api::DROPBOX_ARG_HEADER, v);
+            req = req.header(api::DROPBOX_ARG_HEADER, api::escape_header_arg(v));
+        }
+
+        // Target a team space or another member's namespace, if configured
+        let (path_root, select_user) = {
+            let token = self.token.lock().unwrap();
+            (token.path_root.clone(), token.select_user.clone())
+        };
+
+        if let Some(root) = path_root {
+            req = req.header(api::DROPBOX_PATH_ROOT_HEADER, serde_json::to_string(&root)?);
+        }
+
+        if let Some(member) = select_user {
+            req = req.header(api::DROPBOX_SELECT_USER_HEADER, member);
         }
 
         // Map response into an error if applicable
-        let resp = api::map_status(req.send().await?);
+        let resp = api::map_status(req.send().await?).await;
 
         Ok(resp?.bytes().await?)
     }
 
+    /// Send a request, renewing the access token and retrying once if it
+    /// has expired
+    #[inline]
+    async fn request(
+        &self,
+        endpoint: api::Endpoint,
+        body: Bytes,
+        args: Option<&str>,
+        content_type: Option<&str>,
+    ) -> Result<bytes::Bytes, Error> {
+        let url = api::build_endpoint_url(&self.base_api, &self.base_content, endpoint);
+
+        match self.send(&url, body.clone(), args, content_type).await {
+            Err(Error::TokenExpired(msg)) if self.app_key.is_some() => {
+                log::info!("Dropbox access token expired: {}", msg);
+
+                self.refresh().await?;
+                self.send(&url, body, args, content_type).await
+            }
+            r => r,
+        }
+    }
+
     pub async fn list_folder(&self, path: &str) -> Result<api::ListFolderResult, Error> {
         let body = serde_json::json!({ "path": path }).to_string();
         let resp = self
@@ -71,12 +258,43 @@ impl<'a> DropboxClient<'a> {
         Ok(())
     }
 
-    /// Upload a file to a user's Dropbox
-    /// This function does not return any API metadata
-    pub async fn upload(&self, path: &str, data: Vec<u8>) -> Result<(), Error> {
-        // Auto-rename the attachment if it exists
-        let args = serde_json::json!({"path": path, "autorename": true}).to_string();
+    /// Get metadata for the file or folder at `path`, or `None` if there is
+    /// nothing there
+    pub async fn get_metadata(&self, path: &str) -> Result<Option<api::SearchResultEntry>, Error> {
+        let body = serde_json::json!({ "path": path }).to_string();
+
+        match self
+            .request(api::Endpoint::GetMetadata, body.into(), None, None)
+            .await
+        {
+            Ok(resp) => Ok(Some(serde_json::from_slice(&resp)?)),
+            Err(Error::BadEndpoint(msg)) if msg.contains("path/not_found") => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Delete a file, or a folder with all its contents
+    pub async fn delete_path(&self, path: &str) -> Result<(), Error> {
+        let body = serde_json::json!({ "path": path }).to_string();
         let _resp = self
+            .request(api::Endpoint::Delete, body.into(), None, None)
+            .await?;
+        Ok(())
+    }
+
+    /// Upload a file to a user's Dropbox
+    ///
+    /// The content hash Dropbox returns is checked against the data sent.
+    pub async fn upload(
+        &self,
+        path: &str,
+        data: Vec<u8>,
+        metadata: &Metadata,
+    ) -> Result<api::FileUploadResult, Error> {
+        let content_hash = api::content_hash(&data);
+
+        let args = serde_json::to_string(&self.commit_info(path, metadata).await)?;
+        let resp = self
             .request(
                 api::Endpoint::FileUpload,
                 data.into(),
@@ -84,53 +302,1277 @@ impl<'a> DropboxClient<'a> {
                 Some("application/octet-stream"),
             )
             .await?;
-        Ok(())
+
+        let result: api::FileUploadResult = serde_json::from_slice(&resp)?;
+        result.verify(&content_hash)?;
+
+        Ok(result)
     }
 
     pub async fn search(&self, path: &str, query: &str) -> Result<api::SearchResult, Error> {
-        let data = serde_json::json!({"path": path, "query": query}).to_string();
+        self.search_from(path, query, 0).await
+    }
+
+    /// Fetch the page of search results starting at `start`
+    pub async fn search_from(
+        &self,
+        path: &str,
+        query: &str,
+        start: usize,
+    ) -> Result<api::SearchResult, Error> {
+        let data = serde_json::json!({"path": path, "query": query, "start": start}).to_string();
         let resp = self
             .request(api::Endpoint::Search, data.into(), None, None)
             .await?;
         serde_json::from_slice(&resp).map_err(|e| e.into())
     }
+
+    /// Fetch the next page of a folder listing
+    pub async fn list_folder_continue(&self, cursor: &str) -> Result<api::ListFolderResult, Error> {
+        let body = serde_json::json!({ "cursor": cursor }).to_string();
+        let resp = self
+            .request(api::Endpoint::ListFolderContinue, body.into(), None, None)
+            .await?;
+        serde_json::from_slice(&resp).map_err(|e| e.into())
+    }
+
+    /// Stream all entries in a folder, fetching pages as needed
+    pub fn list_folder_entries<'b>(
+        &'b self,
+        path: &'b str,
+    ) -> BoxStream<'b, Result<api::SearchResultEntry, Error>> {
+        let pages = stream::unfold(Page::<String>::First, move |page| async move {
+            let result = match page {
+                Page::First => self.list_folder(path).await,
+                Page::Next(cursor) => self.list_folder_continue(cursor.as_str()).await,
+                Page::Done => return None,
+            };
+
+            Some(match result {
+                Ok(r) if r.has_more => (Ok(r.entries), Page::Next(r.cursor)),
+                Ok(r) => (Ok(r.entries), Page::Done),
+                Err(e) => (Err(e), Page::Done),
+            })
+        });
+
+        flatten_pages(pages).boxed()
+    }
+
+    /// Stream all search results, fetching pages as needed
+    pub fn search_entries<'b>(
+        &'b self,
+        path: &'b str,
+        query: &'b str,
+    ) -> BoxStream<'b, Result<api::SearchResultEntry, Error>> {
+        let pages = stream::unfold(Page::First, move |page| async move {
+            let result = match page {
+                Page::First => self.search(path, query).await,
+                Page::Next(start) => self.search_from(path, query, start).await,
+                Page::Done => return None,
+            };
+
+            Some(match result {
+                Ok(r) => {
+                    let next = if r.more {
+                        Page::Next(r.start)
+                    } else {
+                        Page::Done
+                    };
+                    let entries = r.matches.into_iter().map(|m| m.metadata).collect();
+
+                    (Ok(entries), next)
+                }
+                Err(e) => (Err(e), Page::Done),
+            })
+        });
+
+        flatten_pages(pages).boxed()
+    }
+
+    /// Start an upload session with the first chunk of a file.
+    /// Returns the session ID.
+    async fn upload_session_start(&self, data: Bytes) -> Result<String, Error> {
+        let args = serde_json::json!({ "close": false }).to_string();
+        let resp = self
+            .request(
+                api::Endpoint::UploadSessionStart,
+                data,
+                Some(&args),
+                Some("application/octet-stream"),
+            )
+            .await?;
+
+        let result: api::UploadSessionStartResult = serde_json::from_slice(&resp)?;
+
+        Ok(result.session_id)
+    }
+
+    /// Append a chunk to an upload session at the given offset.
+    /// A closed session accepts no more data, and can be committed in a
+    /// batch.
+    async fn upload_session_append(
+        &self,
+        session_id: &str,
+        offset: usize,
+        data: Bytes,
+        close: bool,
+    ) -> Result<(), Error> {
+        let args = serde_json::json!({
+            "cursor": { "session_id": session_id, "offset": offset },
+            "close": close,
+        })
+        .to_string();
+
+        let _resp = self
+            .request(
+                api::Endpoint::UploadSessionAppend,
+                data,
+                Some(&args),
+                Some("application/octet-stream"),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Upload the last chunk of a session and commit the file
+    async fn upload_session_finish(
+        &self,
+        session_id: &str,
+        offset: usize,
+        commit: api::CommitInfo,
+        data: Bytes,
+    ) -> Result<api::FileUploadResult, Error> {
+        let args = serde_json::to_string(&api::UploadSessionFinishArg {
+            cursor: api::UploadSessionCursor {
+                session_id: session_id.to_string(),
+                offset,
+            },
+            commit,
+        })?;
+
+        let resp = self
+            .request(
+                api::Endpoint::UploadSessionFinish,
+                data,
+                Some(&args),
+                Some("application/octet-stream"),
+            )
+            .await?;
+
+        serde_json::from_slice(&resp).map_err(|e| e.into())
+    }
+
+    /// Upload the rest of a stream using an upload session.
+    ///
+    /// `buf` holds the first chunk, already read from the stream. The content
+    /// hash is computed as chunks are sent, and checked once committed.
+    async fn upload_session<S>(
+        &self,
+        path: &str,
+        mut buf: BytesMut,
+        mut data: S,
+        metadata: &Metadata,
+    ) -> Result<api::FileUploadResult, Error>
+    where
+        S: Stream<Item = Result<Bytes, crate::Error>> + Send + Unpin,
+    {
+        let mut hasher = api::ContentHasher::new();
+
+        let chunk = buf.split_to(api::DROPBOX_UPLOAD_CHUNK_SIZE).freeze();
+        let mut offset = chunk.len();
+        hasher.update(&chunk);
+        let session_id = self.upload_session_start(chunk).await?;
+
+        loop {
+            while buf.len() < api::DROPBOX_UPLOAD_CHUNK_SIZE {
+                match data.next().await {
+                    Some(chunk) => buf.extend_from_slice(&chunk.map_err(map_stream_error)?),
+                    // The last chunk (possibly empty) is sent with the commit
+                    None => {
+                        hasher.update(&buf);
+
+                        let commit = self.commit_info(path, metadata).await;
+                        let result = self
+                            .upload_session_finish(&session_id, offset, commit, buf.freeze())
+                            .await?;
+
+                        result.verify(&hasher.finish())?;
+
+                        return Ok(result);
+                    }
+                }
+            }
+
+            let chunk = buf.split_to(api::DROPBOX_UPLOAD_CHUNK_SIZE).freeze();
+            let len = chunk.len();
+            hasher.update(&chunk);
+
+            self.upload_session_append(&session_id, offset, chunk, false)
+                .await?;
+            offset += len;
+        }
+    }
+
+    /// Upload a file to a closed session without committing it.
+    ///
+    /// Files staged this way are committed together with `commit_batch`,
+    /// which takes a single lock on the folder instead of one per file.
+    /// Staging a path again replaces the earlier upload.
+    pub async fn stage_upload(
+        &self,
+        path: &str,
+        data: impl Stream<Item = Result<Bytes, crate::Error>> + Send,
+        metadata: &Metadata,
+    ) -> Result<(), Error> {
+        let mut data = Box::pin(data);
+        let mut buf = BytesMut::with_capacity(api::DROPBOX_UPLOAD_CHUNK_SIZE);
+        let mut hasher = api::ContentHasher::new();
+
+        let session_id = self.upload_session_start(Bytes::new()).await?;
+        let mut offset = 0;
+
+        loop {
+            let mut done = false;
+
+            while buf.len() < api::DROPBOX_UPLOAD_CHUNK_SIZE {
+                match data.next().await {
+                    Some(chunk) => buf.extend_from_slice(&chunk.map_err(map_stream_error)?),
+                    None => {
+                        done = true;
+                        break;
+                    }
+                }
+            }
+
+            // The last chunk (possibly empty) closes the session
+            let len = std::cmp::min(buf.len(), api::DROPBOX_UPLOAD_CHUNK_SIZE);
+            let chunk = buf.split_to(len).freeze();
+            hasher.update(&chunk);
+
+            self.upload_session_append(&session_id, offset, chunk, done)
+                .await?;
+            offset += len;
+
+            if done {
+                break;
+            }
+        }
+
+        let staged = api::StagedUpload {
+            entry: api::UploadSessionFinishArg {
+                cursor: api::UploadSessionCursor { session_id, offset },
+                commit: self.commit_info(path, metadata).await,
+            },
+            content_hash: hasher.finish(),
+        };
+
+        let mut batch = self.batch.lock().unwrap();
+        batch.staged.retain(|s| s.entry.commit.path != path);
+        batch.staged.push(staged);
+
+        Ok(())
+    }
+
+    /// Commit the staged files of the email together.
+    ///
+    /// A job launched by an earlier attempt is checked on first, so that its
+    /// files are not sent again, and only files that are not committed yet
+    /// are sent in a new job. Returns all files committed for the email.
+    pub async fn commit_batch(&self) -> Result<Vec<api::FileUploadResult>, Error> {
+        let job = self.batch.lock().unwrap().job.take();
+
+        if let Some(job) = job {
+            self.wait_for_batch(job).await?;
+        }
+
+        let staged = {
+            let mut batch = self.batch.lock().unwrap();
+            let mut staged = std::mem::take(&mut batch.staged);
+
+            // Files staged again when retrying a commit that went through
+            staged.retain(|s| !batch.is_committed(&s.entry.commit.path));
+            staged
+        };
+
+        if !staged.is_empty() {
+            let entries = staged.iter().map(|s| &s.entry).collect::<Vec<_>>();
+            let body = serde_json::json!({ "entries": entries }).to_string();
+
+            let resp = match self
+                .request(
+                    api::Endpoint::UploadSessionFinishBatch,
+                    body.into(),
+                    None,
+                    None,
+                )
+                .await
+            {
+                Ok(resp) => resp,
+                Err(e) => {
+                    // The sessions were not used, so they can be sent again
+                    self.batch.lock().unwrap().staged.extend(staged);
+                    return Err(e);
+                }
+            };
+
+            let status: api::FinishBatchStatus = serde_json::from_slice(&resp)?;
+
+            match status {
+                api::FinishBatchStatus::AsyncJobId { async_job_id } => {
+                    self.wait_for_batch(api::BatchJob {
+                        async_job_id,
+                        uploads: staged,
+                    })
+                    .await?
+                }
+                api::FinishBatchStatus::Complete { entries } => {
+                    self.verify_batch(&staged, entries)?
+                }
+                s => {
+                    return Err(Error::Internal(format!(
+                        "Failed to start Dropbox batch commit: {:?}",
+                        s
+                    )))
+                }
+            }
+        }
+
+        let committed = self.batch.lock().unwrap().committed.clone();
+
+        Ok(committed.into_iter().map(|(_, r)| r).collect())
+    }
+
+    async fn finish_batch_check(
+        &self,
+        async_job_id: &str,
+    ) -> Result<api::FinishBatchStatus, Error> {
+        let body = serde_json::json!({ "async_job_id": async_job_id }).to_string();
+        let resp = self
+            .request(
+                api::Endpoint::UploadSessionFinishBatchCheck,
+                body.into(),
+                None,
+                None,
+            )
+            .await?;
+        serde_json::from_slice(&resp).map_err(|e| e.into())
+    }
+
+    /// Wait a bounded time for a batch commit job to complete.
+    /// A job still running is kept, to be checked on by the next commit.
+    async fn wait_for_batch(&self, job: api::BatchJob) -> Result<(), Error> {
+        let deadline = Instant::now() + self.batch_timeout;
+
+        loop {
+            match self.finish_batch_check(&job.async_job_id).await {
+                Ok(api::FinishBatchStatus::Complete { entries }) => {
+                    return self.verify_batch(&job.uploads, entries)
+                }
+                Ok(api::FinishBatchStatus::InProgress) if Instant::now() < deadline => (),
+                Ok(api::FinishBatchStatus::InProgress) => {
+                    self.batch.lock().unwrap().job = Some(job);
+                    return Err(Error::RequestTimeout);
+                }
+                Ok(s) => {
+                    return Err(Error::Internal(format!(
+                        "Dropbox batch commit failed: {:?}",
+                        s
+                    )))
+                }
+                Err(e) => {
+                    self.batch.lock().unwrap().job = Some(job);
+                    return Err(e);
+                }
+            }
+
+            tokio::time::delay_for(Duration::from_secs(api::DROPBOX_BATCH_POLL_INTERVAL)).await;
+        }
+    }
+
+    /// Check the result of each entry in a batch commit, and record the
+    /// files that were committed. Files that failed have to be staged again.
+    fn verify_batch(
+        &self,
+        staged: &[api::StagedUpload],
+        entries: Vec<api::FinishBatchEntry>,
+    ) -> Result<(), Error> {
+        if staged.len() != entries.len() {
+            return Err(Error::Internal(format!(
+                "Expected {} batch commit results, got {}",
+                staged.len(),
+                entries.len()
+            )));
+        }
+
+        let mut batch = self.batch.lock().unwrap();
+        let mut error = None;
+
+        for (s, entry) in staged.iter().zip(entries) {
+            match entry.verify(&s.content_hash) {
+                Ok(result) => batch.committed.push((s.entry.commit.path.clone(), result)),
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+
+        match error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Upload a stream to a user's Dropbox
+    ///
+    /// Files that fit in a single chunk are sent with one request. Larger
+    /// files are sent chunk by chunk using an upload session, so at most one
+    /// chunk is buffered in memory at a time.
+    pub async fn upload_file(
+        &self,
+        path: &str,
+        data: impl Stream<Item = Result<Bytes, crate::Error>> + Send,
+        metadata: &Metadata,
+    ) -> Result<api::FileUploadResult, Error> {
+        let mut data = Box::pin(data);
+        let mut buf = BytesMut::with_capacity(api::DROPBOX_UPLOAD_CHUNK_SIZE);
+
+        while buf.len() <= api::DROPBOX_UPLOAD_CHUNK_SIZE {
+            match data.next().await {
+                Some(chunk) => buf.extend_from_slice(&chunk.map_err(map_stream_error)?),
+                None => return self.upload(path, buf.to_vec(), metadata).await,
+            }
+        }
+
+        self.upload_session(path, buf, data, metadata).await
+    }
+
+    /// Find the property template holding email fields, creating it if the
+    /// user has none yet
+    pub async fn property_template(&self) -> Result<String, Error> {
+        let key = self.cache_key()?;
+
+        if let Some(template_id) = PROPERTY_TEMPLATE_CACHE.lock().unwrap().get(&key) {
+            return Ok(template_id.clone());
+        }
+
+        // This endpoint takes no arguments
+        let resp = self
+            .request(
+                api::Endpoint::ListPropertyTemplates,
+                "null".into(),
+                None,
+                None,
+            )
+            .await?;
+        let result: api::ListTemplateResult = serde_json::from_slice(&resp)?;
+
+        let mut found = None;
+
+        for template_id in result.template_ids {
+            let body = serde_json::json!({ "template_id": template_id }).to_string();
+            let resp = self
+                .request(api::Endpoint::GetPropertyTemplate, body.into(), None, None)
+                .await?;
+            let template: api::GetTemplateResult = serde_json::from_slice(&resp)?;
+
+            if template.name == api::DROPBOX_PROPERTY_TEMPLATE {
+                found = Some(template_id);
+                break;
+            }
+        }
+
+        let template_id = match found {
+            Some(template_id) => template_id,
+            None => {
+                let body = api::property_template_args().to_string();
+                let resp = self
+                    .request(api::Endpoint::AddPropertyTemplate, body.into(), None, None)
+                    .await?;
+                let result: api::AddTemplateResult = serde_json::from_slice(&resp)?;
+
+                result.template_id
+            }
+        };
+
+        PROPERTY_TEMPLATE_CACHE
+            .lock()
+            .unwrap()
+            .insert(key, template_id.clone());
+
+        Ok(template_id)
+    }
+
+    /// Build the commit for a new file.
+    /// Email fields are left out if the property template is not available.
+    async fn commit_info(&self, path: &str, metadata: &Metadata) -> api::CommitInfo {
+        let template_id = if metadata.fields().is_empty() {
+            None
+        } else {
+            match self.property_template().await {
+                Ok(template_id) => Some(template_id),
+                Err(e) => {
+                    log::warn!("Failed to get Dropbox property template: {}", e);
+                    None
+                }
+            }
+        };
+
+        api::CommitInfo::new(path, metadata, template_id.as_deref())
+    }
+
+    /// Shared link settings for this address, if links should be created
+    pub fn shared_link_settings(&self) -> Option<api::SharedLinkSettings> {
+        self.token.lock().unwrap().shared_links.clone()
+    }
+
+    /// Create a shared link for an uploaded file.
+    /// Returns the link URL.
+    pub async fn create_shared_link(
+        &self,
+        path: &str,
+        settings: &api::SharedLinkSettings,
+    ) -> Result<String, Error> {
+        let body = settings.to_args(path, Utc::now()).to_string();
+        let resp = self
+            .request(api::Endpoint::CreateSharedLink, body.into(), None, None)
+            .await?;
+
+        let link: api::SharedLinkMetadata = serde_json::from_slice(&resp)?;
+
+        Ok(link.url)
+    }
+
+    /// Get the space used in the account, cached for a short while
+    pub async fn get_space_usage(&self) -> Result<SpaceUsage, Error> {
+        let key = self.cache_key()?;
+        let ttl = Duration::from_secs(api::DROPBOX_SPACE_USAGE_TTL);
+
+        let cached = SPACE_USAGE_CACHE.lock().unwrap().get(&key).cloned();
+        if let Some((time, usage)) = cached {
+            if time.elapsed() < ttl {
+                return Ok(usage);
+            }
+        }
+
+        // This endpoint takes no arguments
+        let resp = self
+            .request(api::Endpoint::GetSpaceUsage, "null".into(), None, None)
+            .await?;
+
+        let result: api::SpaceUsageResult = serde_json::from_slice(&resp)?;
+        let usage = result.to_space_usage();
+
+        let mut cache = SPACE_USAGE_CACHE.lock().unwrap();
+        cache.retain(|_, (time, _)| time.elapsed() < ttl);
+        cache.insert(key, (Instant::now(), usage));
+
+        Ok(usage)
+    }
+}
+
+/// Paging state for listings: the first page, the next one (by cursor or
+/// offset), or no more pages
+enum Page<T> {
+    First,
+    Next(T),
+    Done,
+}
+
+/// Flatten a stream of pages into a stream of entries.
+/// An error ends the stream.
+fn flatten_pages<T>(
+    pages: impl Stream<Item = Result<Vec<T>, Error>>,
+) -> impl Stream<Item = Result<T, Error>> {
+    pages.flat_map(|page| {
+        let entries = match page {
+            Ok(entries) => entries.into_iter().map(Ok).collect(),
+            Err(e) => vec![Err(e)],
+        };
+
+        stream::iter(entries)
+    })
+}
+
+fn map_stream_error(err: crate::Error) -> Error {
+    Error::Internal(err.to_string())
 }
 
 impl<'a> Client for DropboxClient<'a> {
     /// Upload a file to a user's Dropbox
-    /// This function does not return any API metadata
     fn upload_stream(
         &self,
         path: &str,
-        data: impl Stream<Item = Result<Bytes, crate::Error>> + Send + Sync + 'static,
-    ) -> ClientFuture<'_, ()> {
-        // Auto-rename the attachment if it exists
-        let args = serde_json::json!({"path": path, "autorename": true}).to_string();
-        let url = api::build_endpoint_url(api::Endpoint::FileUpload);
+        data: ByteStream,
+        metadata: &Metadata,
+    ) -> ClientFuture<'_, Option<StoredObject>> {
+        let path = path.to_string();
+        let metadata = metadata.clone();
+
+        Box::pin(async move {
+            let result = self.upload_file(&path, data, &metadata).await?;
+            Ok(Some(result.into_stored_object()))
+        })
+    }
+
+    /// Upload a file to a closed session, to be committed along with the
+    /// other attachments of the email. This avoids contention on the lock
+    /// Dropbox takes on the folder for each commit.
+    fn stage_stream(
+        &self,
+        path: &str,
+        data: ByteStream,
+        metadata: &Metadata,
+    ) -> ClientFuture<'_, Option<StoredObject>> {
+        let path = path.to_string();
+        let metadata = metadata.clone();
 
         Box::pin(async move {
-            let mut req = self
-                .client
-                .post(reqwest::Url::parse(&url)?)
-                .bearer_auth(&self.token)
-                .header(CONTENT_TYPE, "application/octet-stream")
-                .body(reqwest::Body::wrap_stream(data));
+            self.stage_upload(&path, data, &metadata).await?;
+            Ok(None)
+        })
+    }
 
-            req = req.header(api::DROPBOX_ARG_HEADER, args);
+    fn commit_staged(&self) -> ClientFuture<'_, Vec<StoredObject>> {
+        Box::pin(async move {
+            let results = self.commit_batch().await?;
+            Ok(results
+                .into_iter()
+                .map(|r| r.into_stored_object())
+                .collect())
+        })
+    }
 
-            // Map response into an error if applicable
-            let _resp = api::map_status(req.send().await?)?.bytes().await?;
+    /// Create a folder, succeeding if it already exists
+    fn create_folder(&self, path: &str) -> ClientFuture<'_, ()> {
+        let path = path.to_string();
 
-            Ok(())
+        Box::pin(async move {
+            match DropboxClient::create_folder(self, &path).await {
+                Err(Error::PathConflict(msg)) if msg.contains("conflict/folder") => Ok(()),
+                r => r,
+            }
         })
     }
+
+    fn stat(&self, path: &str) -> ClientFuture<'_, Option<Entry>> {
+        let path = path.to_string();
+
+        Box::pin(async move {
+            let entry = self.get_metadata(&path).await?;
+            Ok(entry.map(|e| e.into_entry()))
+        })
+    }
+
+    fn list(&self, path: &str) -> ClientFuture<'_, Vec<Entry>> {
+        let path = path.to_string();
+
+        Box::pin(async move {
+            self.list_folder_entries(&path)
+                .map_ok(|e| e.into_entry())
+                .try_collect()
+                .await
+        })
+    }
+
+    fn delete(&self, path: &str) -> ClientFuture<'_, ()> {
+        let path = path.to_string();
+
+        Box::pin(async move { self.delete_path(&path).await })
+    }
+
+    fn space_usage(&self) -> ClientFuture<'_, Option<SpaceUsage>> {
+        Box::pin(async move { self.get_space_usage().await.map(Some) })
+    }
+
+    /// Create a shared link with the settings of the storage token, if it
+    /// has shared links enabled
+    fn shared_link(&self, path: &str) -> ClientFuture<'_, Option<String>> {
+        let path = path.to_string();
+
+        Box::pin(async move {
+            match self.shared_link_settings() {
+                Some(settings) => self.create_shared_link(&path, &settings).await.map(Some),
+                None => Ok(None),
+            }
+        })
+    }
+
+    fn refreshed_token(&self) -> Option<String> {
+        DropboxClient::refreshed_token(self)
+    }
+
+    /// The files staged and committed so far for the email
+    fn email_state(&self) -> Option<String> {
+        let batch = self.batch.lock().unwrap();
+
+        if batch.is_empty() {
+            None
+        } else {
+            serde_json::to_string(&*batch).ok()
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use crate::storage::dropbox::mock::{self, MockDropbox};
+
+    #[tokio::test]
+    async fn test_mock_list_folder() {
+        let server = MockDropbox::start();
+        let client = server.client();
+
+        client.create_folder("/vaulty/a").await.unwrap();
+        client
+            .upload("/vaulty/b.txt", b"b".to_vec(), &Metadata::default())
+            .await
+            .unwrap();
+
+        let result = client.list_folder("/vaulty").await.unwrap();
+        let names = result.entries.iter().map(entry_name).collect::<Vec<_>>();
+
+        assert_eq!(names, vec!["a", "b.txt"]);
+        assert!(!result.has_more);
+    }
+
+    fn entry_name(entry: &api::SearchResultEntry) -> String {
+        match entry {
+            api::SearchResultEntry::Folder { name, .. } => name.clone(),
+            api::SearchResultEntry::File { name, .. } => name.clone(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mock_list_folder_entries() {
+        let server = MockDropbox::start();
+        let client = server.client();
+
+        server.state.lock().unwrap().page_size = 2;
+
+        for i in 0..5 {
+            let path = format!("/vaulty/{}.txt", i);
+            client
+                .upload(&path, b"a".to_vec(), &Metadata::default())
+                .await
+                .unwrap();
+        }
+
+        assert!(client.list_folder("/vaulty").await.unwrap().has_more);
+
+        let names = client
+            .list_folder_entries("/vaulty")
+            .map(|e| entry_name(&e.unwrap()))
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(names, vec!["0.txt", "1.txt", "2.txt", "3.txt", "4.txt"]);
+
+        // An error ends the stream
+        let entries = client
+            .list_folder_entries("/missing")
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].is_err());
+    }
+
+    #[tokio::test]
+    async fn test_mock_search_entries() {
+        let server = MockDropbox::start();
+        let client = server.client();
+
+        server.state.lock().unwrap().page_size = 2;
+
+        for name in &["test1", "test2", "other", "a/test3"] {
+            let path = format!("/vaulty/{}", name);
+            client
+                .upload(&path, b"a".to_vec(), &Metadata::default())
+                .await
+                .unwrap();
+        }
+
+        let mut names = client
+            .search_entries("/vaulty", "test")
+            .map(|e| entry_name(&e.unwrap()))
+            .collect::<Vec<_>>()
+            .await;
+        names.sort();
+
+        assert_eq!(names, vec!["test1", "test2", "test3"]);
+    }
+
+    #[tokio::test]
+    async fn test_mock_create_folder_conflict() {
+        let server = MockDropbox::start();
+        let client = server.client();
+
+        assert!(client.create_folder("/vaulty").await.is_ok());
+
+        match client.create_folder("/Vaulty").await {
+            Err(Error::PathConflict(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mock_client_operations() {
+        let server = MockDropbox::start();
+        let client = server.client();
+
+        // Creating a folder twice is fine, but not over a file
+        Client::create_folder(&client, "/vaulty/a").await.unwrap();
+        Client::create_folder(&client, "/vaulty/a").await.unwrap();
+
+        let data = futures::stream::iter(vec![Ok(Bytes::from("Hello there!"))]);
+        let object = client
+            .upload_stream("/vaulty/b.txt", Box::pin(data), &Metadata::default())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(object.path, "/vaulty/b.txt");
+        assert_eq!(object.size, 12);
+        assert_eq!(
+            object.hash.as_deref(),
+            Some(api::content_hash(b"Hello there!").as_str())
+        );
+
+        match Client::create_folder(&client, "/vaulty/b.txt").await {
+            Err(Error::PathConflict(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+
+        assert_eq!(
+            client.stat("/vaulty/B.txt").await.unwrap(),
+            Some(Entry::File(object.clone()))
+        );
+        assert!(client.exists("/vaulty/a").await.unwrap());
+        assert!(!client.exists("/vaulty/c").await.unwrap());
+
+        let paths = client.list("/vaulty").await.unwrap();
+        let paths = paths.iter().map(|e| e.path()).collect::<Vec<_>>();
+        assert_eq!(paths, vec!["/vaulty/a", "/vaulty/b.txt"]);
+
+        client.delete("/vaulty").await.unwrap();
+        assert!(!client.exists("/vaulty/b.txt").await.unwrap());
+
+        match client.delete("/vaulty").await {
+            Err(Error::BadEndpoint(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mock_upload_autorename() {
+        let server = MockDropbox::start();
+        let client = server.client();
+
+        for _ in 0..2 {
+            client
+                .upload("/vaulty/a.txt", b"hello".to_vec(), &Metadata::default())
+                .await
+                .unwrap();
+        }
+
+        let state = server.state.lock().unwrap();
+        assert_eq!(state.files["/vaulty/a.txt"].data, b"hello");
+        assert_eq!(
+            state.files["/vaulty/a (1).txt"].path_display,
+            "/vaulty/a (1).txt"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mock_search() {
+        let server = MockDropbox::start();
+        let client = server.client();
+
+        client.create_folder("/vaulty/test").await.unwrap();
+        client.create_folder("/vaulty/other").await.unwrap();
+        client
+            .upload("/vaulty/other/test123", b"".to_vec(), &Metadata::default())
+            .await
+            .unwrap();
+
+        let result = client.search("/vaulty", "test").await.unwrap();
+
+        assert_eq!(result.matches.len(), 2);
+        assert!(!result.more);
+    }
+
+    #[tokio::test]
+    async fn test_mock_upload_stream_session() {
+        let server = MockDropbox::start();
+        let client = server.client();
+
+        // Two and a half chunks
+        let chunk = Bytes::from(vec![1u8; api::DROPBOX_UPLOAD_CHUNK_SIZE / 2]);
+        let data = futures::stream::iter((0..5).map(move |_| Ok(chunk.clone())));
+
+        client
+            .upload_stream("/vaulty/session.bin", Box::pin(data), &Metadata::default())
+            .await
+            .unwrap();
+
+        let state = server.state.lock().unwrap();
+        let file = &state.files["/vaulty/session.bin"];
+        assert_eq!(file.data.len(), api::DROPBOX_UPLOAD_CHUNK_SIZE * 5 / 2);
+    }
+
     #[tokio::test]
+    async fn test_mock_upload_metadata() {
+        use chrono::TimeZone;
+
+        let server = MockDropbox::start();
+        let client = server.client();
+
+        let metadata = Metadata {
+            modified: Some(Utc.with_ymd_and_hms(2020, 8, 1, 12, 0, 0).unwrap()),
+            sender: Some("a@b.com".to_string()),
+            subject: Some("Reçu".to_string()),
+            ..Default::default()
+        };
+
+        for name in &["a.txt", "b.txt"] {
+            let data = futures::stream::iter(vec![Ok(Bytes::from("hello"))]);
+
+            client
+                .upload_stream(&format!("/vaulty/{}", name), Box::pin(data), &metadata)
+                .await
+                .unwrap();
+        }
+
+        let state = server.state.lock().unwrap();
+
+        // The template is created once, then reused
+        assert_eq!(state.templates.len(), 1);
+
+        let file = &state.files["/vaulty/b.txt"];
+        assert_eq!(
+            file.client_modified.as_deref(),
+            Some("2020-08-01T12:00:00Z")
+        );
+        assert_eq!(file.property_groups[0]["fields"][1]["value"], "Reçu");
+    }
+
+    #[tokio::test]
+    async fn test_mock_content_hash_mismatch() {
+        let server = MockDropbox::start();
+        let client = server.client();
+
+        server.state.lock().unwrap().corrupt = true;
+
+        match client
+            .upload("/vaulty/a.txt", b"hello".to_vec(), &Metadata::default())
+            .await
+        {
+            Err(Error::IntegrityError(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+
+        // Also checked when committing an upload session
+        let chunk = Bytes::from(vec![1u8; api::DROPBOX_UPLOAD_CHUNK_SIZE]);
+        let data = futures::stream::iter((0..2).map(move |_| Ok(chunk.clone())));
+
+        match client
+            .upload_stream("/vaulty/session.bin", Box::pin(data), &Metadata::default())
+            .await
+        {
+            Err(Error::IntegrityError(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mock_commit_batch() {
+        let server = MockDropbox::start();
+        let client = server.client();
+
+        // Empty, small, and multi-chunk files
+        for (name, size) in &[
+            ("a.txt", 0),
+            ("b.txt", 5),
+            ("c.bin", api::DROPBOX_UPLOAD_CHUNK_SIZE + 1),
+        ] {
+            let data = futures::stream::iter(vec![Ok(Bytes::from(vec![1u8; *size]))]);
+            let path = format!("/vaulty/{}", name);
+
+            let stored = client
+                .stage_stream(&path, Box::pin(data), &Metadata::default())
+                .await
+                .unwrap();
+            assert!(stored.is_none());
+        }
+
+        // Nothing is visible until the batch is committed
+        assert!(server.state.lock().unwrap().files.is_empty());
+
+        // The batch is carried over to the client for the next attachment
+        let state = client.email_state().unwrap();
+        let client = server.client().with_batch(&state).unwrap();
+
+        let stored = client.commit_staged().await.unwrap();
+        assert_eq!(stored.len(), 3);
+        assert_eq!(stored[2].path, "/vaulty/c.bin");
+
+        let state = server.state.lock().unwrap();
+        assert_eq!(state.files.len(), 3);
+        assert_eq!(
+            state.files["/vaulty/c.bin"].data.len(),
+            api::DROPBOX_UPLOAD_CHUNK_SIZE + 1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mock_commit_batch_failure() {
+        let server = MockDropbox::start();
+        let client = server.client();
+
+        let data = futures::stream::iter(vec![Ok(Bytes::from("a"))]);
+        client
+            .stage_upload("/vaulty/a.txt", data, &Metadata::default())
+            .await
+            .unwrap();
+        client.batch.lock().unwrap().staged[0].entry.cursor.offset += 1;
+
+        match client.commit_batch().await {
+            Err(Error::Internal(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mock_commit_batch_retry() {
+        let server = MockDropbox::start();
+        let mut client = server.client();
+        client.batch_timeout = Duration::from_secs(0);
+
+        for name in &["a.txt", "b.txt"] {
+            let data = futures::stream::iter(vec![Ok(Bytes::from("a"))]);
+            let path = format!("/vaulty/{}", name);
+
+            client
+                .stage_upload(&path, data, &Metadata::default())
+                .await
+                .unwrap();
+        }
+
+        // The job is still running when the request gives up
+        server.state.lock().unwrap().pending_checks = 1;
+
+        match client.commit_batch().await {
+            Err(Error::RequestTimeout) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+
+        // The last attachment is staged again by the retry
+        let client = server
+            .client()
+            .with_batch(&client.email_state().unwrap())
+            .unwrap();
+
+        let data = futures::stream::iter(vec![Ok(Bytes::from("a"))]);
+        client
+            .stage_upload("/vaulty/b.txt", data, &Metadata::default())
+            .await
+            .unwrap();
+
+        // The job is checked on rather than sending its sessions again, and
+        // the file staged again is not committed twice
+        let results = client.commit_batch().await.unwrap();
+        assert_eq!(results.len(), 2);
+
+        let state = server.state.lock().unwrap();
+        assert_eq!(state.files.len(), 2);
+        assert!(state.files.contains_key("/vaulty/b.txt"));
+    }
+
+    #[tokio::test]
+    async fn test_mock_map_status() {
+        let server = MockDropbox::start();
+        let client = server.client();
+
+        for status in &[400, 401, 403, 409, 429, 500] {
+            let result = client.list_folder(&format!("/error/{}", status)).await;
+
+            match (status, result) {
+                (400, Err(Error::BadInput(_)))
+                | (401, Err(Error::TokenExpired(_)))
+                | (403, Err(Error::TokenExpired(_)))
+                | (409, Err(Error::BadEndpoint(_)))
+                | (429, Err(Error::RateLimited(_)))
+                | (500, Err(Error::Internal(_))) => (),
+                (s, r) => panic!("Unexpected result for {}: {:?}", s, r),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mock_error_summary() {
+        let server = MockDropbox::start();
+        let client = server.client();
+
+        let result = client
+            .upload(
+                "/error/409/path/insufficient_space/a.txt",
+                b"a".to_vec(),
+                &Metadata::default(),
+            )
+            .await;
+        match result {
+            Err(Error::InsufficientSpace(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+
+        let result = client
+            .create_folder("/error/409/path/malformed_path/a")
+            .await;
+        match result {
+            Err(Error::MalformedPath(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+
+        // Write contention is retryable, despite the 409
+        let result = client
+            .list_folder("/error/409/too_many_write_operations")
+            .await;
+        match result {
+            Err(Error::RateLimited(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mock_namespace_headers() {
+        let server = MockDropbox::start();
+
+        // Home namespace by default
+        server.client().list_folder("").await.unwrap();
+        assert!(server.state.lock().unwrap().path_root.is_none());
+
+        let mut token = api::Token::from_token(mock::MOCK_ACCESS_TOKEN);
+        token.path_root = Some(api::PathRoot::Root {
+            root: "1234".to_string(),
+        });
+        token.select_user = Some("dbmid:abcd".to_string());
+
+        let client = server.client_with_token(&token.to_token());
+        client.list_folder("").await.unwrap();
+
+        let state = server.state.lock().unwrap();
+        assert_eq!(
+            state.path_root.as_deref(),
+            Some(r#"{".tag":"root","root":"1234"}"#)
+        );
+        assert_eq!(state.select_user.as_deref(), Some("dbmid:abcd"));
+    }
+
+    #[tokio::test]
+    async fn test_mock_shared_link() {
+        let server = MockDropbox::start();
+        let client = server.client();
+
+        assert!(client.shared_link_settings().is_none());
+
+        let data = futures::stream::iter(vec![Ok(Bytes::from("a"))]);
+        client
+            .upload("/vaulty/a.txt", b"a".to_vec(), &Metadata::default())
+            .await
+            .unwrap();
+        let result = client
+            .upload_file("/vaulty/a.txt", data, &Metadata::default())
+            .await
+            .unwrap();
+
+        // The link is created for the renamed file
+        assert_eq!(result.path_display, "/vaulty/a (1).txt");
+
+        let settings = api::SharedLinkSettings {
+            visibility: Some(api::LinkVisibility::Public),
+            expires_in_days: Some(30),
+        };
+        let url = client
+            .create_shared_link(&result.path_display, &settings)
+            .await
+            .unwrap();
+
+        assert!(url.ends_with("/a (1).txt?dl=0"));
+
+        let state = server.state.lock().unwrap();
+        assert_eq!(state.shared_links[0]["requested_visibility"], "public");
+        assert!(state.shared_links[0]["expires"].is_string());
+    }
+
+    #[tokio::test]
+    async fn test_mock_space_usage() {
+        let server = MockDropbox::start();
+        let client = server.client();
+
+        server.state.lock().unwrap().allocated = 1000;
+        client
+            .upload("/vaulty/a.txt", vec![0u8; 100], &Metadata::default())
+            .await
+            .unwrap();
+
+        let usage = client.space_usage().await.unwrap().unwrap();
+        assert_eq!(usage.used, 100);
+        assert_eq!(usage.available(), Some(900));
+
+        // Served from the cache
+        client
+            .upload("/vaulty/b.txt", vec![0u8; 100], &Metadata::default())
+            .await
+            .unwrap();
+        assert_eq!(client.get_space_usage().await.unwrap().used, 100);
+
+        // but not for another member of a team using the same token
+        let mut token = api::Token::from_token(mock::MOCK_ACCESS_TOKEN);
+        token.select_user = Some("dbmid:abcd".to_string());
+
+        let member = server.client_with_token(&token.to_token());
+        assert_eq!(member.get_space_usage().await.unwrap().used, 200);
+    }
+
+    #[tokio::test]
+    async fn test_mock_refresh() {
+        let server = MockDropbox::start();
+        let mut token = api::Token::from_token("expired");
+        token.refresh_token = Some(mock::MOCK_REFRESH_TOKEN.to_string());
+
+        // Without app credentials, the expired token is returned as is
+        let client = server.client_with_token(&token.to_token());
+        match client.list_folder("").await {
+            Err(Error::TokenExpired(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert!(client.refreshed_token().is_none());
+
+        let client = server
+            .client_with_token(&token.to_token())
+            .with_app_credentials(mock::MOCK_APP_KEY, mock::MOCK_APP_SECRET);
+
+        assert!(client.list_folder("").await.is_ok());
+
+        let refreshed = api::Token::from_token(&client.refreshed_token().unwrap());
+        assert_eq!(
+            refreshed.access_token,
+            server.state.lock().unwrap().access_token
+        );
+        assert_eq!(
+            refreshed.refresh_token.as_deref(),
+            Some(mock::MOCK_REFRESH_TOKEN)
+        );
+    }
+
+    // The tests below run against a real Dropbox account:
+    // DROPBOX_TOKEN=... cargo test -- --ignored
+
+    #[tokio::test]
+    #[ignore]
     async fn test_list_folder() {
         let token = std::env::var("DROPBOX_TOKEN").expect("No Dropbox token found");
         let client = DropboxClient::from_token(&token);
@@ -142,6 +1584,7 @@ mod tests {
     }
 
     #[tokio::test]
+    #[ignore]
     async fn test_create_folder() {
         let token = std::env::var("DROPBOX_TOKEN").expect("No Dropbox token found");
         let client = DropboxClient::from_token(&token);
@@ -152,18 +1595,44 @@ mod tests {
     }
 
     #[tokio::test]
+    #[ignore]
     async fn test_file_upload() {
         let token = std::env::var("DROPBOX_TOKEN").expect("No Dropbox token found");
         let client = DropboxClient::from_token(&token);
         let data = String::from("Hello there!").into_bytes();
 
-        let result = client.upload("/vaulty_test.txt", data).await;
+        let result = client
+            .upload("/vaulty_test.txt", data, &Metadata::default())
+            .await;
+
+        println!("{:?}", result);
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_upload_stream_session() {
+        let token = std::env::var("DROPBOX_TOKEN").expect("No Dropbox token found");
+        let client = DropboxClient::from_token(&token);
+
+        // Two and a half chunks
+        let chunk = Bytes::from(vec![0u8; api::DROPBOX_UPLOAD_CHUNK_SIZE / 2]);
+        let data = futures::stream::iter((0..5).map(move |_| Ok(chunk.clone())));
+
+        let result = client
+            .upload_stream(
+                "/vaulty/vaulty_session.bin",
+                Box::pin(data),
+                &Metadata::default(),
+            )
+            .await;
 
         println!("{:?}", result);
         assert!(result.is_ok());
     }
 
     #[tokio::test]
+    #[ignore]
     /// /vaulty/search1 -> "test/", "test123/"
     async fn test_search_folders() {
         let token = std::env::var("DROPBOX_TOKEN").expect("No Dropbox token found");
@@ -176,6 +1645,7 @@ mod tests {
     }
 
     #[tokio::test]
+    #[ignore]
     /// /vaulty/search2 -> "test", "test123", "test/"
     async fn test_search_files_and_folders() {
         let token = std::env::var("DROPBOX_TOKEN").expect("No Dropbox token found");
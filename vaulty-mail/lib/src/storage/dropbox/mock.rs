@@ -0,0 +1,733 @@
+//! In-memory mock of the Dropbox API endpoints used by `DropboxClient`.
+//!
+//! Any path of the form `/error/<status>[/<summary>]` makes the mock respond
+//! with that status code and error summary, which is used to test error
+//! mapping.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use serde_json::{json, Value};
+use warp::http::StatusCode;
+use warp::reply::{Json, WithStatus};
+use warp::{Filter, Rejection, Reply};
+
+use super::api;
+use super::client::DropboxClient;
+
+pub const MOCK_ACCESS_TOKEN: &str = "mock-access-token";
+pub const MOCK_REFRESH_TOKEN: &str = "mock-refresh-token";
+pub const MOCK_APP_KEY: &str = "mock-app-key";
+pub const MOCK_APP_SECRET: &str = "mock-app-secret";
+
+type Response = WithStatus<Json>;
+type Handler = fn(&mut State, &Value, Bytes) -> Result<Value, (u16, &'static str)>;
+
+pub struct MockFile {
+    pub path_display: String,
+    pub data: Vec<u8>,
+    pub client_modified: Option<String>,
+    pub property_groups: Value,
+    id: usize,
+}
+
+/// Mock Dropbox state. Paths are keyed in lowercase, like Dropbox does.
+pub struct State {
+    pub access_token: String,
+    /// Drop the last byte of every stored file, to simulate corruption
+    pub corrupt: bool,
+    /// Maximum number of entries per page of list or search results
+    pub page_size: usize,
+    /// Space allocated to the account, in bytes
+    pub allocated: u64,
+    /// Settings of each shared link created
+    pub shared_links: Vec<Value>,
+    /// File property templates, by ID
+    pub templates: HashMap<String, Value>,
+    /// Namespace headers sent with the last API request
+    pub path_root: Option<String>,
+    pub select_user: Option<String>,
+    pub folders: HashMap<String, String>,
+    pub files: HashMap<String, MockFile>,
+    /// Number of batch job checks answered with `in_progress` before the
+    /// job is reported complete
+    pub pending_checks: usize,
+    sessions: HashMap<String, Vec<u8>>,
+    jobs: HashMap<String, Value>,
+    next_id: usize,
+}
+
+impl State {
+    fn new() -> Self {
+        Self {
+            access_token: MOCK_ACCESS_TOKEN.to_string(),
+            corrupt: false,
+            page_size: 100,
+            allocated: 2_000_000_000,
+            shared_links: Vec::new(),
+            templates: HashMap::new(),
+            path_root: None,
+            select_user: None,
+            folders: HashMap::new(),
+            files: HashMap::new(),
+            pending_checks: 0,
+            sessions: HashMap::new(),
+            jobs: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    fn next_id(&mut self) -> usize {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn exists(&self, path_lower: &str) -> bool {
+        self.folders.contains_key(path_lower) || self.files.contains_key(path_lower)
+    }
+
+    /// Create a folder along with any missing parents
+    fn create_folders(&mut self, path: &str) {
+        let mut current = String::new();
+
+        for part in path.split('/').filter(|s| !s.is_empty()) {
+            current.push('/');
+            current.push_str(part);
+
+            self.folders
+                .entry(current.to_lowercase())
+                .or_insert_with(|| current.clone());
+        }
+    }
+
+    /// Store a file as described by commit info, renaming it if requested
+    /// and the path is taken
+    fn commit(&mut self, commit: &Value, mut data: Vec<u8>) -> Result<Value, (u16, &'static str)> {
+        let path = commit["path"].as_str().unwrap_or("");
+        let autorename = commit["autorename"].as_bool().unwrap_or(false);
+        let property_groups = commit["property_groups"].clone();
+
+        let empty = Vec::new();
+        for group in property_groups.as_array().unwrap_or(&empty) {
+            let template_id = group["template_id"].as_str().unwrap_or("");
+
+            if !self.templates.contains_key(template_id) {
+                return Err((409, "properties_error/template_not_found"));
+            }
+        }
+
+        let (parent, name) = split_path(path);
+        let mut i = 0;
+
+        let path_display = loop {
+            let candidate = format!("{}/{}", parent, crate::storage::util::autorename(name, i));
+
+            if !self.exists(&candidate.to_lowercase()) {
+                break candidate;
+            }
+
+            if !autorename {
+                return Err((409, "path/conflict/file"));
+            }
+
+            i += 1;
+        };
+
+        if self.corrupt {
+            data.pop();
+        }
+
+        self.create_folders(parent);
+
+        let id = self.next_id();
+        let file = MockFile {
+            path_display: path_display.clone(),
+            data,
+            client_modified: commit["client_modified"].as_str().map(String::from),
+            property_groups,
+            id,
+        };
+
+        let metadata = file_metadata(&file);
+        self.files.insert(path_display.to_lowercase(), file);
+
+        Ok(metadata)
+    }
+
+    /// All entries under a folder, as Dropbox metadata
+    fn entries(&self, path_lower: &str, recursive: bool) -> Vec<(String, Value)> {
+        let is_child = |p: &str| {
+            let (parent, _) = split_path(p);
+            if recursive {
+                p.starts_with(&format!("{}/", path_lower))
+            } else {
+                parent == path_lower
+            }
+        };
+
+        let folders = self
+            .folders
+            .iter()
+            .filter(|(k, _)| is_child(k))
+            .map(|(_, v)| (name_of(v), folder_metadata(v)));
+
+        let files = self
+            .files
+            .iter()
+            .filter(|(k, _)| is_child(k))
+            .map(|(_, f)| (name_of(&f.path_display), file_metadata(f)));
+
+        let mut entries = folders.chain(files).collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+}
+
+/// Split a path into parent folder and name, e.g., `/a/b.txt` -> (`/a`, `b.txt`)
+fn split_path(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => ("", path),
+    }
+}
+
+fn name_of(path: &str) -> String {
+    split_path(path).1.to_string()
+}
+
+fn folder_metadata(path_display: &str) -> Value {
+    json!({
+        ".tag": "folder",
+        "name": name_of(path_display),
+        "id": format!("id:{}", path_display.to_lowercase()),
+        "path_lower": path_display.to_lowercase(),
+        "path_display": path_display,
+    })
+}
+
+fn file_metadata(file: &MockFile) -> Value {
+    json!({
+        ".tag": "file",
+        "name": name_of(&file.path_display),
+        "id": format!("id:{}", file.id),
+        "size": file.data.len(),
+        "client_modified": file.client_modified.as_deref().unwrap_or("2020-01-01T00:00:00Z"),
+        "server_modified": "2020-01-01T00:00:00Z",
+        "path_lower": file.path_display.to_lowercase(),
+        "path_display": file.path_display,
+        "content_hash": api::content_hash(&file.data),
+    })
+}
+
+fn reply(status: u16, body: Value) -> Response {
+    warp::reply::with_status(
+        warp::reply::json(&body),
+        StatusCode::from_u16(status).unwrap(),
+    )
+}
+
+fn error(status: u16, summary: &str) -> Response {
+    let tag = summary.split('/').next().unwrap_or(summary);
+
+    reply(
+        status,
+        json!({ "error_summary": format!("{}/...", summary), "error": { ".tag": tag } }),
+    )
+}
+
+/// Common request handling: auth, argument parsing, and error injection
+fn handle(
+    state: &Mutex<State>,
+    auth: Option<String>,
+    arg: Option<String>,
+    body: Bytes,
+    handler: Handler,
+) -> Response {
+    let mut state = state.lock().unwrap();
+
+    if auth != Some(format!("Bearer {}", state.access_token)) {
+        return error(401, "expired_access_token");
+    }
+
+    // Content endpoints pass arguments in a header, the rest in the body
+    let (args, body) = match arg {
+        Some(arg) => (serde_json::from_str::<Value>(&arg), body),
+        None => (serde_json::from_slice::<Value>(&body), Bytes::new()),
+    };
+
+    let args = match args {
+        Ok(args) => args,
+        Err(_) => return error(400, "invalid_json"),
+    };
+
+    let path = args["path"]
+        .as_str()
+        .or_else(|| args["commit"]["path"].as_str())
+        .unwrap_or("");
+
+    if let Some(rest) = path.strip_prefix("/error/") {
+        let mut parts = rest.splitn(2, '/');
+        let status = parts
+            .next()
+            .and_then(|s| s.parse::<u16>().ok())
+            .unwrap_or(500);
+        let summary = parts.next().unwrap_or("mock_error");
+
+        return error(status, summary);
+    }
+
+    match handler(&mut state, &args, body) {
+        Ok(v) => reply(200, v),
+        Err((status, summary)) => error(status, summary),
+    }
+}
+
+/// One page of folder entries, starting at `offset`.
+/// The cursor encodes the next offset and the folder path.
+fn list_page(state: &State, path: &str, offset: usize) -> Result<Value, (u16, &'static str)> {
+    if !path.is_empty() && !state.folders.contains_key(path) {
+        return Err((409, "path/not_found"));
+    }
+
+    let entries = state
+        .entries(path, false)
+        .into_iter()
+        .skip(offset)
+        .take(state.page_size)
+        .map(|(_, v)| v)
+        .collect::<Vec<_>>();
+
+    let next = offset + entries.len();
+    let has_more = next < state.entries(path, false).len();
+
+    Ok(json!({
+        "entries": entries,
+        "cursor": format!("{}:{}", next, path),
+        "has_more": has_more,
+    }))
+}
+
+fn list_folder(state: &mut State, args: &Value, _: Bytes) -> Result<Value, (u16, &'static str)> {
+    let path = args["path"].as_str().unwrap_or("").to_lowercase();
+
+    list_page(state, &path, 0)
+}
+
+fn list_folder_continue(
+    state: &mut State,
+    args: &Value,
+    _: Bytes,
+) -> Result<Value, (u16, &'static str)> {
+    let cursor = args["cursor"].as_str().unwrap_or("");
+    let mut parts = cursor.splitn(2, ':');
+
+    let offset = parts.next().and_then(|s| s.parse::<usize>().ok());
+    let path = parts.next();
+
+    match (offset, path) {
+        (Some(offset), Some(path)) => list_page(state, path, offset),
+        _ => Err((409, "reset")),
+    }
+}
+
+fn create_folder(state: &mut State, args: &Value, _: Bytes) -> Result<Value, (u16, &'static str)> {
+    let path = args["path"].as_str().unwrap_or("");
+
+    if state.files.contains_key(&path.to_lowercase()) {
+        return Err((409, "path/conflict/file"));
+    } else if state.folders.contains_key(&path.to_lowercase()) {
+        return Err((409, "path/conflict/folder"));
+    }
+
+    state.create_folders(path);
+
+    Ok(json!({ "metadata": folder_metadata(path) }))
+}
+
+fn get_metadata(state: &mut State, args: &Value, _: Bytes) -> Result<Value, (u16, &'static str)> {
+    let path = args["path"].as_str().unwrap_or("").to_lowercase();
+
+    if let Some(file) = state.files.get(&path) {
+        Ok(file_metadata(file))
+    } else if let Some(path_display) = state.folders.get(&path) {
+        Ok(folder_metadata(path_display))
+    } else {
+        Err((409, "path/not_found"))
+    }
+}
+
+fn delete(state: &mut State, args: &Value, _: Bytes) -> Result<Value, (u16, &'static str)> {
+    let path = args["path"].as_str().unwrap_or("").to_lowercase();
+
+    let metadata = if let Some(file) = state.files.remove(&path) {
+        file_metadata(&file)
+    } else if let Some(path_display) = state.folders.remove(&path) {
+        // Deleting a folder also deletes everything in it
+        let prefix = format!("{}/", path);
+        state.folders.retain(|k, _| !k.starts_with(&prefix));
+        state.files.retain(|k, _| !k.starts_with(&prefix));
+
+        folder_metadata(&path_display)
+    } else {
+        return Err((409, "path_lookup/not_found"));
+    };
+
+    Ok(json!({ "metadata": metadata }))
+}
+
+fn search(state: &mut State, args: &Value, _: Bytes) -> Result<Value, (u16, &'static str)> {
+    let path = args["path"].as_str().unwrap_or("").to_lowercase();
+    let query = args["query"].as_str().unwrap_or("").to_lowercase();
+    let start = args["start"].as_u64().unwrap_or(0) as usize;
+
+    let all = state
+        .entries(&path, true)
+        .into_iter()
+        .filter(|(name, _)| name.to_lowercase().contains(&query))
+        .collect::<Vec<_>>();
+
+    let matches = all
+        .iter()
+        .skip(start)
+        .take(state.page_size)
+        .map(|(_, v)| json!({ "match_type": { ".tag": "filename" }, "metadata": v }))
+        .collect::<Vec<_>>();
+
+    let next = start + matches.len();
+
+    Ok(json!({ "matches": matches, "more": next < all.len(), "start": next }))
+}
+
+fn upload(state: &mut State, args: &Value, body: Bytes) -> Result<Value, (u16, &'static str)> {
+    state.commit(args, body.to_vec())
+}
+
+fn upload_session_start(
+    state: &mut State,
+    _: &Value,
+    body: Bytes,
+) -> Result<Value, (u16, &'static str)> {
+    let session_id = format!("session-{}", state.next_id());
+    state.sessions.insert(session_id.clone(), body.to_vec());
+
+    Ok(json!({ "session_id": session_id }))
+}
+
+/// Append to a session, checking the offset like Dropbox does
+fn append_to_session(
+    state: &mut State,
+    args: &Value,
+    body: &[u8],
+) -> Result<(), (u16, &'static str)> {
+    let session_id = args["cursor"]["session_id"].as_str().unwrap_or("");
+    let offset = args["cursor"]["offset"].as_u64().unwrap_or(0) as usize;
+
+    let data = match state.sessions.get_mut(session_id) {
+        Some(data) => data,
+        None => return Err((409, "not_found")),
+    };
+
+    if data.len() != offset {
+        return Err((409, "incorrect_offset"));
+    }
+
+    data.extend_from_slice(body);
+
+    Ok(())
+}
+
+fn upload_session_append(
+    state: &mut State,
+    args: &Value,
+    body: Bytes,
+) -> Result<Value, (u16, &'static str)> {
+    append_to_session(state, args, &body)?;
+    Ok(Value::Null)
+}
+
+fn upload_session_finish(
+    state: &mut State,
+    args: &Value,
+    body: Bytes,
+) -> Result<Value, (u16, &'static str)> {
+    append_to_session(state, args, &body)?;
+
+    let session_id = args["cursor"]["session_id"].as_str().unwrap_or("");
+    let data = state.sessions.remove(session_id).unwrap_or_default();
+
+    state.commit(&args["commit"], data)
+}
+
+fn get_space_usage(state: &mut State, _: &Value, _: Bytes) -> Result<Value, (u16, &'static str)> {
+    let used = state.files.values().map(|f| f.data.len()).sum::<usize>();
+
+    Ok(json!({
+        "used": used,
+        "allocation": { ".tag": "individual", "allocated": state.allocated },
+    }))
+}
+
+fn list_templates(state: &mut State, _: &Value, _: Bytes) -> Result<Value, (u16, &'static str)> {
+    let mut template_ids = state.templates.keys().cloned().collect::<Vec<_>>();
+    template_ids.sort();
+
+    Ok(json!({ "template_ids": template_ids }))
+}
+
+fn get_template(state: &mut State, args: &Value, _: Bytes) -> Result<Value, (u16, &'static str)> {
+    let template_id = args["template_id"].as_str().unwrap_or("");
+
+    state
+        .templates
+        .get(template_id)
+        .cloned()
+        .ok_or((409, "template_not_found"))
+}
+
+fn add_template(state: &mut State, args: &Value, _: Bytes) -> Result<Value, (u16, &'static str)> {
+    let template_id = format!("ptid:{}", state.next_id());
+    state.templates.insert(template_id.clone(), args.clone());
+
+    Ok(json!({ "template_id": template_id }))
+}
+
+/// Create a shared link for a file, recording the requested settings
+fn create_shared_link(
+    state: &mut State,
+    args: &Value,
+    _: Bytes,
+) -> Result<Value, (u16, &'static str)> {
+    let path = args["path"].as_str().unwrap_or("").to_lowercase();
+
+    let file = match state.files.get(&path) {
+        Some(file) => file,
+        None => return Err((409, "path/not_found")),
+    };
+
+    let name = name_of(&file.path_display);
+    let url = format!("https://www.dropbox.com/s/{}/{}?dl=0", file.id, name);
+
+    state.shared_links.push(args["settings"].clone());
+
+    Ok(json!({
+        ".tag": "file",
+        "url": url,
+        "name": name,
+        "path_lower": path,
+    }))
+}
+
+/// Commit a batch of closed sessions as an async job
+fn upload_session_finish_batch(
+    state: &mut State,
+    args: &Value,
+    _: Bytes,
+) -> Result<Value, (u16, &'static str)> {
+    let empty = Vec::new();
+    let mut entries = Vec::new();
+
+    for entry in args["entries"].as_array().unwrap_or(&empty) {
+        let session_id = entry["cursor"]["session_id"].as_str().unwrap_or("");
+        let offset = entry["cursor"]["offset"].as_u64().unwrap_or(0) as usize;
+
+        let result = match state.sessions.remove(session_id) {
+            Some(data) if data.len() == offset => state.commit(&entry["commit"], data),
+            Some(_) => Err((409, "lookup_failed/incorrect_offset")),
+            None => Err((409, "lookup_failed/not_found")),
+        };
+
+        entries.push(match result {
+            Ok(mut metadata) => {
+                metadata[".tag"] = json!("success");
+                metadata
+            }
+            Err((_, summary)) => {
+                let tags = summary.split('/').collect::<Vec<_>>();
+
+                let mut failure = json!({ ".tag": tags[0] });
+                failure[tags[0]] = json!({ ".tag": tags[1] });
+
+                json!({ ".tag": "failure", "failure": failure })
+            }
+        });
+    }
+
+    let async_job_id = format!("job-{}", state.next_id());
+    state.jobs.insert(
+        async_job_id.clone(),
+        json!({ ".tag": "complete", "entries": entries }),
+    );
+
+    Ok(json!({ ".tag": "async_job_id", "async_job_id": async_job_id }))
+}
+
+fn upload_session_finish_batch_check(
+    state: &mut State,
+    args: &Value,
+    _: Bytes,
+) -> Result<Value, (u16, &'static str)> {
+    let async_job_id = args["async_job_id"].as_str().unwrap_or("");
+
+    if !state.jobs.contains_key(async_job_id) {
+        return Err((409, "invalid_async_job_id"));
+    }
+
+    if state.pending_checks > 0 {
+        state.pending_checks -= 1;
+        return Ok(json!({ ".tag": "in_progress" }));
+    }
+
+    Ok(state.jobs[async_job_id].clone())
+}
+
+/// OAuth2 token endpoint; only the refresh token grant is supported
+fn token(state: &Mutex<State>, body: Bytes) -> Response {
+    let params = url::form_urlencoded::parse(&body)
+        .into_owned()
+        .collect::<HashMap<String, String>>();
+
+    let get = |k: &str| params.get(k).map(|s| s.as_str());
+
+    let valid = get("grant_type") == Some("refresh_token")
+        && get("refresh_token") == Some(MOCK_REFRESH_TOKEN)
+        && get("client_id") == Some(MOCK_APP_KEY)
+        && get("client_secret") == Some(MOCK_APP_SECRET);
+
+    if !valid {
+        return reply(400, json!({ "error": "invalid_grant" }));
+    }
+
+    let mut state = state.lock().unwrap();
+    let access_token = format!("mock-access-token-{}", state.next_id());
+    state.access_token = access_token.clone();
+
+    reply(
+        200,
+        json!({ "access_token": access_token, "token_type": "bearer", "expires_in": 14400 }),
+    )
+}
+
+fn routes(
+    state: Arc<Mutex<State>>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone + Send + Sync + 'static {
+    let token_state = state.clone();
+    let oauth = warp::post()
+        .and(warp::path!("oauth2" / "token"))
+        .and(warp::body::bytes())
+        .map(move |body| token(&token_state, body));
+
+    // The body can only be taken once, so match the full path before it
+    let endpoint = move |name: &'static str, handler: Handler| {
+        let state = state.clone();
+
+        warp::post()
+            .and(warp::path("2"))
+            .and(
+                warp::path::tail()
+                    .and_then(move |tail: warp::path::Tail| async move {
+                        if tail.as_str() == name {
+                            Ok(())
+                        } else {
+                            Err(warp::reject::not_found())
+                        }
+                    })
+                    .untuple_one(),
+            )
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::header::optional::<String>("dropbox-api-arg"))
+            .and(warp::header::optional::<String>("dropbox-api-path-root"))
+            .and(warp::header::optional::<String>("dropbox-api-select-user"))
+            .and(warp::body::bytes())
+            .map(move |auth, arg, path_root, select_user, body| {
+                {
+                    let mut state = state.lock().unwrap();
+                    state.path_root = path_root;
+                    state.select_user = select_user;
+                }
+
+                handle(&state, auth, arg, body, handler)
+            })
+    };
+
+    endpoint("files/list_folder", list_folder)
+        .or(endpoint("files/list_folder/continue", list_folder_continue))
+        .or(endpoint("files/create_folder_v2", create_folder))
+        .or(endpoint("files/get_metadata", get_metadata))
+        .or(endpoint("files/delete_v2", delete))
+        .or(endpoint("files/search", search))
+        .or(endpoint("files/upload", upload))
+        .or(endpoint("files/upload_session/start", upload_session_start))
+        .or(endpoint(
+            "files/upload_session/append_v2",
+            upload_session_append,
+        ))
+        .or(endpoint(
+            "files/upload_session/finish",
+            upload_session_finish,
+        ))
+        .or(endpoint(
+            "files/upload_session/finish_batch",
+            upload_session_finish_batch,
+        ))
+        .or(endpoint(
+            "files/upload_session/finish_batch/check",
+            upload_session_finish_batch_check,
+        ))
+        .or(endpoint("users/get_space_usage", get_space_usage))
+        .or(endpoint(
+            "sharing/create_shared_link_with_settings",
+            create_shared_link,
+        ))
+        .or(endpoint(
+            "file_properties/templates/list_for_user",
+            list_templates,
+        ))
+        .or(endpoint(
+            "file_properties/templates/get_for_user",
+            get_template,
+        ))
+        .or(endpoint(
+            "file_properties/templates/add_for_user",
+            add_template,
+        ))
+        .or(oauth)
+}
+
+/// A mock Dropbox server running on an ephemeral local port.
+/// Must be started from within a Tokio runtime.
+pub struct MockDropbox {
+    pub addr: SocketAddr,
+    pub state: Arc<Mutex<State>>,
+}
+
+impl MockDropbox {
+    pub fn start() -> Self {
+        let state = Arc::new(Mutex::new(State::new()));
+        let (addr, server) = warp::serve(routes(state.clone())).bind_ephemeral(([127, 0, 0, 1], 0));
+
+        tokio::spawn(server);
+
+        Self { addr, state }
+    }
+
+    /// Base URL for both API and content endpoints
+    pub fn base_url(&self) -> String {
+        format!("http://{}/2/", self.addr)
+    }
+
+    /// Build a client for this server using the given storage token
+    pub fn client_with_token<'a>(&self, token: &str) -> DropboxClient<'a> {
+        let url = self.base_url();
+
+        DropboxClient::from_token(token)
+            .with_base_urls(&url, &url)
+            .unwrap()
+    }
+
+    /// Build a client for this server with a valid access token
+    pub fn client<'a>(&self) -> DropboxClient<'a> {
+        self.client_with_token(MOCK_ACCESS_TOKEN)
+    }
+}
@@ -15,6 +15,12 @@ pub enum Error {
     BadEndpoint(String),
     TokenExpired(String),
     RateLimited(String),
+    InsufficientSpace(String),
+    PathConflict(String),
+    MalformedPath(String),
+    IntegrityError(String),
+    Unsupported(String),
+    UnknownBackend(String),
     Internal(String),
 }
 
@@ -29,6 +35,12 @@ impl fmt::Display for Error {
             Error::BadEndpoint(_) => f.write_str("BadEndpoint"),
             Error::TokenExpired(_) => f.write_str("TokenExpired"),
             Error::RateLimited(_) => f.write_str("RateLimited"),
+            Error::InsufficientSpace(_) => f.write_str("InsufficientSpace"),
+            Error::PathConflict(ref msg) => f.write_str(&format!("PathConflict: {}", msg)),
+            Error::MalformedPath(ref msg) => f.write_str(&format!("MalformedPath: {}", msg)),
+            Error::IntegrityError(ref msg) => f.write_str(&format!("IntegrityError: {}", msg)),
+            Error::Unsupported(ref msg) => f.write_str(&format!("Unsupported: {}", msg)),
+            Error::UnknownBackend(ref name) => f.write_str(&format!("UnknownBackend: {}", name)),
             Error::Internal(_) => f.write_str("Internal Error"),
         }
     }
@@ -52,6 +64,41 @@ impl From<reqwest::Error> for Error {
     }
 }
 
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::NotFound | std::io::ErrorKind::PermissionDenied => {
+                Self::BadEndpoint(err.to_string())
+            }
+            _ => Self::Internal(err.to_string()),
+        }
+    }
+}
+
+impl From<ssh2::Error> for Error {
+    fn from(err: ssh2::Error) -> Self {
+        Self::Internal(err.to_string())
+    }
+}
+
+impl From<git2::Error> for Error {
+    fn from(err: git2::Error) -> Self {
+        match err.code() {
+            git2::ErrorCode::Auth => Self::TokenExpired(err.to_string()),
+            _ => Self::Internal(err.to_string()),
+        }
+    }
+}
+
+impl From<imap::error::Error> for Error {
+    fn from(err: imap::error::Error) -> Self {
+        match err {
+            imap::error::Error::Io(e) => e.into(),
+            _ => Self::Internal(err.to_string()),
+        }
+    }
+}
+
 impl From<serde_json::error::Error> for Error {
     fn from(err: serde_json::error::Error) -> Self {
         Self::JsonParseError(err.to_string())
@@ -0,0 +1,131 @@
+use crate::storage::client::Metadata;
+use crate::storage::Error;
+
+use reqwest::StatusCode;
+
+use serde::Deserialize;
+
+pub const GDRIVE_BASE_URL: &str = "https://www.googleapis.com/";
+pub const GDRIVE_FOLDER_MIME: &str = "application/vnd.google-apps.folder";
+
+// Request timeout, in seconds
+pub(crate) const GDRIVE_REQUEST_TIMEOUT: u64 = 30;
+
+// Maximum size of a custom file property (key and value), in bytes
+pub(crate) const GDRIVE_PROPERTY_LEN: usize = 124;
+
+// Size of each chunk in a resumable upload, in bytes
+// Drive requires every chunk except the last to be a multiple of 256 KB.
+pub(crate) const GDRIVE_CHUNK_SIZE: usize = 32 * 256 * 1024;
+
+/// Map possible Drive API errors to generic storage backend error
+pub async fn map_status(resp: reqwest::Response) -> Result<reqwest::Response, Error> {
+    let status = resp.status();
+
+    if status.is_success() {
+        return Ok(resp);
+    }
+
+    let body = resp.text().await.unwrap_or_default();
+    let msg = format!("{}: {}", status, body);
+
+    match status {
+        StatusCode::BAD_REQUEST => Err(Error::BadInput(msg)),
+        StatusCode::UNAUTHORIZED => Err(Error::TokenExpired(msg)),
+        // Drive reports rate limits as a 403 with a reason, either
+        // `rateLimitExceeded` or `userRateLimitExceeded`
+        StatusCode::FORBIDDEN if body.contains("ateLimitExceeded") => Err(Error::RateLimited(msg)),
+        StatusCode::FORBIDDEN => Err(Error::TokenExpired(msg)),
+        StatusCode::NOT_FOUND => Err(Error::BadEndpoint(msg)),
+        StatusCode::TOO_MANY_REQUESTS => Err(Error::RateLimited(msg)),
+        _ => Err(Error::Internal(msg)),
+    }
+}
+
+pub enum Endpoint {
+    Files,
+    ResumableUpload,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct File {
+    pub id: String,
+    pub name: String,
+    pub mime_type: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct FileList {
+    pub files: Vec<File>,
+}
+
+/// Escape a value for use in a Drive search query string literal
+pub fn escape_query(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
+/// Drive metadata for a new file in `folder_id`.
+///
+/// The email fields are kept as custom file properties, truncated to fit.
+pub fn file_metadata(name: &str, folder_id: &str, metadata: &Metadata) -> serde_json::Value {
+    let mut body = serde_json::json!({ "name": name, "parents": [folder_id] });
+
+    if let Some(modified) = metadata.modified {
+        body["modifiedTime"] = modified.to_rfc3339().into();
+    }
+
+    let mut properties = serde_json::Map::new();
+
+    for (k, v) in metadata.fields() {
+        let mut len = std::cmp::min(GDRIVE_PROPERTY_LEN - k.len(), v.len());
+        while !v.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        properties.insert(k.to_string(), v[..len].into());
+    }
+
+    if !properties.is_empty() {
+        body["properties"] = properties.into();
+    }
+
+    body
+}
+
+#[inline]
+pub fn build_endpoint_url(base_url: &str, endpoint: Endpoint) -> String {
+    match endpoint {
+        Endpoint::Files => format!("{}{}", base_url, "drive/v3/files"),
+        Endpoint::ResumableUpload => format!(
+            "{}{}",
+            base_url, "upload/drive/v3/files?uploadType=resumable"
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_query() {
+        assert_eq!(escape_query("Bob's files"), "Bob\\'s files");
+        assert_eq!(escape_query("a\\b"), "a\\\\b");
+    }
+
+    #[test]
+    fn test_file_metadata() {
+        let metadata = Metadata {
+            subject: Some("é".repeat(100)),
+            ..Default::default()
+        };
+
+        let body = file_metadata("a.txt", "1234", &metadata);
+        let subject = body["properties"]["subject"].as_str().unwrap();
+
+        // Property key and value must fit in 124 bytes
+        assert_eq!(subject.len(), 116);
+        assert!(body.get("modifiedTime").is_none());
+    }
+}
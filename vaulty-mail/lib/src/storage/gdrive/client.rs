@@ -0,0 +1,416 @@
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use futures::stream::StreamExt;
+use reqwest::header::{CONTENT_RANGE, LOCATION, RANGE};
+use reqwest::StatusCode;
+
+use super::api;
+
+use crate::storage::client::{ByteStream, Client, ClientFuture, Metadata, StoredObject};
+use crate::storage::Error;
+
+pub struct GdriveClient<'a> {
+    token: &'a str,
+    base_url: String,
+    client: reqwest::Client,
+}
+
+impl<'a> GdriveClient<'a> {
+    pub fn from_token(token: &'a str) -> Self {
+        // Resumable uploads respond with a 308 that must not be followed
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(api::GDRIVE_REQUEST_TIMEOUT))
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        Self {
+            token,
+            base_url: api::GDRIVE_BASE_URL.to_string(),
+            client,
+        }
+    }
+
+    /// Override the API base URL (e.g., to point at a mock server)
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.base_url = if base_url.ends_with('/') {
+            base_url.to_string()
+        } else {
+            format!("{}/", base_url)
+        };
+        self
+    }
+
+    /// Find a folder by name under the given parent folder ID
+    pub async fn find_folder(&self, parent: &str, name: &str) -> Result<Option<api::File>, Error> {
+        let url = api::build_endpoint_url(&self.base_url, api::Endpoint::Files);
+        let query = format!(
+            "'{}' in parents and name = '{}' and mimeType = '{}' and trashed = false",
+            api::escape_query(parent),
+            api::escape_query(name),
+            api::GDRIVE_FOLDER_MIME
+        );
+
+        let req = self
+            .client
+            .get(reqwest::Url::parse(&url)?)
+            .bearer_auth(self.token)
+            .query(&[("q", query.as_str()), ("fields", "files(id,name,mimeType)")]);
+
+        let resp = api::map_status(req.send().await?).await?;
+        let list: api::FileList = serde_json::from_slice(&resp.bytes().await?)?;
+
+        Ok(list.files.into_iter().next())
+    }
+
+    /// Create a folder under the given parent folder ID
+    pub async fn create_folder(&self, parent: &str, name: &str) -> Result<api::File, Error> {
+        let url = api::build_endpoint_url(&self.base_url, api::Endpoint::Files);
+        let body = serde_json::json!({
+            "name": name,
+            "mimeType": api::GDRIVE_FOLDER_MIME,
+            "parents": [parent],
+        });
+
+        let req = self
+            .client
+            .post(reqwest::Url::parse(&url)?)
+            .bearer_auth(self.token)
+            .json(&body);
+
+        let resp = api::map_status(req.send().await?).await?;
+        serde_json::from_slice(&resp.bytes().await?).map_err(|e| e.into())
+    }
+
+    /// Resolve a path like `/vaulty/receipts` into a folder ID.
+    ///
+    /// Any missing folders along the path are created.
+    pub async fn resolve_folder(&self, path: &str) -> Result<String, Error> {
+        let mut parent = "root".to_string();
+
+        for name in path.split('/').filter(|s| !s.is_empty()) {
+            parent = match self.find_folder(&parent, name).await? {
+                Some(folder) => folder.id,
+                None => self.create_folder(&parent, name).await?.id,
+            };
+        }
+
+        Ok(parent)
+    }
+
+    /// Start a resumable upload session and return the session URI
+    async fn start_upload(
+        &self,
+        folder_id: &str,
+        name: &str,
+        metadata: &Metadata,
+    ) -> Result<String, Error> {
+        let url = api::build_endpoint_url(&self.base_url, api::Endpoint::ResumableUpload);
+        let body = api::file_metadata(name, folder_id, metadata);
+
+        let req = self
+            .client
+            .post(reqwest::Url::parse(&url)?)
+            .bearer_auth(self.token)
+            .json(&body);
+
+        let resp = api::map_status(req.send().await?).await?;
+
+        resp.headers()
+            .get(LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from)
+            .ok_or_else(|| Error::Internal("No upload session URI returned".to_string()))
+    }
+
+    /// Send a single chunk of a resumable upload starting at `offset`.
+    ///
+    /// `total` must be set for the final chunk. Returns `true` once Drive
+    /// reports the upload as complete.
+    async fn upload_chunk(
+        &self,
+        session: &str,
+        offset: usize,
+        data: Bytes,
+        total: Option<usize>,
+    ) -> Result<bool, Error> {
+        let end = offset + data.len();
+        let content_range = match total {
+            Some(total) if data.is_empty() => format!("bytes */{}", total),
+            Some(total) => format!("bytes {}-{}/{}", offset, end - 1, total),
+            None => format!("bytes {}-{}/*", offset, end - 1),
+        };
+
+        let req = self
+            .client
+            .put(reqwest::Url::parse(session)?)
+            .bearer_auth(self.token)
+            .header(CONTENT_RANGE, content_range)
+            .body(data);
+
+        let resp = req.send().await?;
+
+        // 308 means Drive is waiting for more data
+        if resp.status() == StatusCode::PERMANENT_REDIRECT {
+            // Make sure Drive persisted the whole chunk
+            let range = resp
+                .headers()
+                .get(RANGE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.rsplit('-').next())
+                .and_then(|v| v.parse::<usize>().ok());
+
+            return match range {
+                Some(last) if last + 1 >= end => Ok(false),
+                _ => Err(Error::Internal(format!(
+                    "Incomplete chunk upload (range: {:?})",
+                    range
+                ))),
+            };
+        }
+
+        let _resp = api::map_status(resp).await?.bytes().await?;
+
+        Ok(true)
+    }
+}
+
+fn map_stream_error(err: crate::Error) -> Error {
+    Error::Internal(err.to_string())
+}
+
+impl<'a> Client for GdriveClient<'a> {
+    /// Upload a file to a user's Google Drive using a resumable upload.
+    ///
+    /// The stream is sent in fixed-size chunks, so at most one chunk is
+    /// buffered in memory at a time.
+    fn upload_stream(
+        &self,
+        path: &str,
+        data: ByteStream,
+        metadata: &Metadata,
+    ) -> ClientFuture<'_, Option<StoredObject>> {
+        let metadata = metadata.clone();
+        let (folder, name) = match path.rfind('/') {
+            Some(i) => (path[..i].to_string(), path[i + 1..].to_string()),
+            None => (String::new(), path.to_string()),
+        };
+
+        Box::pin(async move {
+            let folder_id = self.resolve_folder(&folder).await?;
+            let session = self.start_upload(&folder_id, &name, &metadata).await?;
+
+            let mut data = Box::pin(data);
+            let mut buf = BytesMut::with_capacity(api::GDRIVE_CHUNK_SIZE);
+            let mut offset = 0;
+            let mut done = false;
+
+            loop {
+                // Read until we know whether the current chunk is the last one
+                while buf.len() <= api::GDRIVE_CHUNK_SIZE && !done {
+                    match data.next().await {
+                        Some(chunk) => buf.extend_from_slice(&chunk.map_err(map_stream_error)?),
+                        None => done = true,
+                    }
+                }
+
+                if done {
+                    let total = offset + buf.len();
+
+                    let finished = self
+                        .upload_chunk(&session, offset, buf.freeze(), Some(total))
+                        .await?;
+
+                    if !finished {
+                        return Err(Error::Internal(format!(
+                            "Upload of {} was not finalized",
+                            name
+                        )));
+                    }
+
+                    return Ok(None);
+                }
+
+                let chunk = buf.split_to(api::GDRIVE_CHUNK_SIZE).freeze();
+
+                self.upload_chunk(&session, offset, chunk, None).await?;
+                offset += api::GDRIVE_CHUNK_SIZE;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::storage::gdrive::mock::MockGdrive;
+
+    /// Status and body of an error response, and a check of the error
+    type ErrorCase = (u16, &'static str, fn(&Error) -> bool);
+
+    fn upload_data(size: usize) -> ByteStream {
+        let chunk = Bytes::from(vec![0u8; size]);
+        Box::pin(futures::stream::iter(vec![Ok(chunk)]))
+    }
+
+    #[tokio::test]
+    async fn test_mock_resolve_folder() {
+        let server = MockGdrive::start();
+        let client = server.client();
+
+        let id = client.resolve_folder("/vaulty/test").await.unwrap();
+
+        // Existing folders are reused
+        assert_eq!(client.resolve_folder("/vaulty//test/").await.unwrap(), id);
+        assert_eq!(client.resolve_folder("").await.unwrap(), "root");
+
+        assert_eq!(server.children("root"), vec!["vaulty/"]);
+        assert_eq!(server.state.lock().unwrap().files.len(), 2);
+
+        // Names are escaped in search queries
+        let id = client.resolve_folder("/Bob's files").await.unwrap();
+        assert_eq!(client.resolve_folder("/Bob's files").await.unwrap(), id);
+        assert_eq!(server.children("root"), vec!["Bob's files/", "vaulty/"]);
+    }
+
+    #[tokio::test]
+    async fn test_mock_upload_stream() {
+        let server = MockGdrive::start();
+        let client = server.client();
+
+        let metadata = Metadata {
+            subject: Some("Invoice".to_string()),
+            ..Default::default()
+        };
+        let data =
+            futures::stream::iter(vec![Ok(Bytes::from("Hello ")), Ok(Bytes::from("there!"))]);
+
+        client
+            .upload_stream("/vaulty/test.txt", Box::pin(data), &metadata)
+            .await
+            .unwrap();
+
+        let folder_id = client.resolve_folder("/vaulty").await.unwrap();
+        assert_eq!(server.children(&folder_id), vec!["test.txt"]);
+
+        let state = server.state.lock().unwrap();
+        let file = state.files.iter().find(|f| f.name == "test.txt").unwrap();
+
+        assert_eq!(file.data, b"Hello there!");
+        assert_eq!(file.properties["subject"], "Invoice");
+        assert_eq!(state.chunks, 1);
+    }
+
+    #[tokio::test]
+    async fn test_mock_upload_stream_chunks() {
+        let server = MockGdrive::start();
+        let client = server.client();
+
+        // Two and a half chunks, sent as chunks of a full chunk each
+        let size = api::GDRIVE_CHUNK_SIZE * 5 / 2;
+
+        client
+            .upload_stream("/test.bin", upload_data(size), &Metadata::default())
+            .await
+            .unwrap();
+
+        let state = server.state.lock().unwrap();
+
+        assert_eq!(state.files[0].data.len(), size);
+        assert_eq!(state.chunks, 3);
+    }
+
+    #[tokio::test]
+    async fn test_mock_errors() {
+        let server = MockGdrive::start();
+        let client = server.client();
+
+        let cases: Vec<ErrorCase> = vec![
+            (401, "Invalid Credentials", |e| {
+                matches!(e, Error::TokenExpired(_))
+            }),
+            (403, "userRateLimitExceeded", |e| {
+                matches!(e, Error::RateLimited(_))
+            }),
+            (403, "rateLimitExceeded", |e| {
+                matches!(e, Error::RateLimited(_))
+            }),
+            (403, "insufficientPermissions", |e| {
+                matches!(e, Error::TokenExpired(_))
+            }),
+            (404, "File not found", |e| {
+                matches!(e, Error::BadEndpoint(_))
+            }),
+            (429, "Too many requests", |e| {
+                matches!(e, Error::RateLimited(_))
+            }),
+        ];
+
+        for (status, body, expected) in cases {
+            server.state.lock().unwrap().error = Some((status, body));
+
+            match client.resolve_folder("/vaulty").await {
+                Err(e) if expected(&e) => (),
+                r => panic!("Unexpected result for {} {}: {:?}", status, body, r),
+            }
+        }
+
+        // A bad token is reported as expired
+        let client =
+            GdriveClient::from_token("bad-token").with_base_url(&format!("http://{}", server.addr));
+
+        match client
+            .upload_stream("/test.bin", upload_data(1), &Metadata::default())
+            .await
+        {
+            Err(Error::TokenExpired(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    fn get_client(token: &str) -> GdriveClient<'_> {
+        let client = GdriveClient::from_token(token);
+
+        match std::env::var("GDRIVE_BASE_URL") {
+            Ok(url) => client.with_base_url(&url),
+            Err(_) => client,
+        }
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_resolve_folder() {
+        let token = std::env::var("GDRIVE_TOKEN").expect("No Google Drive token found");
+        let client = get_client(&token);
+
+        let id = client.resolve_folder("/vaulty/test").await.unwrap();
+
+        assert!(!id.is_empty());
+        assert_eq!(client.resolve_folder("/vaulty/test").await.unwrap(), id);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_upload_stream() {
+        let token = std::env::var("GDRIVE_TOKEN").expect("No Google Drive token found");
+        let client = get_client(&token);
+        let data = futures::stream::iter(vec![Ok(Bytes::from("Hello there!"))]);
+
+        client
+            .upload_stream(
+                "/vaulty/vaulty_test.txt",
+                Box::pin(data),
+                &Metadata::default(),
+            )
+            .await
+            .unwrap();
+
+        let folder_id = client.resolve_folder("/vaulty").await.unwrap();
+        assert!(client
+            .find_folder(&folder_id, "vaulty_test.txt")
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
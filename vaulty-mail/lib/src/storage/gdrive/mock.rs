@@ -0,0 +1,313 @@
+//! In-memory mock of the Drive API endpoints used by `GdriveClient`.
+//!
+//! Setting `State::error` makes the mock answer the next request with that
+//! status code and body, which is used to test error mapping.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use serde_json::{json, Value};
+use warp::http::Response;
+use warp::{Filter, Rejection, Reply};
+
+use super::api;
+use super::client::GdriveClient;
+
+pub const MOCK_ACCESS_TOKEN: &str = "mock-access-token";
+
+pub struct MockFile {
+    pub id: String,
+    pub name: String,
+    pub parent: String,
+    pub mime_type: String,
+    pub properties: Value,
+    pub data: Vec<u8>,
+}
+
+/// A resumable upload in progress
+struct Session {
+    metadata: Value,
+    data: Vec<u8>,
+}
+
+/// Mock Drive state
+pub struct State {
+    pub files: Vec<MockFile>,
+    /// Status and body of the response to the next request
+    pub error: Option<(u16, &'static str)>,
+    /// Number of chunks received for resumable uploads
+    pub chunks: usize,
+    base_url: String,
+    sessions: HashMap<String, Session>,
+    next_id: usize,
+}
+
+impl State {
+    fn new() -> Self {
+        Self {
+            files: Vec::new(),
+            error: None,
+            chunks: 0,
+            base_url: String::new(),
+            sessions: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    fn next_id(&mut self) -> String {
+        self.next_id += 1;
+        format!("id-{}", self.next_id)
+    }
+
+    fn create(&mut self, metadata: &Value, mime_type: &str, data: Vec<u8>) -> Value {
+        let file = MockFile {
+            id: self.next_id(),
+            name: metadata["name"].as_str().unwrap_or("").to_string(),
+            parent: metadata["parents"][0]
+                .as_str()
+                .unwrap_or("root")
+                .to_string(),
+            mime_type: mime_type.to_string(),
+            properties: metadata["properties"].clone(),
+            data,
+        };
+
+        let body = file_metadata(&file);
+        self.files.push(file);
+
+        body
+    }
+}
+
+fn file_metadata(file: &MockFile) -> Value {
+    json!({ "id": file.id, "name": file.name, "mimeType": file.mime_type })
+}
+
+fn reply(status: u16, body: String) -> Response<String> {
+    Response::builder().status(status).body(body).unwrap()
+}
+
+/// Common request handling: auth and error injection
+fn handle(
+    state: &Mutex<State>,
+    auth: Option<String>,
+    handler: impl FnOnce(&mut State) -> Response<String>,
+) -> Response<String> {
+    let mut state = state.lock().unwrap();
+
+    if let Some((status, body)) = state.error.take() {
+        return reply(status, body.to_string());
+    }
+
+    if auth != Some(format!("Bearer {}", MOCK_ACCESS_TOKEN)) {
+        return reply(401, r#"{"error": {"code": 401}}"#.to_string());
+    }
+
+    handler(&mut state)
+}
+
+/// String literals of a Drive search query, unescaped, in order
+fn query_literals(q: &str) -> Vec<String> {
+    let mut literals = Vec::new();
+    let mut current: Option<String> = None;
+    let mut chars = q.chars();
+
+    while let Some(c) = chars.next() {
+        match (&mut current, c) {
+            (None, '\'') => current = Some(String::new()),
+            (None, _) => (),
+            (Some(s), '\\') => s.extend(chars.next()),
+            (Some(_), '\'') => literals.extend(current.take()),
+            (Some(s), c) => s.push(c),
+        }
+    }
+
+    literals
+}
+
+fn list_files(state: &mut State, query: &str) -> Response<String> {
+    let params = url::form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect::<HashMap<String, String>>();
+    let literals = query_literals(params.get("q").map(|s| s.as_str()).unwrap_or(""));
+
+    let files = match literals.as_slice() {
+        [parent, name, mime_type] => state
+            .files
+            .iter()
+            .filter(|f| &f.parent == parent && &f.name == name && &f.mime_type == mime_type)
+            .map(file_metadata)
+            .collect::<Vec<_>>(),
+        _ => return reply(400, "Invalid query".to_string()),
+    };
+
+    reply(200, json!({ "files": files }).to_string())
+}
+
+fn create_folder(state: &mut State, metadata: Value) -> Response<String> {
+    let mime_type = metadata["mimeType"].as_str().unwrap_or("").to_string();
+    let body = state.create(&metadata, &mime_type, Vec::new());
+
+    reply(200, body.to_string())
+}
+
+fn start_upload(state: &mut State, metadata: Value) -> Response<String> {
+    let session_id = state.next_id();
+    let location = format!("{}upload/sessions/{}", state.base_url, session_id);
+
+    state.sessions.insert(
+        session_id,
+        Session {
+            metadata,
+            data: Vec::new(),
+        },
+    );
+
+    Response::builder()
+        .header("location", location)
+        .body(String::new())
+        .unwrap()
+}
+
+/// Receive a chunk, checking its range like Drive does
+fn upload_chunk(
+    state: &mut State,
+    session_id: &str,
+    range: Option<String>,
+    body: Bytes,
+) -> Response<String> {
+    state.chunks += 1;
+
+    // `bytes <first>-<last>/<total>` or `bytes */<total>`, where the total
+    // is `*` until the last chunk
+    let range = range.unwrap_or_default();
+    let (span, total) = match range.trim_start_matches("bytes ").split_once('/') {
+        Some((span, total)) => (span.to_string(), total.parse::<usize>().ok()),
+        None => return reply(400, "Invalid range".to_string()),
+    };
+
+    let session = match state.sessions.get_mut(session_id) {
+        Some(session) => session,
+        None => return reply(404, "No such session".to_string()),
+    };
+
+    if span != "*" {
+        let first = span.split('-').next().and_then(|s| s.parse::<usize>().ok());
+
+        if first != Some(session.data.len()) {
+            return reply(400, "Invalid offset".to_string());
+        }
+
+        session.data.extend_from_slice(&body);
+    }
+
+    match total {
+        Some(total) if total == session.data.len() => {
+            let session = state.sessions.remove(session_id).unwrap();
+            let body = state.create(&session.metadata, "text/plain", session.data);
+
+            reply(200, body.to_string())
+        }
+        Some(_) => reply(400, "Invalid total size".to_string()),
+        None => Response::builder()
+            .status(308)
+            .header("range", format!("bytes=0-{}", session.data.len() - 1))
+            .body(String::new())
+            .unwrap(),
+    }
+}
+
+fn routes(
+    state: Arc<Mutex<State>>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone + Send + Sync + 'static {
+    let with_state = warp::any().map(move || state.clone());
+    let auth = warp::header::optional::<String>("authorization");
+
+    let list = warp::get()
+        .and(warp::path!("drive" / "v3" / "files"))
+        .and(with_state.clone())
+        .and(auth)
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .map(|state: Arc<Mutex<State>>, auth, query: String| {
+            handle(&state, auth, |state| list_files(state, &query))
+        });
+
+    let create = warp::post()
+        .and(warp::path!("drive" / "v3" / "files"))
+        .and(with_state.clone())
+        .and(auth)
+        .and(warp::body::json())
+        .map(|state: Arc<Mutex<State>>, auth, metadata| {
+            handle(&state, auth, |state| create_folder(state, metadata))
+        });
+
+    let start = warp::post()
+        .and(warp::path!("upload" / "drive" / "v3" / "files"))
+        .and(with_state.clone())
+        .and(auth)
+        .and(warp::body::json())
+        .map(|state: Arc<Mutex<State>>, auth, metadata| {
+            handle(&state, auth, |state| start_upload(state, metadata))
+        });
+
+    let chunk = warp::put()
+        .and(warp::path!("upload" / "sessions" / String))
+        .and(with_state)
+        .and(auth)
+        .and(warp::header::optional::<String>("content-range"))
+        .and(warp::body::bytes())
+        .map(
+            |session_id: String, state: Arc<Mutex<State>>, auth, range, body| {
+                handle(&state, auth, |state| {
+                    upload_chunk(state, &session_id, range, body)
+                })
+            },
+        );
+
+    list.or(create).or(start).or(chunk)
+}
+
+/// A mock Drive server running on an ephemeral local port.
+/// Must be started from within a Tokio runtime.
+pub struct MockGdrive {
+    pub addr: SocketAddr,
+    pub state: Arc<Mutex<State>>,
+}
+
+impl MockGdrive {
+    pub fn start() -> Self {
+        let state = Arc::new(Mutex::new(State::new()));
+        let (addr, server) = warp::serve(routes(state.clone())).bind_ephemeral(([127, 0, 0, 1], 0));
+
+        tokio::spawn(server);
+
+        state.lock().unwrap().base_url = format!("http://{}/", addr);
+
+        Self { addr, state }
+    }
+
+    /// Build a client for this server with a valid access token
+    pub fn client(&self) -> GdriveClient<'static> {
+        GdriveClient::from_token(MOCK_ACCESS_TOKEN).with_base_url(&format!("http://{}", self.addr))
+    }
+
+    /// Names of the folders and files under `parent`, sorted
+    pub fn children(&self, parent: &str) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+
+        let mut names = state
+            .files
+            .iter()
+            .filter(|f| f.parent == parent)
+            .map(|f| match f.mime_type.as_str() {
+                api::GDRIVE_FOLDER_MIME => format!("{}/", f.name),
+                _ => f.name.clone(),
+            })
+            .collect::<Vec<_>>();
+        names.sort();
+
+        names
+    }
+}
@@ -0,0 +1,131 @@
+use crate::storage::Error;
+
+use serde::Deserialize;
+
+pub const GIT_DEFAULT_BRANCH: &str = "master";
+pub const GIT_COMMITTER_NAME: &str = "Vaulty";
+pub const GIT_COMMITTER_EMAIL: &str = "noreply@vaulty.net";
+
+// Trailer used to tie a commit to the email it was created for
+pub const GIT_EMAIL_TRAILER: &str = "Vaulty-Email-ID";
+
+// Number of times to retry a commit if the branch moves underneath us
+pub(crate) const GIT_MAX_RETRIES: usize = 5;
+
+// File modes for tree entries
+pub(crate) const GIT_FILE_MODE: i32 = 0o100644;
+pub(crate) const GIT_TREE_MODE: i32 = 0o040000;
+
+fn default_branch() -> String {
+    GIT_DEFAULT_BRANCH.to_string()
+}
+
+/// Repository settings for an address.
+///
+/// Stored as JSON in the address storage token. `repo` is the path to a
+/// bare repository under the folder of the address on the mail server.
+///
+/// If `remote` (an `https://` or SSH URL) is set, the branch is pushed after
+/// every commit. Pushing requires credentials of the address: a password
+/// (or access token), or a private key (PEM).
+#[derive(Clone, Deserialize, Debug)]
+pub struct Settings {
+    pub repo: String,
+    #[serde(default = "default_branch")]
+    pub branch: String,
+    pub remote: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub private_key: Option<String>,
+    pub passphrase: Option<String>,
+}
+
+impl Settings {
+    pub fn from_token(token: &str) -> Result<Self, Error> {
+        let settings: Self = serde_json::from_str(token)
+            .map_err(|e| Error::BadInput(format!("Invalid git settings: {}", e)))?;
+
+        if let Some(remote) = &settings.remote {
+            if !is_network_url(remote) {
+                return Err(Error::BadInput(format!("Invalid git remote: {}", remote)));
+            }
+
+            if settings.password.is_none() && settings.private_key.is_none() {
+                return Err(Error::BadInput(
+                    "Git remotes require a password or private key".to_string(),
+                ));
+            }
+        }
+
+        Ok(settings)
+    }
+}
+
+/// Check that a remote URL points to another host, rather than to a
+/// repository on the mail server: `https://`, `ssh://` or `user@host:path`
+pub fn is_network_url(url: &str) -> bool {
+    if url.starts_with("https://") || url.starts_with("ssh://") {
+        return true;
+    }
+
+    // scp-like syntax
+    match url.find(':') {
+        Some(idx) => {
+            let host = &url[..idx];
+            !url.contains("://") && host.contains('@') && !host.contains('/')
+        }
+        None => false,
+    }
+}
+
+/// Build a commit message from an email subject
+pub fn commit_message(subject: Option<&str>, email_id: &str) -> String {
+    let subject = subject
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .unwrap_or("(no subject)");
+
+    format!("{}\n\n{}: {}\n", subject, GIT_EMAIL_TRAILER, email_id)
+}
+
+/// Check if a commit message was created for the given email
+pub fn is_email_commit(message: &str, email_id: &str) -> bool {
+    let trailer = format!("{}: {}", GIT_EMAIL_TRAILER, email_id);
+    message.lines().any(|l| l.trim() == trailer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commit_message() {
+        let message = commit_message(Some("Receipt #12 "), "abcd");
+
+        assert_eq!(message, "Receipt #12\n\nVaulty-Email-ID: abcd\n");
+        assert!(is_email_commit(&message, "abcd"));
+        assert!(!is_email_commit(&message, "abc"));
+        assert!(commit_message(None, "abcd").starts_with("(no subject)"));
+    }
+
+    #[test]
+    fn test_settings_from_token() {
+        let token = r#"{"repo": "docs.git", "remote": "git@example.com:alice/docs.git",
+                        "password": "secret"}"#;
+        assert!(Settings::from_token(token).is_ok());
+
+        // No credentials of the address
+        let token = r#"{"repo": "docs.git", "remote": "https://example.com/docs.git"}"#;
+        assert!(Settings::from_token(token).is_err());
+
+        for remote in &["/srv/git/other.git", "file:///srv/git/other.git", "origin"] {
+            let token = serde_json::json!({
+                "repo": "docs.git",
+                "remote": remote,
+                "password": "secret",
+            });
+
+            assert!(Settings::from_token(&token.to_string()).is_err());
+        }
+    }
+}
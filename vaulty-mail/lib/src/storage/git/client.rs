@@ -0,0 +1,362 @@
+use std::cell::Cell;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use bytes::Bytes;
+use futures::stream::Stream;
+use git2::{
+    Commit, Cred, CredentialType, ErrorCode, ObjectType, Oid, PushOptions, RemoteCallbacks,
+    Repository, Signature, Tree,
+};
+
+use super::api;
+
+use crate::email::Email;
+use crate::storage::client::{ByteStream, Client, ClientFuture, Metadata, StoredObject};
+use crate::storage::local::client::LocalClient;
+use crate::storage::util::autorename;
+use crate::storage::Error;
+
+// Max number of renamed candidates to try on a name collision
+const GIT_MAX_RENAMES: usize = 1000;
+
+/// Commits attachments into a branch of a local bare repository.
+///
+/// All attachments of an email end up in a single commit: if the branch tip
+/// was created for the same email, it is replaced by a commit that also
+/// contains the new attachment. Branches that are pushed only ever move
+/// forward, so there each attachment gets its own commit instead.
+///
+/// libgit2 is blocking, so all work happens on the blocking thread pool.
+pub struct GitClient {
+    settings: api::Settings,
+    repo: PathBuf,
+    author_email: String,
+    subject: Option<String>,
+    email_id: String,
+}
+
+impl GitClient {
+    /// Build a client from a JSON storage token (see `api::Settings`) for
+    /// the attachments of `email`.
+    ///
+    /// The repository path is resolved under `root`, and may not escape it.
+    pub fn from_token(token: &str, root: impl AsRef<Path>, email: &Email) -> Result<Self, Error> {
+        let settings = api::Settings::from_token(token)?;
+        let repo = LocalClient::new(root).resolve(&settings.repo)?;
+
+        Ok(Self {
+            settings,
+            repo,
+            author_email: email.sender.clone(),
+            subject: email.subject.clone(),
+            email_id: email.uuid.to_string(),
+        })
+    }
+}
+
+/// Build a new tree from `base` with `blob` inserted at `components`
+fn insert_path(
+    repo: &Repository,
+    base: Option<&Tree>,
+    components: &[&str],
+    blob: Oid,
+) -> Result<Oid, git2::Error> {
+    let mut builder = repo.treebuilder(base)?;
+
+    match components.split_first() {
+        Some((name, [])) => {
+            builder.insert(*name, blob, api::GIT_FILE_MODE)?;
+        }
+        Some((name, rest)) => {
+            let subtree = match base.and_then(|t| t.get_name(name)) {
+                Some(entry) if entry.kind() == Some(ObjectType::Tree) => {
+                    Some(repo.find_tree(entry.id())?)
+                }
+                _ => None,
+            };
+
+            let oid = insert_path(repo, subtree.as_ref(), rest, blob)?;
+            builder.insert(*name, oid, api::GIT_TREE_MODE)?;
+        }
+        None => unreachable!(),
+    }
+
+    builder.write()
+}
+
+/// Find a free file name in `dir` of `tree`, renaming on collision
+fn free_path(tree: Option<&Tree>, dir: &[&str], name: &str) -> Result<String, Error> {
+    for i in 0..GIT_MAX_RENAMES {
+        let candidate = autorename(name, i);
+
+        let mut path = dir.join("/");
+        if !path.is_empty() {
+            path.push('/');
+        }
+        path.push_str(&candidate);
+
+        let taken = tree
+            .map(|t| t.get_path(Path::new(&path)).is_ok())
+            .unwrap_or(false);
+
+        if !taken {
+            return Ok(candidate);
+        }
+    }
+
+    Err(Error::BadEndpoint(format!("Too many files named {}", name)))
+}
+
+impl GitClient {
+    fn author(&self) -> Result<Signature<'static>, git2::Error> {
+        let name = self
+            .author_email
+            .split('@')
+            .next()
+            .filter(|s| !s.is_empty())
+            .unwrap_or(self.author_email.as_str());
+
+        Signature::now(name, &self.author_email)
+    }
+
+    /// Commit a blob to the configured branch.
+    ///
+    /// The branch is only moved if it still points at the commit we built
+    /// on; otherwise we start over from the new tip.
+    fn commit_blob(&self, repo: &Repository, path: &str, blob: Oid) -> Result<Oid, Error> {
+        let refname = format!("refs/heads/{}", self.settings.branch);
+        let mut parts = path
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<&str>>();
+
+        let name = match parts.pop() {
+            Some(name) => name,
+            None => return Err(Error::BadInput(format!("Invalid path: {}", path))),
+        };
+
+        let message = api::commit_message(self.subject.as_deref(), &self.email_id);
+        let author = self.author()?;
+        let committer = Signature::now(api::GIT_COMMITTER_NAME, api::GIT_COMMITTER_EMAIL)?;
+
+        for _ in 0..api::GIT_MAX_RETRIES {
+            let tip = match repo.find_reference(&refname) {
+                Ok(r) => Some(r.peel_to_commit()?),
+                Err(ref e) if e.code() == ErrorCode::NotFound => None,
+                Err(e) => return Err(e.into()),
+            };
+
+            // Replace the tip if it holds earlier attachments of this email,
+            // unless it may have been pushed already
+            let parents: Vec<Commit> = match &tip {
+                Some(c)
+                    if self.settings.remote.is_none()
+                        && api::is_email_commit(c.message().unwrap_or(""), &self.email_id) =>
+                {
+                    c.parents().collect()
+                }
+                Some(c) => vec![c.clone()],
+                None => Vec::new(),
+            };
+
+            let base = match &tip {
+                Some(c) => Some(c.tree()?),
+                None => None,
+            };
+
+            let name = free_path(base.as_ref(), &parts, name)?;
+            let mut components = parts.clone();
+            components.push(&name);
+
+            let tree = repo.find_tree(insert_path(repo, base.as_ref(), &components, blob)?)?;
+            let parents = parents.iter().collect::<Vec<&Commit>>();
+            let oid = repo.commit(None, &author, &committer, &message, &tree, &parents)?;
+
+            let log_message = format!("vaulty: commit {}", path);
+            let updated = match &tip {
+                Some(c) => repo.reference_matching(&refname, oid, true, c.id(), &log_message),
+                None => repo.reference(&refname, oid, false, &log_message),
+            };
+
+            match updated {
+                Ok(_) => return Ok(oid),
+                // Branch moved since we read it: try again
+                Err(ref e) if e.code() == ErrorCode::Modified || e.code() == ErrorCode::Exists => {
+                    continue
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Err(Error::RateLimited(format!(
+            "Branch {} is being updated concurrently",
+            self.settings.branch
+        )))
+    }
+
+    /// Push the branch to the configured remote URL.
+    ///
+    /// Only the credentials of the address are ever offered. The push is a
+    /// plain fast-forward, so it never discards commits on the remote.
+    fn push(&self, repo: &Repository, url: &str) -> Result<(), Error> {
+        let mut remote = repo.remote_anonymous(url)?;
+        let settings = &self.settings;
+
+        // libgit2 asks again after a rejected attempt: only try once
+        let attempted = Cell::new(false);
+
+        let mut callbacks = RemoteCallbacks::new();
+        callbacks.credentials(|_url, username_from_url, allowed| {
+            if attempted.replace(true) {
+                return Err(git2::Error::from_str("Git credentials were rejected"));
+            }
+
+            let user = settings
+                .username
+                .as_deref()
+                .or(username_from_url)
+                .unwrap_or("git");
+
+            match (&settings.password, &settings.private_key) {
+                (Some(password), _) if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) => {
+                    Cred::userpass_plaintext(user, password)
+                }
+                (_, Some(key)) if allowed.contains(CredentialType::SSH_KEY) => {
+                    Cred::ssh_key_from_memory(user, None, key, settings.passphrase.as_deref())
+                }
+                _ => Err(git2::Error::from_str("No git credentials for this remote")),
+            }
+        });
+
+        // A rejected update does not fail the push itself
+        callbacks.push_update_reference(|refname, status| match status {
+            Some(msg) => Err(git2::Error::from_str(&format!(
+                "Push of {} rejected: {}",
+                refname, msg
+            ))),
+            None => Ok(()),
+        });
+
+        let mut options = PushOptions::new();
+        options.remote_callbacks(callbacks);
+
+        let refname = format!("refs/heads/{}", self.settings.branch);
+        let refspec = format!("{}:{}", refname, refname);
+
+        remote.push(&[refspec], Some(&mut options))?;
+
+        Ok(())
+    }
+
+    fn upload_blocking(
+        &self,
+        path: &str,
+        data: impl Stream<Item = Result<Bytes, crate::Error>>,
+    ) -> Result<(), Error> {
+        let repo =
+            Repository::open_bare(&self.repo).map_err(|e| Error::BadEndpoint(e.to_string()))?;
+
+        // Stream the attachment straight into the object database
+        let mut writer = repo.blob_writer(None)?;
+        for chunk in futures::executor::block_on_stream(Box::pin(data)) {
+            let chunk = chunk.map_err(|e| Error::Internal(e.to_string()))?;
+            writer.write_all(&chunk)?;
+        }
+        let blob = writer.commit()?;
+
+        let oid = self.commit_blob(&repo, path, blob)?;
+        log::debug!("Committed {} as {}", path, oid);
+
+        if let Some(remote) = &self.settings.remote {
+            self.push(&repo, remote)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Client for GitClient {
+    /// Commit a file to a git repository.
+    ///
+    /// The commit already carries the email details, so the metadata is
+    /// ignored.
+    fn upload_stream(
+        &self,
+        path: &str,
+        data: ByteStream,
+        _metadata: &Metadata,
+    ) -> ClientFuture<'_, Option<StoredObject>> {
+        let client = Self {
+            settings: self.settings.clone(),
+            repo: self.repo.clone(),
+            author_email: self.author_email.clone(),
+            subject: self.subject.clone(),
+            email_id: self.email_id.clone(),
+        };
+        let path = path.to_string();
+
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || client.upload_blocking(&path, data))
+                .await
+                .map_err(|e| Error::Internal(e.to_string()))?
+                .map(|_| None)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_upload_stream_single_commit() {
+        let root = std::env::temp_dir().join(format!("vaulty_git_{}", std::process::id()));
+        let repo_path = root.join("receipts.git");
+        let repo = Repository::init_bare(&repo_path).unwrap();
+
+        let mut email = Email::new();
+        email.sender = "alice@example.com".to_string();
+        email.subject = Some("Receipts".to_string());
+
+        let token = serde_json::json!({ "repo": "/receipts.git" }).to_string();
+        let client = GitClient::from_token(&token, &root, &email).unwrap();
+
+        for name in &["a.txt", "b.txt", "a.txt"] {
+            let data = futures::stream::iter(vec![Ok(Bytes::from("Hello there!"))]);
+            let result = client
+                .upload_stream(
+                    &format!("/vaulty/{}", name),
+                    Box::pin(data),
+                    &Metadata::default(),
+                )
+                .await;
+
+            assert!(result.is_ok());
+        }
+
+        // All three attachments are in a single commit
+        let tip = repo.find_reference("refs/heads/master").unwrap();
+        let commit = tip.peel_to_commit().unwrap();
+        let tree = commit.tree().unwrap();
+
+        assert_eq!(commit.parent_count(), 0);
+        assert_eq!(commit.author().email(), Some("alice@example.com"));
+        assert!(commit.message().unwrap().starts_with("Receipts"));
+        assert!(tree.get_path(Path::new("vaulty/a.txt")).is_ok());
+        assert!(tree.get_path(Path::new("vaulty/b.txt")).is_ok());
+        assert!(tree.get_path(Path::new("vaulty/a (1).txt")).is_ok());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_from_token_outside_root() {
+        let email = Email::new();
+
+        for repo in &["../other/receipts.git", "/srv/git/../../receipts.git"] {
+            let token = serde_json::json!({ "repo": repo }).to_string();
+            assert!(GitClient::from_token(&token, "/var/lib/vaulty/git/1", &email).is_err());
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use serde::Deserialize;
+
+use crate::storage::Error;
+
+pub(crate) const IMAP_DEFAULT_PORT: u16 = 993;
+pub(crate) const IMAP_DEFAULT_MAILBOX: &str = "Vaulty";
+
+fn default_port() -> u16 {
+    IMAP_DEFAULT_PORT
+}
+
+fn default_mailbox() -> String {
+    IMAP_DEFAULT_MAILBOX.to_string()
+}
+
+fn default_seen() -> bool {
+    true
+}
+
+/// IMAP server, credentials, and target mailbox for an address.
+///
+/// Stored as JSON in the address storage token. Connections always use
+/// implicit TLS (IMAPS). `accept_invalid_certs` is meant for local servers
+/// with self-signed certificates only.
+#[derive(Clone, Deserialize, Debug)]
+pub struct Settings {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub username: String,
+    pub password: String,
+    #[serde(default = "default_mailbox")]
+    pub mailbox: String,
+    #[serde(default = "default_seen")]
+    pub seen: bool,
+    #[serde(default)]
+    pub accept_invalid_certs: bool,
+}
+
+impl Settings {
+    pub fn from_token(token: &str) -> Result<Self, Error> {
+        serde_json::from_str(token)
+            .map_err(|e| Error::BadInput(format!("Invalid IMAP settings: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_settings_from_token() {
+        let token = r#"{"host": "127.0.0.1", "username": "alice", "password": "secret"}"#;
+        let settings = Settings::from_token(token).unwrap();
+
+        assert_eq!(settings.port, 993);
+        assert_eq!(settings.mailbox, "Vaulty");
+        assert!(settings.seen);
+        assert!(!settings.accept_invalid_certs);
+    }
+}
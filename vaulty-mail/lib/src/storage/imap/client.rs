@@ -0,0 +1,215 @@
+use std::net::TcpStream;
+use std::time::Duration;
+
+use bytes::BytesMut;
+use futures::stream::StreamExt;
+use imap::types::Flag;
+use native_tls::{TlsConnector, TlsStream};
+
+use super::api;
+
+use crate::storage::client::{ByteStream, Client, ClientFuture, Metadata, StoredObject};
+use crate::storage::Error;
+
+// Socket read/write timeout, in seconds
+const IMAP_TIMEOUT: u64 = 30;
+
+type Session = imap::Session<TlsStream<TcpStream>>;
+
+/// Archives whole messages into a mailbox on the user's IMAP server.
+///
+/// The imap crate is blocking, so all network work happens on the blocking
+/// thread pool.
+pub struct ImapClient {
+    settings: api::Settings,
+}
+
+impl ImapClient {
+    /// Build a client from a JSON storage token (see `api::Settings`)
+    pub fn from_token(token: &str) -> Result<Self, Error> {
+        Ok(Self {
+            settings: api::Settings::from_token(token)?,
+        })
+    }
+
+    /// Append a raw RFC 822 message to the configured mailbox
+    pub async fn append(&self, message: Vec<u8>) -> Result<(), Error> {
+        let settings = self.settings.clone();
+
+        tokio::task::spawn_blocking(move || append_blocking(&settings, &message))
+            .await
+            .map_err(|e| Error::Internal(e.to_string()))?
+    }
+}
+
+/// Open an authenticated IMAPS session
+fn connect(settings: &api::Settings) -> Result<Session, Error> {
+    let tcp = TcpStream::connect((settings.host.as_str(), settings.port))
+        .map_err(|e| Error::BadEndpoint(e.to_string()))?;
+
+    tcp.set_read_timeout(Some(Duration::from_secs(IMAP_TIMEOUT)))?;
+    tcp.set_write_timeout(Some(Duration::from_secs(IMAP_TIMEOUT)))?;
+
+    let connector = TlsConnector::builder()
+        .danger_accept_invalid_certs(settings.accept_invalid_certs)
+        .build()
+        .map_err(|e| Error::Internal(e.to_string()))?;
+
+    let tls = connector
+        .connect(&settings.host, tcp)
+        .map_err(|e| Error::BadEndpoint(e.to_string()))?;
+
+    let mut client = imap::Client::new(tls);
+    client.read_greeting()?;
+
+    client
+        .login(&settings.username, &settings.password)
+        .map_err(|(e, _)| {
+            Error::TokenExpired(format!(
+                "IMAP login failed for {}@{}: {}",
+                settings.username, settings.host, e
+            ))
+        })
+}
+
+/// Create the mailbox if it does not exist yet
+fn ensure_mailbox(session: &mut Session, mailbox: &str) -> Result<(), Error> {
+    if session.list(None, Some(mailbox))?.is_empty() {
+        log::debug!("Creating IMAP mailbox {}", mailbox);
+        session.create(mailbox)?;
+    }
+
+    Ok(())
+}
+
+fn append_blocking(settings: &api::Settings, message: &[u8]) -> Result<(), Error> {
+    let mut session = connect(settings)?;
+
+    ensure_mailbox(&mut session, &settings.mailbox)?;
+
+    let flags = if settings.seen {
+        vec![Flag::Seen]
+    } else {
+        Vec::new()
+    };
+
+    session.append_with_flags(&settings.mailbox, message, &flags)?;
+
+    log::debug!(
+        "Appended message to {} on {}",
+        settings.mailbox,
+        settings.host
+    );
+
+    // The message is stored at this point, so a failed logout is harmless
+    if let Err(e) = session.logout() {
+        log::warn!("IMAP logout failed: {}", e);
+    }
+
+    Ok(())
+}
+
+impl Client for ImapClient {
+    /// Append a stream holding an RFC 822 message to the configured mailbox.
+    ///
+    /// IMAP literals need their size up front, so the message is buffered
+    /// in full. The path is ignored: messages always go to the mailbox set
+    /// in the storage token. The metadata is ignored too, as the message
+    /// holds its own headers.
+    fn upload_stream(
+        &self,
+        _path: &str,
+        data: ByteStream,
+        _metadata: &Metadata,
+    ) -> ClientFuture<'_, Option<StoredObject>> {
+        Box::pin(async move {
+            let mut data = Box::pin(data);
+            let mut buf = BytesMut::new();
+
+            while let Some(chunk) = data.next().await {
+                buf.extend_from_slice(&chunk.map_err(|e| Error::Internal(e.to_string()))?);
+            }
+
+            self.append(buf.to_vec()).await.map(|_| None)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::Bytes;
+
+    fn message_data(subject: &str) -> ByteStream {
+        let message = format!(
+            "From: bob@example.com\r\n\
+             To: alice@vaulty.net\r\n\
+             Subject: {}\r\n\
+             \r\n\
+             Hello there!\r\n",
+            subject
+        );
+
+        Box::pin(futures::stream::iter(vec![Ok(Bytes::from(message))]))
+    }
+
+    #[tokio::test]
+    async fn test_upload_stream_unreachable() {
+        // A port nothing listens on
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let token = format!(
+            r#"{{"host": "127.0.0.1", "port": {}, "username": "alice", "password": "secret"}}"#,
+            port
+        );
+        let client = ImapClient::from_token(&token).unwrap();
+
+        match client
+            .upload_stream("", message_data("Vaulty test"), &Metadata::default())
+            .await
+        {
+            Err(Error::BadEndpoint(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    // Dovecot in a local container, e.g.:
+    // {"host": "localhost", "port": 993, "username": "alice", "password": "secret",
+    //  "accept_invalid_certs": true}
+    fn get_client() -> ImapClient {
+        let token = std::env::var("IMAP_TOKEN").expect("No IMAP token found");
+        ImapClient::from_token(&token).unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_upload_stream() {
+        let client = get_client();
+        let subject = format!("Vaulty test {}", chrono::Utc::now().timestamp_millis());
+
+        let result = client
+            .upload_stream(
+                "/vaulty/message.eml",
+                message_data(&subject),
+                &Metadata::default(),
+            )
+            .await
+            .unwrap();
+
+        // Messages are stored in the mailbox, not as files
+        assert!(result.is_none());
+
+        let mut session = connect(&client.settings).unwrap();
+        session.select(&client.settings.mailbox).unwrap();
+
+        let found = session.search(format!("SUBJECT \"{}\"", subject)).unwrap();
+        assert_eq!(found.len(), 1);
+
+        session.logout().unwrap();
+    }
+}
@@ -0,0 +1,466 @@
+use std::path::{Component, Path, PathBuf};
+
+use bytes::Bytes;
+use futures::stream::{Stream, StreamExt};
+use tokio::io::AsyncWriteExt;
+
+use crate::storage::client::{ByteStream, Client, ClientFuture, Entry, Metadata, StoredObject};
+use crate::storage::util::{autorename, temp_name};
+use crate::storage::Error;
+
+// Max number of renamed candidates to try on a name collision
+const LOCAL_MAX_RENAMES: usize = 1000;
+
+/// Stores files in a directory on the local filesystem.
+///
+/// All paths are resolved relative to a fixed root; storage paths and
+/// attachment names are never allowed to escape it.
+pub struct LocalClient {
+    root: PathBuf,
+}
+
+impl LocalClient {
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Map a storage path onto the filesystem under the root.
+    ///
+    /// Every component must be a plain file or folder name: `..`, `.`
+    /// and anything else that could escape the root is rejected.
+    pub fn resolve(&self, path: &str) -> Result<PathBuf, Error> {
+        let mut resolved = self.root.clone();
+
+        for part in path.split('/').filter(|p| !p.is_empty()) {
+            let mut components = Path::new(part).components();
+
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(c)), None) if !part.contains('\0') => resolved.push(c),
+                _ => return Err(Error::BadInput(format!("Invalid path: {}", path))),
+            }
+        }
+
+        if resolved == self.root {
+            return Err(Error::BadInput(format!("Invalid path: {}", path)));
+        }
+
+        Ok(resolved)
+    }
+
+    /// Map a path under the root back to a storage path
+    fn storage_path(&self, resolved: &Path) -> String {
+        let relative = resolved.strip_prefix(&self.root).unwrap_or(resolved);
+
+        relative
+            .components()
+            .map(|c| format!("/{}", c.as_os_str().to_string_lossy()))
+            .collect()
+    }
+
+    /// Guard against symlinks inside the root pointing elsewhere
+    async fn check_in_root(&self, dir: &Path) -> Result<(), Error> {
+        let root = tokio::fs::canonicalize(&self.root).await?;
+
+        if tokio::fs::canonicalize(dir).await?.starts_with(&root) {
+            Ok(())
+        } else {
+            Err(Error::BadInput(format!("Invalid path: {}", dir.display())))
+        }
+    }
+
+    /// Create `dir` and any missing parents.
+    ///
+    /// The nearest existing ancestor is checked before anything is
+    /// created, so that a symlink inside the root can never be used to
+    /// create folders outside of it.
+    async fn create_dir(&self, dir: &Path) -> Result<(), Error> {
+        tokio::fs::create_dir_all(&self.root).await?;
+
+        let mut ancestor = dir;
+
+        loop {
+            match tokio::fs::symlink_metadata(ancestor).await {
+                Ok(_) => break,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e.into()),
+            }
+
+            // `resolve` only returns paths under the root, which exists
+            ancestor = ancestor.parent().unwrap_or(self.root.as_path());
+        }
+
+        self.check_in_root(ancestor).await?;
+
+        tokio::fs::create_dir_all(dir).await?;
+        self.check_in_root(dir).await
+    }
+
+    /// Describe the file or folder at `resolved`, if any.
+    /// Symlinks are not followed.
+    async fn entry(&self, resolved: &Path) -> Result<Option<Entry>, Error> {
+        let metadata = match tokio::fs::symlink_metadata(resolved).await {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let path = self.storage_path(resolved);
+
+        if metadata.is_dir() {
+            Ok(Some(Entry::Folder {
+                id: path.clone(),
+                path,
+            }))
+        } else {
+            Ok(Some(Entry::File(StoredObject {
+                id: path.clone(),
+                path,
+                size: metadata.len(),
+                hash: None,
+            })))
+        }
+    }
+
+    /// Write a stream to a hidden temporary file in `dir`
+    async fn write_temp(
+        &self,
+        dir: &Path,
+        name: &str,
+        data: impl Stream<Item = Result<Bytes, crate::Error>>,
+    ) -> Result<PathBuf, Error> {
+        let tmp_path = dir.join(temp_name(name));
+
+        let mut file = tokio::fs::File::create(&tmp_path).await?;
+        let mut data = Box::pin(data);
+
+        let result: Result<(), Error> = async {
+            while let Some(chunk) = data.next().await {
+                let chunk = chunk.map_err(|e| Error::Internal(e.to_string()))?;
+                file.write_all(&chunk).await?;
+            }
+
+            file.sync_all().await?;
+
+            Ok(())
+        }
+        .await;
+
+        if let Err(e) = result {
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            return Err(e);
+        }
+
+        Ok(tmp_path)
+    }
+
+    /// Set the modification time of a file
+    async fn set_mtime(&self, path: &Path, mtime: filetime::FileTime) -> Result<(), Error> {
+        let path = path.to_path_buf();
+
+        tokio::task::spawn_blocking(move || filetime::set_file_mtime(&path, mtime))
+            .await
+            .map_err(|e| Error::Internal(e.to_string()))?
+            .map_err(|e| e.into())
+    }
+
+    /// Move a temporary file to `name` in `dir`, renaming on collision.
+    ///
+    /// Hard links are used so that an existing file is never clobbered.
+    async fn persist(&self, tmp_path: &Path, dir: &Path, name: &str) -> Result<PathBuf, Error> {
+        for i in 0..LOCAL_MAX_RENAMES {
+            let dest = dir.join(autorename(name, i));
+
+            match tokio::fs::hard_link(tmp_path, &dest).await {
+                Ok(_) => {
+                    tokio::fs::remove_file(tmp_path).await?;
+                    return Ok(dest);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => {
+                    let _ = tokio::fs::remove_file(tmp_path).await;
+                    return Err(e.into());
+                }
+            }
+        }
+
+        let _ = tokio::fs::remove_file(tmp_path).await;
+
+        Err(Error::BadEndpoint(format!(
+            "Too many files named {} in {}",
+            name,
+            dir.display()
+        )))
+    }
+}
+
+impl Client for LocalClient {
+    /// Write a file to the local filesystem.
+    ///
+    /// Data is streamed to a temporary file that is only moved into place
+    /// once it has been fully written.
+    fn upload_stream(
+        &self,
+        path: &str,
+        data: ByteStream,
+        metadata: &Metadata,
+    ) -> ClientFuture<'_, Option<StoredObject>> {
+        let resolved = self.resolve(path);
+        let mtime = metadata
+            .modified
+            .map(|t| filetime::FileTime::from_unix_time(t.timestamp(), 0));
+
+        Box::pin(async move {
+            let resolved = resolved?;
+
+            // `resolve` guarantees both a parent and a file name
+            let dir = resolved.parent().unwrap();
+            let name = resolved.file_name().unwrap().to_string_lossy().to_string();
+
+            self.create_dir(dir).await?;
+
+            let tmp_path = self.write_temp(dir, &name, data).await?;
+
+            // Only the modification time can be kept on a plain file
+            if let Some(mtime) = mtime {
+                if let Err(e) = self.set_mtime(&tmp_path, mtime).await {
+                    let _ = tokio::fs::remove_file(&tmp_path).await;
+                    return Err(e);
+                }
+            }
+
+            let dest = self.persist(&tmp_path, dir, &name).await?;
+
+            log::debug!("Wrote file to {}", dest.display());
+
+            self.entry(&dest).await.map(|entry| match entry {
+                Some(Entry::File(object)) => Some(object),
+                _ => None,
+            })
+        })
+    }
+
+    fn create_folder(&self, path: &str) -> ClientFuture<'_, ()> {
+        let resolved = self.resolve(path);
+
+        Box::pin(async move {
+            let resolved = resolved?;
+            self.create_dir(&resolved).await
+        })
+    }
+
+    fn stat(&self, path: &str) -> ClientFuture<'_, Option<Entry>> {
+        let resolved = self.resolve(path);
+
+        Box::pin(async move {
+            let resolved = resolved?;
+
+            // `resolve` guarantees a parent
+            let dir = resolved.parent().unwrap();
+            if tokio::fs::metadata(dir).await.is_err() {
+                return Ok(None);
+            }
+
+            self.check_in_root(dir).await?;
+            self.entry(&resolved).await
+        })
+    }
+
+    fn list(&self, path: &str) -> ClientFuture<'_, Vec<Entry>> {
+        let resolved = self.resolve(path);
+
+        Box::pin(async move {
+            let resolved = resolved?;
+            self.check_in_root(&resolved).await?;
+
+            let mut dir = tokio::fs::read_dir(&resolved).await?;
+            let mut entries = Vec::new();
+
+            while let Some(child) = dir.next_entry().await? {
+                // Skip uploads still in progress
+                let name = child.file_name().to_string_lossy().to_string();
+                if name.starts_with('.') && name.ends_with(".tmp") {
+                    continue;
+                }
+
+                if let Some(entry) = self.entry(&child.path()).await? {
+                    entries.push(entry);
+                }
+            }
+
+            entries.sort_by(|a, b| a.path().cmp(b.path()));
+
+            Ok(entries)
+        })
+    }
+
+    fn delete(&self, path: &str) -> ClientFuture<'_, ()> {
+        let resolved = self.resolve(path);
+        let path = path.to_string();
+
+        Box::pin(async move {
+            let resolved = resolved?;
+
+            match self.stat(&path).await? {
+                Some(Entry::Folder { .. }) => tokio::fs::remove_dir_all(&resolved).await?,
+                Some(Entry::File(_)) => tokio::fs::remove_file(&resolved).await?,
+                None => return Err(Error::BadEndpoint(format!("No such file: {}", path))),
+            }
+
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("vaulty_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    #[test]
+    fn test_resolve() {
+        let client = LocalClient::new("/srv/vaulty");
+
+        assert_eq!(
+            client.resolve("/vaulty//receipts/a.pdf").unwrap(),
+            PathBuf::from("/srv/vaulty/vaulty/receipts/a.pdf")
+        );
+        assert!(client.resolve("/vaulty/../../etc/passwd").is_err());
+        assert!(client.resolve("/vaulty/./a.pdf").is_err());
+        assert!(client.resolve("/").is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_upload_stream_symlink() {
+        let root = get_root("symlink");
+        let outside = get_root("symlink_outside");
+        let client = LocalClient::new(root.join("1"));
+
+        std::fs::create_dir_all(root.join("1")).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("1/link")).unwrap();
+
+        let data = futures::stream::iter(vec![Ok(Bytes::from("Hello there!"))]);
+        let result = client
+            .upload_stream("/link/a/test.txt", Box::pin(data), &Metadata::default())
+            .await;
+
+        match result {
+            Err(Error::BadInput(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+
+        assert!(client.create_folder("/link/b").await.is_err());
+
+        // Nothing was created through the link
+        assert_eq!(std::fs::read_dir(&outside).unwrap().count(), 0);
+
+        std::fs::remove_dir_all(&root).unwrap();
+        std::fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_upload_stream_collision() {
+        let root = get_root("collision");
+        let client = LocalClient::new(root.to_str().unwrap());
+
+        for _ in 0..2 {
+            let data = futures::stream::iter(vec![Ok(Bytes::from("Hello there!"))]);
+            let result = client
+                .upload_stream("/vaulty/test.txt", Box::pin(data), &Metadata::default())
+                .await;
+
+            assert!(result.is_ok());
+        }
+
+        let data = futures::stream::iter(vec![Ok(Bytes::from("Hello there!"))]);
+        let object = client
+            .upload_stream("/vaulty/test.txt", Box::pin(data), &Metadata::default())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(object.path, "/vaulty/test (2).txt");
+        assert_eq!(object.size, 12);
+
+        let contents = std::fs::read_to_string(root.join("vaulty/test (1).txt")).unwrap();
+        assert_eq!(contents, "Hello there!");
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_upload_stream_mtime() {
+        use chrono::{TimeZone, Utc};
+
+        let root = get_root("mtime");
+        let client = LocalClient::new(root.to_str().unwrap());
+
+        let metadata = Metadata {
+            modified: Some(Utc.timestamp_opt(1580693736, 0).unwrap()),
+            ..Default::default()
+        };
+        let data = futures::stream::iter(vec![Ok(Bytes::from("Hello there!"))]);
+
+        client
+            .upload_stream("/vaulty/test.txt", Box::pin(data), &metadata)
+            .await
+            .unwrap();
+
+        let modified = std::fs::metadata(root.join("vaulty/test.txt"))
+            .unwrap()
+            .modified()
+            .unwrap();
+        assert_eq!(
+            modified
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            1580693736
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_client_operations() {
+        let root = get_root("operations");
+        let client = LocalClient::new(root.to_str().unwrap());
+
+        client.create_folder("/vaulty/a/b").await.unwrap();
+
+        let data = futures::stream::iter(vec![Ok(Bytes::from("Hello there!"))]);
+        client
+            .upload_stream("/vaulty/a/test.txt", Box::pin(data), &Metadata::default())
+            .await
+            .unwrap();
+
+        let paths = client
+            .list("/vaulty/a")
+            .await
+            .unwrap()
+            .iter()
+            .map(|e| e.path().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, vec!["/vaulty/a/b", "/vaulty/a/test.txt"]);
+
+        match client.stat("/vaulty/a/test.txt").await.unwrap() {
+            Some(Entry::File(object)) => assert_eq!(object.size, 12),
+            e => panic!("Unexpected entry: {:?}", e),
+        }
+
+        client.delete("/vaulty/a").await.unwrap();
+
+        assert!(!client.exists("/vaulty/a/test.txt").await.unwrap());
+        assert!(client.exists("/vaulty").await.unwrap());
+        assert!(client.delete("/vaulty/a").await.is_err());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,7 +1,22 @@
+pub mod azure;
 mod backends;
 pub mod client;
 pub mod dropbox;
 mod error;
+pub mod gdrive;
+pub mod git;
+pub mod imap;
+pub mod local;
+pub mod notion;
+pub mod onedrive;
+pub mod paperless;
+pub mod registry;
+pub mod s3;
+pub mod sftp;
+mod util;
+pub mod webdav;
+mod xml;
 
 pub use backends::Backend;
 pub use error::Error;
+pub use registry::Registry;
@@ -0,0 +1,171 @@
+use crate::storage::Error;
+
+use reqwest::StatusCode;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+pub const NOTION_BASE_URL: &str = "https://api.notion.com/v1/";
+pub const NOTION_VERSION: &str = "2022-06-28";
+
+// Request timeout, in seconds
+pub(crate) const NOTION_REQUEST_TIMEOUT: u64 = 60;
+
+// Notion limits on rich text length and blocks per request
+pub(crate) const NOTION_MAX_TEXT_LENGTH: usize = 2000;
+pub(crate) const NOTION_MAX_BLOCKS: usize = 100;
+
+// Database properties that pages are created with
+pub(crate) const NOTION_TITLE_PROPERTY: &str = "Name";
+pub(crate) const NOTION_SENDER_PROPERTY: &str = "Sender";
+pub(crate) const NOTION_DATE_PROPERTY: &str = "Date";
+pub(crate) const NOTION_ID_PROPERTY: &str = "Vaulty ID";
+
+/// Notion integration token and target database for an address.
+///
+/// Stored as JSON in the address storage token. The database must have a
+/// `Name` title, a `Sender` email, a `Date` date, and a `Vaulty ID` text
+/// property; the latter ties a page to its email.
+#[derive(Clone, Deserialize, Debug)]
+pub struct Settings {
+    pub token: String,
+    pub database_id: String,
+}
+
+impl Settings {
+    pub fn from_token(token: &str) -> Result<Self, Error> {
+        serde_json::from_str(token)
+            .map_err(|e| Error::BadInput(format!("Invalid Notion settings: {}", e)))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Page {
+    pub id: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct QueryResult {
+    pub results: Vec<Page>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct FileUpload {
+    pub id: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NotionError {
+    pub code: String,
+    pub message: String,
+}
+
+/// Split text into chunks that fit in a single rich text object
+fn split_text(text: &str) -> Vec<String> {
+    let chars = text.chars().collect::<Vec<char>>();
+
+    chars
+        .chunks(NOTION_MAX_TEXT_LENGTH)
+        .map(|c| c.iter().collect())
+        .collect()
+}
+
+/// Convert a plain text body into paragraph blocks.
+/// Paragraphs are separated by blank lines.
+pub fn paragraph_blocks(body: &str) -> Vec<Value> {
+    body.replace("\r\n", "\n")
+        .split("\n\n")
+        .map(|p| p.trim())
+        .filter(|p| !p.is_empty())
+        .flat_map(split_text)
+        .map(|text| {
+            json!({
+                "object": "block",
+                "type": "paragraph",
+                "paragraph": {
+                    "rich_text": [{ "type": "text", "text": { "content": text } }]
+                }
+            })
+        })
+        .collect()
+}
+
+/// Build a file block for an uploaded file
+pub fn file_block(file_upload_id: &str, name: &str) -> Value {
+    json!({
+        "object": "block",
+        "type": "file",
+        "file": {
+            "type": "file_upload",
+            "file_upload": { "id": file_upload_id },
+            "caption": [{ "type": "text", "text": { "content": name } }]
+        }
+    })
+}
+
+/// Build the database properties for a new page
+pub fn page_properties(title: &str, sender: &str, date: &str, email_id: &str) -> Value {
+    json!({
+        NOTION_TITLE_PROPERTY: {
+            "title": [{ "type": "text", "text": { "content": title } }]
+        },
+        NOTION_SENDER_PROPERTY: { "email": sender },
+        NOTION_DATE_PROPERTY: { "date": { "start": date } },
+        NOTION_ID_PROPERTY: {
+            "rich_text": [{ "type": "text", "text": { "content": email_id } }]
+        }
+    })
+}
+
+/// Map possible Notion API errors to generic storage backend error
+pub async fn map_status(resp: reqwest::Response) -> Result<reqwest::Response, Error> {
+    let status = resp.status();
+
+    if status.is_success() {
+        return Ok(resp);
+    }
+
+    let body = resp.bytes().await.unwrap_or_default();
+    let (code, msg) = match serde_json::from_slice::<NotionError>(&body) {
+        Ok(e) => (e.code.clone(), format!("{}: {}", e.code, e.message)),
+        Err(_) => (String::new(), status.to_string()),
+    };
+
+    match code.as_str() {
+        "unauthorized" | "restricted_resource" => Err(Error::TokenExpired(msg)),
+        "rate_limited" | "conflict_error" | "service_unavailable" => Err(Error::RateLimited(msg)),
+        "invalid_json" | "invalid_request" | "validation_error" => Err(Error::BadInput(msg)),
+        "object_not_found" => Err(Error::BadEndpoint(msg)),
+        _ => match status {
+            StatusCode::BAD_REQUEST => Err(Error::BadInput(msg)),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(Error::TokenExpired(msg)),
+            StatusCode::NOT_FOUND => Err(Error::BadEndpoint(msg)),
+            StatusCode::TOO_MANY_REQUESTS => Err(Error::RateLimited(msg)),
+            _ => Err(Error::Internal(msg)),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paragraph_blocks() {
+        let long = "a".repeat(NOTION_MAX_TEXT_LENGTH + 1);
+        let body = format!("Hello,\r\nthere\r\n\r\n\r\n{}\n\n  \n", long);
+
+        let blocks = paragraph_blocks(&body);
+        let text = |i: usize| {
+            blocks[i]["paragraph"]["rich_text"][0]["text"]["content"]
+                .as_str()
+                .unwrap()
+                .to_string()
+        };
+
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(text(0), "Hello,\nthere");
+        assert_eq!(text(1).len(), NOTION_MAX_TEXT_LENGTH);
+        assert_eq!(text(2), "a");
+    }
+}
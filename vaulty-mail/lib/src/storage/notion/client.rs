@@ -0,0 +1,491 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::{TimeZone, Utc};
+use reqwest::multipart::{Form, Part};
+use serde_json::{json, Value};
+
+use super::api;
+
+use crate::email::Email;
+use crate::storage::client::{ByteStream, Client, ClientFuture, Metadata, StoredObject};
+use crate::storage::Error;
+
+/// Creates a page in a Notion database for each email.
+///
+/// The page holds the plain text body, and each attachment is added to it
+/// as a file block.
+pub struct NotionClient {
+    settings: api::Settings,
+    base_url: reqwest::Url,
+    title: String,
+    sender: String,
+    date: String,
+    body: String,
+    email_id: String,
+    client: reqwest::Client,
+
+    /// Set once the page for this email is known
+    page_id: Mutex<Option<String>>,
+}
+
+impl NotionClient {
+    /// Build a client from a JSON storage token (see `api::Settings`) for
+    /// `email`
+    pub fn from_token(token: &str, email: &Email) -> Result<Self, Error> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(api::NOTION_REQUEST_TIMEOUT))
+            .build()
+            .unwrap();
+
+        Ok(Self {
+            settings: api::Settings::from_token(token)?,
+            base_url: reqwest::Url::parse(api::NOTION_BASE_URL).unwrap(),
+            title: email
+                .subject
+                .as_ref()
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .unwrap_or_else(|| "(no subject)".to_string()),
+            sender: email.sender.clone(),
+            // Fall back to the day the email is handled if it has no date
+            date: email
+                .date
+                .and_then(|t| Utc.timestamp_opt(t, 0).single())
+                .unwrap_or_else(Utc::now)
+                .format("%F")
+                .to_string(),
+            body: email.body.clone(),
+            email_id: email.uuid.to_string(),
+            client,
+            page_id: Mutex::new(None),
+        })
+    }
+
+    /// Use the page already created for this email, rather than looking it
+    /// up
+    pub fn with_page_id(self, page_id: &str) -> Self {
+        *self.page_id.lock().unwrap() = Some(page_id.to_string());
+        self
+    }
+
+    /// Override the Notion API base URL (e.g., to point at a mock server)
+    pub fn with_base_url(mut self, base_url: &str) -> Result<Self, Error> {
+        let base_url = if base_url.ends_with('/') {
+            base_url.to_string()
+        } else {
+            format!("{}/", base_url)
+        };
+
+        self.base_url = reqwest::Url::parse(&base_url)?;
+
+        Ok(self)
+    }
+
+    #[inline]
+    fn request(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+    ) -> Result<reqwest::RequestBuilder, Error> {
+        let url = self.base_url.join(path)?;
+
+        Ok(self
+            .client
+            .request(method, url)
+            .bearer_auth(&self.settings.token)
+            .header("Notion-Version", api::NOTION_VERSION))
+    }
+
+    /// Find the page created for this email, if any
+    pub async fn find_page(&self) -> Result<Option<String>, Error> {
+        let path = format!("databases/{}/query", self.settings.database_id);
+        let body = json!({
+            "filter": {
+                "property": api::NOTION_ID_PROPERTY,
+                "rich_text": { "equals": self.email_id }
+            }
+        });
+
+        let req = self.request(reqwest::Method::POST, &path)?.json(&body);
+        let resp = api::map_status(req.send().await?).await?;
+
+        let result: api::QueryResult = serde_json::from_slice(&resp.bytes().await?)?;
+
+        Ok(result.results.into_iter().next().map(|p| p.id))
+    }
+
+    /// Create the page for this email, with the body as paragraphs.
+    /// Returns the page ID.
+    pub async fn create_page(&self) -> Result<String, Error> {
+        let mut blocks = api::paragraph_blocks(&self.body);
+
+        // Only a limited number of blocks can be sent with the page itself
+        let rest = if blocks.len() > api::NOTION_MAX_BLOCKS {
+            blocks.split_off(api::NOTION_MAX_BLOCKS)
+        } else {
+            Vec::new()
+        };
+
+        let body = json!({
+            "parent": { "database_id": self.settings.database_id },
+            "properties": api::page_properties(&self.title, &self.sender, &self.date, &self.email_id),
+            "children": blocks,
+        });
+
+        let req = self.request(reqwest::Method::POST, "pages")?.json(&body);
+        let resp = api::map_status(req.send().await?).await?;
+
+        let page: api::Page = serde_json::from_slice(&resp.bytes().await?)?;
+
+        for chunk in rest.chunks(api::NOTION_MAX_BLOCKS) {
+            self.append_blocks(&page.id, chunk).await?;
+        }
+
+        Ok(page.id)
+    }
+
+    /// Find the page for this email, or create it if missing
+    pub async fn get_or_create_page(&self) -> Result<String, Error> {
+        match self.find_page().await? {
+            Some(id) => Ok(id),
+            None => self.create_page().await,
+        }
+    }
+
+    /// ID of the page for this email, creating the page first if needed
+    async fn page_id(&self) -> Result<String, Error> {
+        if let Some(id) = self.page_id.lock().unwrap().clone() {
+            return Ok(id);
+        }
+
+        let id = self.get_or_create_page().await?;
+        *self.page_id.lock().unwrap() = Some(id.clone());
+
+        Ok(id)
+    }
+
+    /// Append blocks to the end of a page
+    async fn append_blocks(&self, page_id: &str, blocks: &[Value]) -> Result<(), Error> {
+        let path = format!("blocks/{}/children", page_id);
+        let body = json!({ "children": blocks });
+
+        let req = self.request(reqwest::Method::PATCH, &path)?.json(&body);
+        let _resp = api::map_status(req.send().await?).await?;
+
+        Ok(())
+    }
+
+    /// Upload a file to Notion.
+    /// Returns the file upload ID, to be referenced from a block.
+    async fn upload_file(&self, name: &str, data: reqwest::Body) -> Result<String, Error> {
+        let body = json!({ "filename": name });
+
+        let req = self
+            .request(reqwest::Method::POST, "file_uploads")?
+            .json(&body);
+        let resp = api::map_status(req.send().await?).await?;
+
+        let upload: api::FileUpload = serde_json::from_slice(&resp.bytes().await?)?;
+
+        let path = format!("file_uploads/{}/send", upload.id);
+        let form = Form::new().part("file", Part::stream(data).file_name(name.to_string()));
+
+        let req = self.request(reqwest::Method::POST, &path)?.multipart(form);
+        let _resp = api::map_status(req.send().await?).await?;
+
+        Ok(upload.id)
+    }
+}
+
+impl Client for NotionClient {
+    /// Add a file to the page for this email, creating the page first if
+    /// needed.
+    ///
+    /// Only the file name is taken from the path. The metadata is ignored:
+    /// the page already holds the email details.
+    fn upload_stream(
+        &self,
+        path: &str,
+        data: ByteStream,
+        _metadata: &Metadata,
+    ) -> ClientFuture<'_, Option<StoredObject>> {
+        let name = path.rsplit('/').next().unwrap_or(path).to_string();
+
+        Box::pin(async move {
+            let page_id = self.page_id().await?;
+            let upload_id = self
+                .upload_file(&name, reqwest::Body::wrap_stream(data))
+                .await?;
+
+            self.append_blocks(&page_id, &[api::file_block(&upload_id, &name)])
+                .await
+                .map(|_| None)
+        })
+    }
+
+    /// Create the page for this email, even if it has no attachments
+    fn upload_email(&self, _email: &Email) -> ClientFuture<'_, ()> {
+        Box::pin(async move { self.page_id().await.map(|_| ()) })
+    }
+
+    /// The ID of the page for this email, so that later attachments do not
+    /// have to look it up. A page that was just created may not show up in
+    /// database queries yet.
+    fn email_state(&self) -> Option<String> {
+        self.page_id.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::Bytes;
+
+    use crate::storage::notion::mock::MockNotion;
+
+    /// Status and body of an error response, and a check of the error
+    type ErrorCase = (u16, &'static str, fn(&Error) -> bool);
+
+    fn get_client(email: &Email) -> NotionClient {
+        let token = std::env::var("NOTION_TOKEN").expect("No Notion token found");
+        let client = NotionClient::from_token(&token, email).unwrap();
+
+        match std::env::var("NOTION_BASE_URL") {
+            Ok(url) => client.with_base_url(&url).unwrap(),
+            Err(_) => client,
+        }
+    }
+
+    fn get_email() -> Email {
+        let mut email = Email::new();
+        email.sender = "bob@example.com".to_string();
+        email.subject = Some("Vaulty test".to_string());
+        email.body = "Hello there!\n\nSecond paragraph.".to_string();
+        email.uuid = uuid::Uuid::new_v5(&uuid::Uuid::nil(), Utc::now().to_rfc3339().as_bytes());
+        email
+    }
+
+    #[test]
+    fn test_with_base_url() {
+        let email = get_email();
+        let client =
+            NotionClient::from_token(r#"{"token": "abcd", "database_id": "1234"}"#, &email)
+                .unwrap()
+                .with_base_url("http://127.0.0.1:8080/v1")
+                .unwrap();
+
+        let req = client
+            .request(reqwest::Method::POST, "pages")
+            .unwrap()
+            .build()
+            .unwrap();
+
+        assert_eq!(req.url().as_str(), "http://127.0.0.1:8080/v1/pages");
+        assert_eq!(req.headers()["Notion-Version"], api::NOTION_VERSION);
+    }
+
+    #[test]
+    fn test_date() {
+        let mut email = get_email();
+        email.date = Some(1580693736);
+
+        let token = r#"{"token": "abcd", "database_id": "1234"}"#;
+        let client = NotionClient::from_token(token, &email).unwrap();
+
+        assert_eq!(client.date, "2020-02-03");
+        assert!(client.email_state().is_none());
+        assert_eq!(
+            client.with_page_id("5678").email_state().as_deref(),
+            Some("5678")
+        );
+    }
+
+    fn upload_data() -> ByteStream {
+        Box::pin(futures::stream::iter(vec![Ok(Bytes::from("Hello there!"))]))
+    }
+
+    /// Caption of a file block, or text of a paragraph block
+    fn block_text(block: &Value) -> &str {
+        let text = match block["type"].as_str() {
+            Some("file") => &block["file"]["caption"],
+            _ => &block["paragraph"]["rich_text"],
+        };
+
+        text[0]["text"]["content"].as_str().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_mock_upload_stream() {
+        let server = MockNotion::start();
+        let email = get_email();
+        let client = server.client(&email);
+
+        for name in &["a.txt", "b.txt"] {
+            let result = client
+                .upload_stream(
+                    &format!("/vaulty/{}", name),
+                    upload_data(),
+                    &Metadata::default(),
+                )
+                .await
+                .unwrap();
+
+            assert!(result.is_none());
+        }
+
+        let state = server.state.lock().unwrap();
+
+        // Both attachments went to the same page, after the body
+        assert_eq!(state.pages.len(), 1);
+        assert_eq!(state.queries, 1);
+
+        let page = &state.pages[0];
+        let texts = page.children.iter().map(block_text).collect::<Vec<_>>();
+
+        assert_eq!(
+            texts,
+            vec!["Hello there!", "Second paragraph.", "a.txt", "b.txt"]
+        );
+        assert_eq!(
+            page.properties["Vaulty ID"]["rich_text"][0]["text"]["content"],
+            email.uuid.to_string()
+        );
+        assert_eq!(page.properties["Sender"]["email"], "bob@example.com");
+
+        for upload in &state.file_uploads {
+            let form = String::from_utf8_lossy(upload.form.as_ref().unwrap());
+            assert!(form.contains(&format!("filename=\"{}\"", upload.filename)));
+            assert!(form.contains("Hello there!"));
+        }
+
+        assert_eq!(client.email_state(), Some(page.id.clone()));
+    }
+
+    #[tokio::test]
+    async fn test_mock_page_reuse() {
+        let server = MockNotion::start();
+        let email = get_email();
+
+        server.client(&email).upload_email(&email).await.unwrap();
+        let page_id = server.state.lock().unwrap().pages[0].id.clone();
+
+        // A later client finds the page by email ID
+        let client = server.client(&email);
+        client
+            .upload_stream("/a.txt", upload_data(), &Metadata::default())
+            .await
+            .unwrap();
+
+        assert_eq!(client.email_state(), Some(page_id.clone()));
+
+        // or takes it from the state of an earlier client
+        let client = server.client(&email).with_page_id(&page_id);
+        client
+            .upload_stream("/b.txt", upload_data(), &Metadata::default())
+            .await
+            .unwrap();
+
+        let state = server.state.lock().unwrap();
+
+        assert_eq!(state.pages.len(), 1);
+        assert_eq!(state.queries, 2);
+        assert_eq!(state.pages[0].children.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_mock_create_page_long_body() {
+        let server = MockNotion::start();
+
+        let mut email = get_email();
+        email.body = (0..api::NOTION_MAX_BLOCKS * 3 / 2)
+            .map(|i| format!("Paragraph {}", i))
+            .collect::<Vec<_>>()
+            .join("\n\n");
+
+        server.client(&email).create_page().await.unwrap();
+
+        let state = server.state.lock().unwrap();
+        let page = &state.pages[0];
+
+        // Blocks past the limit are appended in a separate request
+        assert_eq!(page.children.len(), api::NOTION_MAX_BLOCKS * 3 / 2);
+        assert_eq!(block_text(page.children.last().unwrap()), "Paragraph 149");
+        assert_eq!(state.appends, 1);
+    }
+
+    #[tokio::test]
+    async fn test_mock_errors() {
+        let server = MockNotion::start();
+        let email = get_email();
+        let client = server.client(&email);
+
+        let cases: Vec<ErrorCase> = vec![
+            (401, r#"{"code": "unauthorized", "message": ""}"#, |e| {
+                matches!(e, Error::TokenExpired(_))
+            }),
+            (404, r#"{"code": "object_not_found", "message": ""}"#, |e| {
+                matches!(e, Error::BadEndpoint(_))
+            }),
+            (409, r#"{"code": "conflict_error", "message": ""}"#, |e| {
+                matches!(e, Error::RateLimited(_))
+            }),
+            (400, r#"{"code": "validation_error", "message": ""}"#, |e| {
+                matches!(e, Error::BadInput(_))
+            }),
+            (502, "Bad Gateway", |e| matches!(e, Error::Internal(_))),
+        ];
+
+        for (status, body, expected) in cases {
+            server.state.lock().unwrap().error = Some((status, body));
+
+            match client
+                .upload_stream("/a.txt", upload_data(), &Metadata::default())
+                .await
+            {
+                Err(e) if expected(&e) => (),
+                r => panic!("Unexpected result for {} {}: {:?}", status, body, r),
+            }
+        }
+
+        // A bad token is reported as expired
+        let client = server.client_with_token("bad-token", &email);
+
+        match client
+            .upload_stream("/a.txt", upload_data(), &Metadata::default())
+            .await
+        {
+            Err(Error::TokenExpired(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+
+        assert!(server.state.lock().unwrap().pages.is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_upload_stream() {
+        let email = get_email();
+        let client = get_client(&email);
+
+        for name in &["a.txt", "b.txt"] {
+            let result = client
+                .upload_stream(
+                    &format!("/vaulty/{}", name),
+                    upload_data(),
+                    &Metadata::default(),
+                )
+                .await
+                .unwrap();
+
+            assert!(result.is_none());
+        }
+
+        // Both attachments went to the page created for the email
+        let page_id = client.find_page().await.unwrap();
+
+        assert!(page_id.is_some());
+        assert_eq!(client.email_state(), page_id);
+    }
+}
@@ -0,0 +1,306 @@
+//! In-memory mock of the Notion API endpoints used by `NotionClient`.
+//!
+//! Setting `State::error` makes the mock answer the next request with that
+//! status code and body, which is used to test error mapping.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use serde_json::{json, Value};
+use warp::http::Response;
+use warp::{Filter, Rejection, Reply};
+
+use super::api;
+use super::client::NotionClient;
+use crate::email::Email;
+
+pub const MOCK_TOKEN: &str = "mock-integration-token";
+pub const MOCK_DATABASE_ID: &str = "mock-database";
+
+pub struct MockPage {
+    pub id: String,
+    pub properties: Value,
+    pub children: Vec<Value>,
+}
+
+pub struct MockFileUpload {
+    pub id: String,
+    pub filename: String,
+    /// Multipart body the file was sent with, if it was sent
+    pub form: Option<Vec<u8>>,
+}
+
+/// Mock Notion state
+pub struct State {
+    pub pages: Vec<MockPage>,
+    pub file_uploads: Vec<MockFileUpload>,
+    /// Status and body of the response to the next request
+    pub error: Option<(u16, &'static str)>,
+    /// Number of database queries
+    pub queries: usize,
+    /// Number of requests appending blocks to a page
+    pub appends: usize,
+    next_id: usize,
+}
+
+impl State {
+    fn new() -> Self {
+        Self {
+            pages: Vec::new(),
+            file_uploads: Vec::new(),
+            error: None,
+            queries: 0,
+            appends: 0,
+            next_id: 0,
+        }
+    }
+
+    fn next_id(&mut self) -> String {
+        self.next_id += 1;
+        format!("id-{}", self.next_id)
+    }
+}
+
+fn reply(status: u16, body: String) -> Response<String> {
+    Response::builder().status(status).body(body).unwrap()
+}
+
+fn error(status: u16, code: &str) -> Response<String> {
+    let body = json!({ "object": "error", "status": status, "code": code, "message": "" });
+    reply(status, body.to_string())
+}
+
+/// Common request handling: auth and error injection
+fn handle(
+    state: &Mutex<State>,
+    auth: Option<String>,
+    version: Option<String>,
+    handler: impl FnOnce(&mut State) -> Response<String>,
+) -> Response<String> {
+    let mut state = state.lock().unwrap();
+
+    if let Some((status, body)) = state.error.take() {
+        return reply(status, body.to_string());
+    }
+
+    if auth != Some(format!("Bearer {}", MOCK_TOKEN)) {
+        return error(401, "unauthorized");
+    }
+
+    if version.as_deref() != Some(api::NOTION_VERSION) {
+        return error(400, "missing_version");
+    }
+
+    handler(&mut state)
+}
+
+fn query_database(state: &mut State, database_id: &str, body: Value) -> Response<String> {
+    state.queries += 1;
+
+    if database_id != MOCK_DATABASE_ID {
+        return error(404, "object_not_found");
+    }
+
+    // Only the filter used by the client is supported
+    let property = body["filter"]["property"].as_str().unwrap_or("");
+    let value = &body["filter"]["rich_text"]["equals"];
+
+    let results = state
+        .pages
+        .iter()
+        .filter(|p| &p.properties[property]["rich_text"][0]["text"]["content"] == value)
+        .map(|p| json!({ "object": "page", "id": p.id }))
+        .collect::<Vec<_>>();
+
+    reply(200, json!({ "results": results }).to_string())
+}
+
+fn create_page(state: &mut State, body: Value) -> Response<String> {
+    if body["parent"]["database_id"] != MOCK_DATABASE_ID {
+        return error(404, "object_not_found");
+    }
+
+    let children = body["children"].as_array().cloned().unwrap_or_default();
+
+    if children.len() > api::NOTION_MAX_BLOCKS {
+        return error(400, "validation_error");
+    }
+
+    let page = MockPage {
+        id: state.next_id(),
+        properties: body["properties"].clone(),
+        children,
+    };
+
+    let body = json!({ "object": "page", "id": page.id });
+    state.pages.push(page);
+
+    reply(200, body.to_string())
+}
+
+fn append_blocks(state: &mut State, page_id: &str, body: Value) -> Response<String> {
+    state.appends += 1;
+
+    let children = body["children"].as_array().cloned().unwrap_or_default();
+
+    if children.len() > api::NOTION_MAX_BLOCKS {
+        return error(400, "validation_error");
+    }
+
+    // File blocks must reference a file that was sent
+    let unsent = children
+        .iter()
+        .filter_map(|b| b["file"]["file_upload"]["id"].as_str())
+        .any(|id| {
+            !state
+                .file_uploads
+                .iter()
+                .any(|f| f.id == id && f.form.is_some())
+        });
+
+    if unsent {
+        return error(400, "validation_error");
+    }
+
+    match state.pages.iter_mut().find(|p| p.id == page_id) {
+        Some(page) => {
+            page.children.extend(children);
+            reply(200, json!({ "object": "list", "results": [] }).to_string())
+        }
+        None => error(404, "object_not_found"),
+    }
+}
+
+fn create_file_upload(state: &mut State, body: Value) -> Response<String> {
+    let upload = MockFileUpload {
+        id: state.next_id(),
+        filename: body["filename"].as_str().unwrap_or("").to_string(),
+        form: None,
+    };
+
+    let body = json!({ "object": "file_upload", "id": upload.id, "status": "pending" });
+    state.file_uploads.push(upload);
+
+    reply(200, body.to_string())
+}
+
+fn send_file_upload(state: &mut State, upload_id: &str, form: Bytes) -> Response<String> {
+    match state.file_uploads.iter_mut().find(|f| f.id == upload_id) {
+        Some(upload) if upload.form.is_none() => {
+            upload.form = Some(form.to_vec());
+            reply(
+                200,
+                json!({ "object": "file_upload", "id": upload.id, "status": "uploaded" })
+                    .to_string(),
+            )
+        }
+        Some(_) => error(400, "validation_error"),
+        None => error(404, "object_not_found"),
+    }
+}
+
+fn routes(
+    state: Arc<Mutex<State>>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone + Send + Sync + 'static {
+    let with_state = warp::any().map(move || state.clone());
+    let headers = warp::header::optional::<String>("authorization")
+        .and(warp::header::optional::<String>("notion-version"));
+
+    let query = warp::post()
+        .and(warp::path!("databases" / String / "query"))
+        .and(with_state.clone())
+        .and(headers)
+        .and(warp::body::json())
+        .map(
+            |database_id: String, state: Arc<Mutex<State>>, auth, version, body| {
+                handle(&state, auth, version, |state| {
+                    query_database(state, &database_id, body)
+                })
+            },
+        );
+
+    let create = warp::post()
+        .and(warp::path!("pages"))
+        .and(with_state.clone())
+        .and(headers)
+        .and(warp::body::json())
+        .map(|state: Arc<Mutex<State>>, auth, version, body| {
+            handle(&state, auth, version, |state| create_page(state, body))
+        });
+
+    let append = warp::patch()
+        .and(warp::path!("blocks" / String / "children"))
+        .and(with_state.clone())
+        .and(headers)
+        .and(warp::body::json())
+        .map(
+            |page_id: String, state: Arc<Mutex<State>>, auth, version, body| {
+                handle(&state, auth, version, |state| {
+                    append_blocks(state, &page_id, body)
+                })
+            },
+        );
+
+    let create_upload = warp::post()
+        .and(warp::path!("file_uploads"))
+        .and(with_state.clone())
+        .and(headers)
+        .and(warp::body::json())
+        .map(|state: Arc<Mutex<State>>, auth, version, body| {
+            handle(&state, auth, version, |state| {
+                create_file_upload(state, body)
+            })
+        });
+
+    let send_upload = warp::post()
+        .and(warp::path!("file_uploads" / String / "send"))
+        .and(with_state)
+        .and(headers)
+        .and(warp::body::bytes())
+        .map(
+            |upload_id: String, state: Arc<Mutex<State>>, auth, version, form| {
+                handle(&state, auth, version, |state| {
+                    send_file_upload(state, &upload_id, form)
+                })
+            },
+        );
+
+    query
+        .or(create)
+        .or(append)
+        .or(create_upload)
+        .or(send_upload)
+}
+
+/// A mock Notion server running on an ephemeral local port.
+/// Must be started from within a Tokio runtime.
+pub struct MockNotion {
+    pub addr: SocketAddr,
+    pub state: Arc<Mutex<State>>,
+}
+
+impl MockNotion {
+    pub fn start() -> Self {
+        let state = Arc::new(Mutex::new(State::new()));
+        let (addr, server) = warp::serve(routes(state.clone())).bind_ephemeral(([127, 0, 0, 1], 0));
+
+        tokio::spawn(server);
+
+        Self { addr, state }
+    }
+
+    /// Build a client for this server with a valid token, for `email`
+    pub fn client(&self, email: &Email) -> NotionClient {
+        self.client_with_token(MOCK_TOKEN, email)
+    }
+
+    pub fn client_with_token(&self, token: &str, email: &Email) -> NotionClient {
+        let settings = json!({ "token": token, "database_id": MOCK_DATABASE_ID });
+
+        NotionClient::from_token(&settings.to_string(), email)
+            .unwrap()
+            .with_base_url(&format!("http://{}", self.addr))
+            .unwrap()
+    }
+}
@@ -0,0 +1,82 @@
+use crate::storage::Error;
+
+use reqwest::StatusCode;
+
+use serde::Deserialize;
+
+pub const ONEDRIVE_BASE_URL: &str = "https://graph.microsoft.com/v1.0/";
+
+// Request timeout, in seconds
+pub(crate) const ONEDRIVE_REQUEST_TIMEOUT: u64 = 60;
+
+// Size of each fragment in an upload session, in bytes
+// Graph requires fragments to be a multiple of 320 KB.
+pub(crate) const ONEDRIVE_FRAGMENT_SIZE: usize = 32 * 320 * 1024;
+
+#[derive(Deserialize, Debug)]
+pub struct GraphErrorBody {
+    pub code: String,
+    pub message: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GraphError {
+    pub error: GraphErrorBody,
+}
+
+/// Map possible Graph API errors to generic storage backend error.
+///
+/// The Graph error code is checked first, since several codes share a
+/// single HTTP status.
+pub async fn map_status(resp: reqwest::Response) -> Result<reqwest::Response, Error> {
+    let status = resp.status();
+
+    if status.is_success() {
+        return Ok(resp);
+    }
+
+    let body = resp.bytes().await.unwrap_or_default();
+    let (code, msg) = match serde_json::from_slice::<GraphError>(&body) {
+        Ok(e) => (
+            e.error.code.clone(),
+            format!("{}: {}", e.error.code, e.error.message),
+        ),
+        Err(_) => (String::new(), status.to_string()),
+    };
+
+    match code.as_str() {
+        "InvalidAuthenticationToken" | "unauthenticated" | "accessDenied" => {
+            return Err(Error::TokenExpired(msg))
+        }
+        "activityLimitReached" | "serviceNotAvailable" => return Err(Error::RateLimited(msg)),
+        "invalidRequest" | "invalidRange" | "malwareDetected" => return Err(Error::BadInput(msg)),
+        "itemNotFound" | "nameAlreadyExists" | "resourceModified" => {
+            return Err(Error::BadEndpoint(msg))
+        }
+        _ => (),
+    }
+
+    match status {
+        StatusCode::BAD_REQUEST => Err(Error::BadInput(msg)),
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(Error::TokenExpired(msg)),
+        StatusCode::NOT_FOUND | StatusCode::CONFLICT => Err(Error::BadEndpoint(msg)),
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => {
+            Err(Error::RateLimited(msg))
+        }
+        _ => Err(Error::Internal(msg)),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadSession {
+    pub upload_url: String,
+}
+
+/// Item created by an upload
+#[derive(Deserialize, Debug)]
+pub struct DriveItem {
+    pub id: String,
+    pub name: String,
+    pub size: Option<u64>,
+}
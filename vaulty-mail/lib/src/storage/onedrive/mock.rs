@@ -0,0 +1,356 @@
+//! In-memory mock of the Graph API endpoints used by `OnedriveClient`.
+//!
+//! Setting `State::error` makes the mock answer the next request with that
+//! status code and body, which is used to test error mapping.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use serde_json::{json, Value};
+use warp::http::Response;
+use warp::{Filter, Rejection, Reply};
+
+use super::client::OnedriveClient;
+
+pub const MOCK_ACCESS_TOKEN: &str = "mock-access-token";
+
+pub struct MockItem {
+    pub id: String,
+    pub path: String,
+    pub modified: Option<String>,
+    pub data: Vec<u8>,
+}
+
+/// An upload session in progress
+struct Session {
+    path: String,
+    modified: Option<String>,
+    data: Vec<u8>,
+}
+
+/// Mock OneDrive state
+pub struct State {
+    pub items: Vec<MockItem>,
+    /// Status and body of the response to the next request
+    pub error: Option<(u16, &'static str)>,
+    /// Number of fragments received for upload sessions
+    pub fragments: usize,
+    /// Number of upload sessions cancelled
+    pub cancelled: usize,
+    base_url: String,
+    sessions: HashMap<String, Session>,
+    next_id: usize,
+}
+
+impl State {
+    fn new() -> Self {
+        Self {
+            items: Vec::new(),
+            error: None,
+            fragments: 0,
+            cancelled: 0,
+            base_url: String::new(),
+            sessions: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    fn next_id(&mut self) -> String {
+        self.next_id += 1;
+        format!("id-{}", self.next_id)
+    }
+
+    /// Create an item, renaming it like Graph does if the path is taken
+    fn create(&mut self, path: &str, modified: Option<String>, data: Vec<u8>) -> Value {
+        let (stem, ext) = match path.rfind('.') {
+            Some(i) if i > path.rfind('/').map_or(0, |j| j + 1) => path.split_at(i),
+            _ => (path, ""),
+        };
+
+        let mut path = path.to_string();
+        let mut i = 0;
+
+        while self.items.iter().any(|item| item.path == path) {
+            i += 1;
+            path = format!("{} {}{}", stem, i, ext);
+        }
+
+        let item = MockItem {
+            id: self.next_id(),
+            path,
+            modified,
+            data,
+        };
+
+        let body = json!({
+            "id": item.id,
+            "name": item.path.rsplit('/').next(),
+            "size": item.data.len(),
+        });
+        self.items.push(item);
+
+        body
+    }
+}
+
+fn reply(status: u16, body: String) -> Response<String> {
+    Response::builder().status(status).body(body).unwrap()
+}
+
+/// Common request handling: auth and error injection
+fn handle(
+    state: &Mutex<State>,
+    authorized: bool,
+    handler: impl FnOnce(&mut State) -> Response<String>,
+) -> Response<String> {
+    let mut state = state.lock().unwrap();
+
+    if let Some((status, body)) = state.error.take() {
+        return reply(status, body.to_string());
+    }
+
+    if !authorized {
+        return reply(
+            401,
+            json!({ "error": { "code": "InvalidAuthenticationToken", "message": "" } }).to_string(),
+        );
+    }
+
+    handler(&mut state)
+}
+
+/// Requests to the API must carry the token
+fn is_authorized(auth: &Option<String>) -> bool {
+    auth.as_deref() == Some(&format!("Bearer {}", MOCK_ACCESS_TOKEN))
+}
+
+/// Split an item URL tail, e.g. `root:/vaulty/a.txt:/content`, into the
+/// item path and the action
+fn parse_item_tail(tail: &str) -> Option<(String, String)> {
+    let (path, action) = tail.strip_prefix("root:")?.rsplit_once(":/")?;
+
+    Some((path.to_string(), action.to_string()))
+}
+
+fn create_session(state: &mut State, path: String, body: Value) -> Response<String> {
+    let session_id = state.next_id();
+    let upload_url = format!("{}upload/sessions/{}", state.base_url, session_id);
+    let modified = body["item"]["fileSystemInfo"]["lastModifiedDateTime"]
+        .as_str()
+        .map(str::to_string);
+
+    state.sessions.insert(
+        session_id,
+        Session {
+            path,
+            modified,
+            data: Vec::new(),
+        },
+    );
+
+    reply(
+        200,
+        json!({
+            "uploadUrl": upload_url,
+            "expirationDateTime": "2020-01-01T00:00:00Z",
+        })
+        .to_string(),
+    )
+}
+
+/// Receive a fragment, checking its range like Graph does
+fn upload_fragment(
+    state: &mut State,
+    session_id: &str,
+    range: Option<String>,
+    body: Bytes,
+) -> Response<String> {
+    state.fragments += 1;
+
+    let invalid_range = || {
+        reply(
+            400,
+            json!({ "error": { "code": "invalidRange", "message": "" } }).to_string(),
+        )
+    };
+
+    // `bytes <first>-<last>/<total>`
+    let range = range.unwrap_or_default();
+    let bounds = range
+        .strip_prefix("bytes ")
+        .and_then(|r| r.split_once('/'))
+        .and_then(|(span, total)| Some((span.split_once('-')?, total)))
+        .and_then(|((first, last), total)| {
+            Some((
+                first.parse::<usize>().ok()?,
+                last.parse::<usize>().ok()?,
+                total.parse::<usize>().ok()?,
+            ))
+        });
+
+    let (first, last, total) = match bounds {
+        Some(bounds) => bounds,
+        None => return invalid_range(),
+    };
+
+    let session = match state.sessions.get_mut(session_id) {
+        Some(session) => session,
+        None => {
+            return reply(
+                404,
+                json!({ "error": { "code": "itemNotFound", "message": "" } }).to_string(),
+            )
+        }
+    };
+
+    let is_last = last + 1 == total;
+
+    if first != session.data.len()
+        || last + 1 - first != body.len()
+        || last >= total
+        || (!is_last && !body.len().is_multiple_of(320 * 1024))
+    {
+        return invalid_range();
+    }
+
+    session.data.extend_from_slice(&body);
+
+    if !is_last {
+        return reply(
+            202,
+            json!({ "nextExpectedRanges": [format!("{}-", session.data.len())] }).to_string(),
+        );
+    }
+
+    let session = state.sessions.remove(session_id).unwrap();
+    let body = state.create(&session.path, session.modified, session.data);
+
+    reply(201, body.to_string())
+}
+
+fn routes(
+    state: Arc<Mutex<State>>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone + Send + Sync + 'static {
+    let with_state = warp::any().map(move || state.clone());
+    let auth = warp::header::optional::<String>("authorization");
+    let item = warp::path!("me" / "drive" / ..).and(warp::path::tail());
+
+    let create_session =
+        warp::post()
+            .and(item)
+            .and(with_state.clone())
+            .and(auth)
+            .and(warp::body::json())
+            .map(
+                |tail: warp::path::Tail, state: Arc<Mutex<State>>, auth, body| {
+                    handle(&state, is_authorized(&auth), |state| match parse_item_tail(
+                        tail.as_str(),
+                    ) {
+                        Some((path, action)) if action == "createUploadSession" => {
+                            create_session(state, path, body)
+                        }
+                        _ => reply(400, "Invalid item URL".to_string()),
+                    })
+                },
+            );
+
+    let upload_content =
+        warp::put()
+            .and(item)
+            .and(with_state.clone())
+            .and(auth)
+            .and(warp::body::bytes())
+            .map(
+                |tail: warp::path::Tail, state: Arc<Mutex<State>>, auth, body: Bytes| {
+                    handle(&state, is_authorized(&auth), |state| match parse_item_tail(
+                        tail.as_str(),
+                    ) {
+                        Some((path, action)) if action == "content" => {
+                            let body = state.create(&path, None, body.to_vec());
+                            reply(201, body.to_string())
+                        }
+                        _ => reply(400, "Invalid item URL".to_string()),
+                    })
+                },
+            );
+
+    // Upload URLs are pre-authenticated, and Graph rejects requests to them
+    // that carry a token
+    let fragment = warp::put()
+        .and(warp::path!("upload" / "sessions" / String))
+        .and(with_state.clone())
+        .and(auth)
+        .and(warp::header::optional::<String>("content-range"))
+        .and(warp::body::bytes())
+        .map(
+            |session_id: String, state: Arc<Mutex<State>>, auth: Option<String>, range, body| {
+                handle(&state, auth.is_none(), |state| {
+                    upload_fragment(state, &session_id, range, body)
+                })
+            },
+        );
+
+    let cancel = warp::delete()
+        .and(warp::path!("upload" / "sessions" / String))
+        .and(with_state)
+        .and(auth)
+        .map(
+            |session_id: String, state: Arc<Mutex<State>>, auth: Option<String>| {
+                handle(&state, auth.is_none(), |state| {
+                    state.sessions.remove(&session_id);
+                    state.cancelled += 1;
+
+                    reply(204, String::new())
+                })
+            },
+        );
+
+    create_session.or(upload_content).or(fragment).or(cancel)
+}
+
+/// A mock OneDrive server running on an ephemeral local port.
+/// Must be started from within a Tokio runtime.
+pub struct MockOnedrive {
+    pub addr: SocketAddr,
+    pub state: Arc<Mutex<State>>,
+}
+
+impl MockOnedrive {
+    pub fn start() -> Self {
+        let state = Arc::new(Mutex::new(State::new()));
+        let (addr, server) = warp::serve(routes(state.clone())).bind_ephemeral(([127, 0, 0, 1], 0));
+
+        tokio::spawn(server);
+
+        state.lock().unwrap().base_url = format!("http://{}/", addr);
+
+        Self { addr, state }
+    }
+
+    /// Build a client for this server with a valid access token
+    pub fn client(&self) -> OnedriveClient<'static> {
+        self.client_with_token(MOCK_ACCESS_TOKEN)
+    }
+
+    pub fn client_with_token<'a>(&self, token: &'a str) -> OnedriveClient<'a> {
+        OnedriveClient::from_token(token)
+            .with_base_url(&format!("http://{}", self.addr))
+            .unwrap()
+    }
+
+    /// Paths of the items stored, sorted
+    pub fn paths(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+
+        let mut paths = state
+            .items
+            .iter()
+            .map(|item| item.path.clone())
+            .collect::<Vec<_>>();
+        paths.sort();
+
+        paths
+    }
+}
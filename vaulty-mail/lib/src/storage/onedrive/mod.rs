@@ -0,0 +1,4 @@
+mod api;
+pub mod client;
+#[cfg(test)]
+mod mock;
@@ -0,0 +1,112 @@
+use crate::storage::Error;
+
+use reqwest::StatusCode;
+
+use serde::Deserialize;
+
+// Request timeout, in seconds
+pub(crate) const PAPERLESS_REQUEST_TIMEOUT: u64 = 60;
+
+// Task status polling interval and deadline, in seconds
+pub(crate) const PAPERLESS_POLL_INTERVAL: u64 = 2;
+pub(crate) const PAPERLESS_POLL_TIMEOUT: u64 = 120;
+
+pub(crate) const PAPERLESS_POST_DOCUMENT: &str = "api/documents/post_document/";
+pub(crate) const PAPERLESS_TASKS: &str = "api/tasks/";
+pub(crate) const PAPERLESS_CORRESPONDENTS: &str = "api/correspondents/";
+
+/// Paperless-ngx instance and API token for an address.
+///
+/// Stored as JSON in the address storage token. `tags` holds the IDs of
+/// existing tags to apply to every consumed document.
+#[derive(Clone, Deserialize, Debug)]
+pub struct Settings {
+    pub url: String,
+    pub token: String,
+    #[serde(default)]
+    pub tags: Vec<u64>,
+}
+
+impl Settings {
+    pub fn from_token(token: &str) -> Result<Self, Error> {
+        serde_json::from_str(token)
+            .map_err(|e| Error::BadInput(format!("Invalid Paperless settings: {}", e)))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Correspondent {
+    pub id: u64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CorrespondentList {
+    pub results: Vec<Correspondent>,
+}
+
+/// State of a consumption task, as returned by `/api/tasks/`
+#[derive(Deserialize, Debug)]
+pub struct Task {
+    pub status: String,
+    pub result: Option<String>,
+    pub related_document: Option<String>,
+}
+
+impl Task {
+    /// Returns `None` while the task is still running
+    pub fn outcome(&self) -> Option<Result<(), Error>> {
+        match self.status.as_str() {
+            "SUCCESS" => Some(Ok(())),
+            // Failures are caused by the document itself (e.g., duplicates)
+            "FAILURE" | "REVOKED" => {
+                Some(Err(Error::BadInput(self.result.clone().unwrap_or_else(
+                    || format!("Consumption task {}", self.status),
+                ))))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Map possible Paperless errors to generic storage backend error
+pub fn map_status(resp: reqwest::Response) -> Result<reqwest::Response, Error> {
+    let err = resp.error_for_status_ref();
+
+    if let Err(e) = err {
+        let status = e.status().unwrap();
+        let msg = e.to_string();
+
+        match status {
+            StatusCode::BAD_REQUEST => Err(Error::BadInput(msg)),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(Error::TokenExpired(msg)),
+            StatusCode::NOT_FOUND => Err(Error::BadEndpoint(msg)),
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => {
+                Err(Error::RateLimited(msg))
+            }
+            _ => Err(Error::Internal(msg)),
+        }
+    } else {
+        Ok(resp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_task_outcome() {
+        let tasks: Vec<Task> = serde_json::from_str(
+            r#"[{"task_id": "abcd", "status": "FAILURE", "result": "Not consuming a.pdf: It is a duplicate.", "related_document": null}]"#,
+        )
+        .unwrap();
+
+        match tasks[0].outcome() {
+            Some(Err(Error::BadInput(msg))) => assert!(msg.contains("duplicate")),
+            o => panic!("Unexpected outcome: {:?}", o),
+        }
+
+        let task: Task = serde_json::from_str(r#"{"status": "STARTED", "result": null}"#).unwrap();
+        assert!(task.outcome().is_none());
+    }
+}
@@ -0,0 +1,428 @@
+use std::time::{Duration, Instant};
+
+use reqwest::header::AUTHORIZATION;
+use reqwest::multipart::{Form, Part};
+
+use super::api;
+
+use crate::email::Email;
+use crate::storage::client::{ByteStream, Client, ClientFuture, Metadata, StoredObject};
+use crate::storage::Error;
+
+/// Sends attachments to a Paperless-ngx instance for consumption.
+///
+/// Each attachment becomes a document titled after the email subject, with
+/// the sender as correspondent.
+pub struct PaperlessClient {
+    settings: api::Settings,
+    base_url: reqwest::Url,
+    title: Option<String>,
+    sender: String,
+    client: reqwest::Client,
+}
+
+impl PaperlessClient {
+    /// Build a client from a JSON storage token (see `api::Settings`) for
+    /// the attachments of `email`
+    pub fn from_token(token: &str, email: &Email) -> Result<Self, Error> {
+        let settings = api::Settings::from_token(token)?;
+
+        let base_url = if settings.url.ends_with('/') {
+            settings.url.clone()
+        } else {
+            format!("{}/", settings.url)
+        };
+
+        let base_url = reqwest::Url::parse(&base_url)?;
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(api::PAPERLESS_REQUEST_TIMEOUT))
+            .build()
+            .unwrap();
+
+        Ok(Self {
+            settings,
+            base_url,
+            title: email
+                .subject
+                .as_ref()
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty()),
+            sender: email.sender.clone(),
+            client,
+        })
+    }
+
+    #[inline]
+    fn auth(&self) -> String {
+        format!("Token {}", self.settings.token)
+    }
+
+    /// Find a correspondent by exact (case-insensitive) name
+    pub async fn find_correspondent(&self, name: &str) -> Result<Option<u64>, Error> {
+        let mut url = self.base_url.join(api::PAPERLESS_CORRESPONDENTS)?;
+        url.query_pairs_mut().append_pair("name__iexact", name);
+
+        let req = self.client.get(url).header(AUTHORIZATION, self.auth());
+        let resp = api::map_status(req.send().await?)?;
+
+        let list: api::CorrespondentList = serde_json::from_slice(&resp.bytes().await?)?;
+
+        Ok(list.results.first().map(|c| c.id))
+    }
+
+    /// Find the correspondent with this name, or create it if missing
+    pub async fn get_or_create_correspondent(&self, name: &str) -> Result<u64, Error> {
+        if let Some(id) = self.find_correspondent(name).await? {
+            return Ok(id);
+        }
+
+        let url = self.base_url.join(api::PAPERLESS_CORRESPONDENTS)?;
+        let body = serde_json::json!({ "name": name });
+
+        let req = self
+            .client
+            .post(url)
+            .header(AUTHORIZATION, self.auth())
+            .json(&body);
+        let resp = api::map_status(req.send().await?)?;
+
+        let correspondent: api::Correspondent = serde_json::from_slice(&resp.bytes().await?)?;
+
+        Ok(correspondent.id)
+    }
+
+    /// Post a document for consumption.
+    /// Returns the ID of the consumption task.
+    async fn post_document(
+        &self,
+        name: &str,
+        correspondent: u64,
+        data: reqwest::Body,
+    ) -> Result<String, Error> {
+        let url = self.base_url.join(api::PAPERLESS_POST_DOCUMENT)?;
+
+        let mut form = Form::new()
+            .part("document", Part::stream(data).file_name(name.to_string()))
+            .text("correspondent", correspondent.to_string());
+
+        if let Some(title) = &self.title {
+            form = form.text("title", title.clone());
+        }
+
+        for tag in &self.settings.tags {
+            form = form.text("tags", tag.to_string());
+        }
+
+        let req = self
+            .client
+            .post(url)
+            .header(AUTHORIZATION, self.auth())
+            .multipart(form);
+        let resp = api::map_status(req.send().await?)?;
+
+        // The task ID is returned as a bare JSON string
+        serde_json::from_slice(&resp.bytes().await?).map_err(|e| e.into())
+    }
+
+    fn task_watcher(&self) -> Result<TaskWatcher, Error> {
+        Ok(TaskWatcher {
+            tasks_url: self.base_url.join(api::PAPERLESS_TASKS)?,
+            auth: self.auth(),
+            client: self.client.clone(),
+        })
+    }
+
+    /// Poll a consumption task until the document is ingested or rejected
+    pub async fn wait_for_task(&self, task_id: &str) -> Result<(), Error> {
+        self.task_watcher()?.wait(task_id).await
+    }
+
+    /// Confirm a consumption task in the background.
+    ///
+    /// Consumption can take minutes, much longer than the mail filter waits
+    /// for an attachment to be handled. The document was accepted at this
+    /// point, so a failure is only logged; Paperless lists it with its other
+    /// failed tasks.
+    fn watch_task(&self, task_id: String) -> Result<(), Error> {
+        let watcher = self.task_watcher()?;
+
+        tokio::spawn(async move {
+            if let Err(e) = watcher.wait(&task_id).await {
+                log::error!("Paperless task {} failed: {}", task_id, e);
+            }
+        });
+
+        Ok(())
+    }
+}
+
+/// Polls consumption tasks, independently of the client that posted them
+struct TaskWatcher {
+    tasks_url: reqwest::Url,
+    auth: String,
+    client: reqwest::Client,
+}
+
+impl TaskWatcher {
+    /// Fetch the state of a consumption task, if it is known yet
+    async fn get_task(&self, task_id: &str) -> Result<Option<api::Task>, Error> {
+        let mut url = self.tasks_url.clone();
+        url.query_pairs_mut().append_pair("task_id", task_id);
+
+        let req = self.client.get(url).header(AUTHORIZATION, &self.auth);
+        let resp = api::map_status(req.send().await?)?;
+
+        let tasks: Vec<api::Task> = serde_json::from_slice(&resp.bytes().await?)?;
+
+        Ok(tasks.into_iter().next())
+    }
+
+    async fn wait(&self, task_id: &str) -> Result<(), Error> {
+        let deadline = Instant::now() + Duration::from_secs(api::PAPERLESS_POLL_TIMEOUT);
+
+        while Instant::now() < deadline {
+            if let Some(task) = self.get_task(task_id).await? {
+                if let Some(outcome) = task.outcome() {
+                    if let Some(document) = &task.related_document {
+                        log::debug!("Paperless task {} created document {}", task_id, document);
+                    }
+
+                    return outcome;
+                }
+            }
+
+            tokio::time::delay_for(Duration::from_secs(api::PAPERLESS_POLL_INTERVAL)).await;
+        }
+
+        Err(Error::RequestTimeout)
+    }
+}
+
+impl Client for PaperlessClient {
+    /// Post a file to Paperless-ngx.
+    ///
+    /// Returns as soon as the document is accepted for consumption; the
+    /// consumption task is confirmed in the background.
+    ///
+    /// Only the file name is taken from the path; Paperless decides where
+    /// documents are stored. The metadata is ignored: the correspondent is
+    /// already set from the email.
+    fn upload_stream(
+        &self,
+        path: &str,
+        data: ByteStream,
+        _metadata: &Metadata,
+    ) -> ClientFuture<'_, Option<StoredObject>> {
+        let name = path.rsplit('/').next().unwrap_or(path).to_string();
+
+        Box::pin(async move {
+            let correspondent = self.get_or_create_correspondent(&self.sender).await?;
+
+            let task_id = self
+                .post_document(&name, correspondent, reqwest::Body::wrap_stream(data))
+                .await?;
+
+            log::debug!("Paperless accepted {} as task {}", name, task_id);
+
+            self.watch_task(task_id).map(|_| None)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::Bytes;
+
+    use crate::storage::paperless::mock::MockPaperless;
+
+    /// Status of an error response, and a check of the error
+    type ErrorCase = (u16, fn(&Error) -> bool);
+
+    fn invoice_email() -> Email {
+        let mut email = Email::new();
+        email.sender = "billing@example.com".to_string();
+        email.subject = Some(" Invoice 1234 ".to_string());
+
+        email
+    }
+
+    fn upload_data(body: &str) -> ByteStream {
+        Box::pin(futures::stream::iter(vec![Ok(Bytes::from(
+            body.to_string(),
+        ))]))
+    }
+
+    #[tokio::test]
+    async fn test_mock_get_or_create_correspondent() {
+        let server = MockPaperless::start();
+        let client = server.client(&invoice_email());
+
+        let first = client
+            .get_or_create_correspondent("billing@example.com")
+            .await
+            .unwrap();
+
+        // Names are matched regardless of case
+        let second = client
+            .get_or_create_correspondent("Billing@Example.com")
+            .await
+            .unwrap();
+
+        assert_eq!(first, second);
+        assert_eq!(server.state.lock().unwrap().correspondents.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_mock_upload_stream() {
+        let server = MockPaperless::start();
+        let client = server.client(&invoice_email());
+
+        let result = client
+            .upload_stream(
+                "/vaulty/invoice.txt",
+                upload_data("Invoice 1234\n"),
+                &Metadata::default(),
+            )
+            .await
+            .unwrap();
+
+        // Paperless decides where documents are stored
+        assert!(result.is_none());
+
+        let state = server.state.lock().unwrap();
+        let document = &state.documents[0];
+
+        assert_eq!(document.file_name, "invoice.txt");
+        assert_eq!(document.title.as_deref(), Some("Invoice 1234"));
+        assert_eq!(document.correspondent, Some(state.correspondents[0].id));
+        assert_eq!(document.tags, vec![1, 2]);
+        assert_eq!(document.data, b"Invoice 1234\n");
+        assert_eq!(state.correspondents[0].name, "billing@example.com");
+    }
+
+    #[tokio::test]
+    async fn test_mock_upload_stream_no_subject() {
+        let server = MockPaperless::start();
+
+        let mut email = invoice_email();
+        email.subject = Some("  ".to_string());
+
+        server
+            .client(&email)
+            .upload_stream("/a.pdf", upload_data("%PDF"), &Metadata::default())
+            .await
+            .unwrap();
+
+        // Paperless titles the document after the file instead
+        assert!(server.state.lock().unwrap().documents[0].title.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_mock_wait_for_task() {
+        let server = MockPaperless::start();
+        let client = server.client(&invoice_email());
+
+        let correspondent = client.get_or_create_correspondent("bob").await.unwrap();
+        let task_id = client
+            .post_document("a.txt", correspondent, reqwest::Body::from("Hello"))
+            .await
+            .unwrap();
+
+        client.wait_for_task(&task_id).await.unwrap();
+
+        server.state.lock().unwrap().task = ("FAILURE", Some("a.txt: It is a duplicate."));
+
+        match client.wait_for_task(&task_id).await {
+            Err(Error::BadInput(msg)) => assert!(msg.contains("duplicate")),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mock_errors() {
+        let server = MockPaperless::start();
+        let client = server.client(&invoice_email());
+
+        let cases: Vec<ErrorCase> = vec![
+            (400, |e| matches!(e, Error::BadInput(_))),
+            (403, |e| matches!(e, Error::TokenExpired(_))),
+            (404, |e| matches!(e, Error::BadEndpoint(_))),
+            (429, |e| matches!(e, Error::RateLimited(_))),
+            (500, |e| matches!(e, Error::Internal(_))),
+        ];
+
+        for (status, expected) in cases {
+            server.state.lock().unwrap().error = Some((status, ""));
+
+            match client
+                .upload_stream("/a.txt", upload_data("Hello"), &Metadata::default())
+                .await
+            {
+                Err(e) if expected(&e) => (),
+                r => panic!("Unexpected result for {}: {:?}", status, r),
+            }
+        }
+
+        // A bad token is reported as expired
+        let client = server.client_with_token("bad-token", &invoice_email());
+
+        match client
+            .upload_stream("/a.txt", upload_data("Hello"), &Metadata::default())
+            .await
+        {
+            Err(Error::TokenExpired(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+
+        assert!(server.state.lock().unwrap().documents.is_empty());
+    }
+
+    // A local Paperless-ngx (or stand-in), e.g.:
+    // {"url": "http://127.0.0.1:8000", "token": "abcd", "tags": [1]}
+    fn get_client() -> PaperlessClient {
+        let token = std::env::var("PAPERLESS_TOKEN").expect("No Paperless token found");
+
+        PaperlessClient::from_token(&token, &invoice_email()).unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_get_or_create_correspondent() {
+        let client = get_client();
+
+        let first = client
+            .get_or_create_correspondent("billing@example.com")
+            .await
+            .unwrap();
+        let second = client
+            .get_or_create_correspondent("billing@example.com")
+            .await
+            .unwrap();
+
+        assert_eq!(first, second);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_upload_stream() {
+        let client = get_client();
+
+        // Use a unique body so Paperless does not reject it as a duplicate
+        let body = format!("Invoice 1234\nGenerated at {}\n", chrono::Utc::now());
+        let correspondent = client
+            .get_or_create_correspondent("billing@example.com")
+            .await
+            .unwrap();
+
+        let task_id = client
+            .post_document("invoice.txt", correspondent, reqwest::Body::from(body))
+            .await
+            .unwrap();
+
+        // Wait for consumption here, rather than in the background
+        client.wait_for_task(&task_id).await.unwrap();
+    }
+}
@@ -0,0 +1,307 @@
+//! In-memory mock of the Paperless-ngx API endpoints used by
+//! `PaperlessClient`.
+//!
+//! Setting `State::error` makes the mock answer the next request with that
+//! status code and body, which is used to test error mapping.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use serde_json::{json, Value};
+use warp::http::Response;
+use warp::{Filter, Rejection, Reply};
+
+use super::client::PaperlessClient;
+use crate::email::Email;
+
+pub const MOCK_API_TOKEN: &str = "mock-api-token";
+
+pub struct MockCorrespondent {
+    pub id: u64,
+    pub name: String,
+}
+
+/// A document posted for consumption
+pub struct MockDocument {
+    pub task_id: String,
+    pub file_name: String,
+    pub title: Option<String>,
+    pub correspondent: Option<u64>,
+    pub tags: Vec<u64>,
+    pub data: Vec<u8>,
+}
+
+/// Mock Paperless state
+pub struct State {
+    pub correspondents: Vec<MockCorrespondent>,
+    pub documents: Vec<MockDocument>,
+    /// Status and body of the response to the next request
+    pub error: Option<(u16, &'static str)>,
+    /// Status and result reported for consumption tasks
+    pub task: (&'static str, Option<&'static str>),
+    next_id: u64,
+}
+
+impl State {
+    fn new() -> Self {
+        Self {
+            correspondents: Vec::new(),
+            documents: Vec::new(),
+            error: None,
+            task: ("SUCCESS", None),
+            next_id: 0,
+        }
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+}
+
+fn reply(status: u16, body: String) -> Response<String> {
+    Response::builder().status(status).body(body).unwrap()
+}
+
+/// Common request handling: auth and error injection
+fn handle(
+    state: &Mutex<State>,
+    auth: Option<String>,
+    handler: impl FnOnce(&mut State) -> Response<String>,
+) -> Response<String> {
+    let mut state = state.lock().unwrap();
+
+    if let Some((status, body)) = state.error.take() {
+        return reply(status, body.to_string());
+    }
+
+    if auth != Some(format!("Token {}", MOCK_API_TOKEN)) {
+        return reply(401, r#"{"detail": "Invalid token."}"#.to_string());
+    }
+
+    handler(&mut state)
+}
+
+fn query_params(query: &str) -> HashMap<String, String> {
+    url::form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect()
+}
+
+fn list_correspondents(state: &mut State, query: &str) -> Response<String> {
+    let name = query_params(query)
+        .remove("name__iexact")
+        .unwrap_or_default()
+        .to_lowercase();
+
+    let results = state
+        .correspondents
+        .iter()
+        .filter(|c| c.name.to_lowercase() == name)
+        .map(|c| json!({ "id": c.id, "name": c.name }))
+        .collect::<Vec<_>>();
+
+    reply(200, json!({ "results": results }).to_string())
+}
+
+fn create_correspondent(state: &mut State, body: Value) -> Response<String> {
+    let correspondent = MockCorrespondent {
+        id: state.next_id(),
+        name: body["name"].as_str().unwrap_or("").to_string(),
+    };
+
+    let body = json!({ "id": correspondent.id, "name": correspondent.name });
+    state.correspondents.push(correspondent);
+
+    reply(201, body.to_string())
+}
+
+/// A part of a multipart body: field name, file name, and data
+type FormPart = (String, Option<String>, Vec<u8>);
+
+/// Split a multipart body into its parts
+fn parse_form(content_type: &str, body: &[u8]) -> Option<Vec<FormPart>> {
+    let boundary = format!(
+        "--{}",
+        content_type.split("boundary=").nth(1)?.trim_matches('"')
+    );
+    let body = String::from_utf8_lossy(body);
+    let mut parts = Vec::new();
+
+    for part in body.split(boundary.as_str()).skip(1) {
+        if part.starts_with("--") {
+            break;
+        }
+
+        let (headers, data) = part.trim_start_matches("\r\n").split_once("\r\n\r\n")?;
+        let disposition = headers
+            .lines()
+            .find(|l| l.to_lowercase().starts_with("content-disposition"))?;
+        let param = |key: &str| {
+            disposition
+                .split(';')
+                .filter_map(|p| p.trim().strip_prefix(&format!("{}=", key)))
+                .map(|v| v.trim_matches('"').to_string())
+                .next()
+        };
+
+        parts.push((
+            param("name")?,
+            param("filename"),
+            data.trim_end_matches("\r\n").as_bytes().to_vec(),
+        ));
+    }
+
+    Some(parts)
+}
+
+fn post_document(state: &mut State, content_type: &str, body: &[u8]) -> Response<String> {
+    let parts = match parse_form(content_type, body) {
+        Some(parts) => parts,
+        None => return reply(400, "Invalid form".to_string()),
+    };
+
+    let text = |name: &str| {
+        parts
+            .iter()
+            .filter(|(n, _, _)| n == name)
+            .map(|(_, _, data)| String::from_utf8_lossy(data).to_string())
+            .collect::<Vec<_>>()
+    };
+
+    let (file_name, data) = match parts.iter().find(|(n, _, _)| n == "document") {
+        Some((_, Some(file_name), data)) => (file_name.clone(), data.clone()),
+        _ => {
+            return reply(
+                400,
+                r#"{"document": ["No file was submitted."]}"#.to_string(),
+            )
+        }
+    };
+
+    let task_id = format!("task-{}", state.next_id());
+
+    state.documents.push(MockDocument {
+        task_id: task_id.clone(),
+        file_name,
+        title: text("title").into_iter().next(),
+        correspondent: text("correspondent").first().and_then(|c| c.parse().ok()),
+        tags: text("tags").iter().filter_map(|t| t.parse().ok()).collect(),
+        data,
+    });
+
+    reply(200, json!(task_id).to_string())
+}
+
+fn list_tasks(state: &mut State, query: &str) -> Response<String> {
+    let task_id = query_params(query).remove("task_id").unwrap_or_default();
+    let (status, result) = state.task;
+
+    let tasks = state
+        .documents
+        .iter()
+        .filter(|d| d.task_id == task_id)
+        .map(|d| {
+            json!({
+                "task_id": d.task_id,
+                "status": status,
+                "result": result,
+                "related_document": Some(d.task_id.clone()).filter(|_| status == "SUCCESS"),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    reply(200, json!(tasks).to_string())
+}
+
+fn routes(
+    state: Arc<Mutex<State>>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone + Send + Sync + 'static {
+    let with_state = warp::any().map(move || state.clone());
+    let auth = warp::header::optional::<String>("authorization");
+    let query = warp::query::raw().or(warp::any().map(String::new)).unify();
+
+    let list_correspondents = warp::get()
+        .and(warp::path!("api" / "correspondents"))
+        .and(with_state.clone())
+        .and(auth)
+        .and(query)
+        .map(|state: Arc<Mutex<State>>, auth, query: String| {
+            handle(&state, auth, |state| list_correspondents(state, &query))
+        });
+
+    let create_correspondent = warp::post()
+        .and(warp::path!("api" / "correspondents"))
+        .and(with_state.clone())
+        .and(auth)
+        .and(warp::body::json())
+        .map(|state: Arc<Mutex<State>>, auth, body| {
+            handle(&state, auth, |state| create_correspondent(state, body))
+        });
+
+    // Documents are streamed without a length, which warp's multipart filter
+    // does not accept, so the form is parsed here
+    let post_document = warp::post()
+        .and(warp::path!("api" / "documents" / "post_document"))
+        .and(with_state.clone())
+        .and(auth)
+        .and(warp::header::<String>("content-type"))
+        .and(warp::body::bytes())
+        .map(
+            |state: Arc<Mutex<State>>, auth, content_type: String, body: Bytes| {
+                handle(&state, auth, |state| {
+                    post_document(state, &content_type, &body)
+                })
+            },
+        );
+
+    let list_tasks = warp::get()
+        .and(warp::path!("api" / "tasks"))
+        .and(with_state)
+        .and(auth)
+        .and(query)
+        .map(|state: Arc<Mutex<State>>, auth, query: String| {
+            handle(&state, auth, |state| list_tasks(state, &query))
+        });
+
+    list_correspondents
+        .or(create_correspondent)
+        .or(post_document)
+        .or(list_tasks)
+}
+
+/// A mock Paperless server running on an ephemeral local port.
+/// Must be started from within a Tokio runtime.
+pub struct MockPaperless {
+    pub addr: SocketAddr,
+    pub state: Arc<Mutex<State>>,
+}
+
+impl MockPaperless {
+    pub fn start() -> Self {
+        let state = Arc::new(Mutex::new(State::new()));
+        let (addr, server) = warp::serve(routes(state.clone())).bind_ephemeral(([127, 0, 0, 1], 0));
+
+        tokio::spawn(server);
+
+        Self { addr, state }
+    }
+
+    /// Build a client for this server with a valid API token, for the
+    /// attachments of `email`
+    pub fn client(&self, email: &Email) -> PaperlessClient {
+        self.client_with_token(MOCK_API_TOKEN, email)
+    }
+
+    pub fn client_with_token(&self, token: &str, email: &Email) -> PaperlessClient {
+        let settings = json!({
+            "url": format!("http://{}", self.addr),
+            "token": token,
+            "tags": [1, 2],
+        });
+
+        PaperlessClient::from_token(&settings.to_string(), email).unwrap()
+    }
+}
@@ -0,0 +1,346 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::azure::client::AzureClient;
+use super::client::Client;
+use super::dropbox::client::DropboxClient;
+use super::gdrive::client::GdriveClient;
+use super::git::client::GitClient;
+use super::imap::client::ImapClient;
+use super::local::client::LocalClient;
+use super::notion::client::NotionClient;
+use super::onedrive::client::OnedriveClient;
+use super::paperless::client::PaperlessClient;
+use super::s3::client::S3Client;
+use super::sftp::client::SftpClient;
+use super::webdav::client::WebdavClient;
+use super::{Backend, Error};
+
+use crate::config::Config;
+use crate::email::Email;
+
+pub type BoxedClient<'a> = Box<dyn Client + Send + Sync + 'a>;
+
+/// Builds a client for a backend from the server config
+pub type Factory = for<'a> fn(&'a Config, &Target<'a>) -> Result<BoxedClient<'a>, Error>;
+
+/// What a client is built for
+pub struct Target<'a> {
+    /// ID of the address the client stores files for
+    pub address_id: i32,
+    /// Storage token of the address
+    pub token: &'a str,
+    /// Email being handled
+    pub email: &'a Email,
+    /// State kept by an earlier client for the same email (see
+    /// `Client::email_state`)
+    pub state: Option<&'a str>,
+}
+
+/// A backend registered with the server
+pub struct Entry {
+    /// Name shown to users (e.g., `OneDrive`)
+    pub display_name: &'static str,
+    pub factory: Factory,
+    /// Whether the backend stores the original message of an email (e.g.,
+    /// IMAP) rather than each of its attachments. Such backends are only
+    /// sent the message, which already holds the attachments.
+    pub stores_messages: bool,
+}
+
+impl Entry {
+    pub fn new(display_name: &'static str, factory: Factory) -> Self {
+        Self {
+            display_name,
+            factory,
+            stores_messages: false,
+        }
+    }
+
+    /// Mark the backend as storing whole messages
+    pub fn with_messages(mut self) -> Self {
+        self.stores_messages = true;
+        self
+    }
+}
+
+/// Storage clients available to the server, keyed by backend name.
+///
+/// Built once from config at startup. A backend is added by registering an
+/// entry for its name.
+pub struct Registry {
+    config: Config,
+    entries: HashMap<String, Entry>,
+}
+
+impl Registry {
+    /// Build a registry with all built-in backends
+    pub fn new(config: Config) -> Self {
+        let mut registry = Self {
+            config,
+            entries: HashMap::new(),
+        };
+
+        registry.register("dropbox", Entry::new("Dropbox", dropbox));
+        registry.register("gdrive", Entry::new("GDrive", gdrive));
+        registry.register("s3", Entry::new("S3", s3));
+        registry.register("local", Entry::new("Local", local));
+        registry.register("webdav", Entry::new("WebDAV", webdav));
+        registry.register("sftp", Entry::new("SFTP", sftp));
+        registry.register("onedrive", Entry::new("OneDrive", onedrive));
+        registry.register("azure", Entry::new("Azure", azure));
+        registry.register("git", Entry::new("Git", git));
+        registry.register("imap", Entry::new("IMAP", imap).with_messages());
+        registry.register("paperless", Entry::new("Paperless", paperless));
+        registry.register("notion", Entry::new("Notion", notion));
+
+        registry
+    }
+
+    /// Add a backend, replacing any entry already registered for `name`
+    pub fn register(&mut self, name: &str, entry: Entry) {
+        self.entries.insert(name.to_string(), entry);
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Whether an entry is registered for `backend`
+    pub fn contains(&self, backend: &Backend) -> bool {
+        self.entries.contains_key(backend.name())
+    }
+
+    /// Name of `backend` shown to users, or its DB name if it is not
+    /// registered
+    pub fn display_name<'a>(&'a self, backend: &'a Backend) -> &'a str {
+        self.entries
+            .get(backend.name())
+            .map_or(backend.name(), |e| e.display_name)
+    }
+
+    /// Whether `backend` stores whole messages (see `Entry::stores_messages`)
+    pub fn stores_messages(&self, backend: &Backend) -> bool {
+        self.entries
+            .get(backend.name())
+            .is_some_and(|e| e.stores_messages)
+    }
+
+    /// Build a client for `backend`
+    pub fn client<'a>(
+        &'a self,
+        backend: &Backend,
+        target: &Target<'a>,
+    ) -> Result<BoxedClient<'a>, Error> {
+        match self.entries.get(backend.name()) {
+            Some(entry) => (entry.factory)(&self.config, target),
+            None => Err(Error::UnknownBackend(backend.name().to_string())),
+        }
+    }
+}
+
+fn dropbox<'a>(config: &'a Config, target: &Target<'a>) -> Result<BoxedClient<'a>, Error> {
+    let mut client = DropboxClient::from_token(target.token);
+
+    // Renews expired tokens with the configured app credentials
+    if let (Some(key), Some(secret)) = (&config.dropbox_app_key, &config.dropbox_app_secret) {
+        client = client.with_app_credentials(key, secret);
+    }
+
+    if let (Some(base_api), Some(base_content)) =
+        (&config.dropbox_base_api, &config.dropbox_base_content)
+    {
+        client = client.with_base_urls(base_api, base_content)?;
+    }
+
+    // Attachments staged for the email by earlier clients
+    if let Some(state) = target.state {
+        client = client.with_batch(state)?;
+    }
+
+    Ok(Box::new(client))
+}
+
+fn gdrive<'a>(config: &'a Config, target: &Target<'a>) -> Result<BoxedClient<'a>, Error> {
+    let mut client = GdriveClient::from_token(target.token);
+
+    if let Some(url) = &config.gdrive_base_url {
+        client = client.with_base_url(url);
+    }
+
+    Ok(Box::new(client))
+}
+
+fn s3<'a>(config: &'a Config, target: &Target<'a>) -> Result<BoxedClient<'a>, Error> {
+    let client = S3Client::new(
+        target.token,
+        config.s3_endpoint.as_deref(),
+        &config.s3_region,
+    )?;
+
+    Ok(Box::new(client))
+}
+
+/// Each address gets its own folder under the configured root, so that a
+/// storage path can never reach the files of another address
+fn local<'a>(config: &'a Config, target: &Target<'a>) -> Result<BoxedClient<'a>, Error> {
+    let root = Path::new(&config.local_root).join(target.address_id.to_string());
+
+    Ok(Box::new(LocalClient::new(root)))
+}
+
+fn webdav<'a>(_config: &'a Config, target: &Target<'a>) -> Result<BoxedClient<'a>, Error> {
+    Ok(Box::new(WebdavClient::from_token(target.token)?))
+}
+
+fn sftp<'a>(_config: &'a Config, target: &Target<'a>) -> Result<BoxedClient<'a>, Error> {
+    Ok(Box::new(SftpClient::from_token(target.token)?))
+}
+
+fn onedrive<'a>(config: &'a Config, target: &Target<'a>) -> Result<BoxedClient<'a>, Error> {
+    let mut client = OnedriveClient::from_token(target.token);
+
+    if let Some(url) = &config.onedrive_base_url {
+        client = client.with_base_url(url)?;
+    }
+
+    Ok(Box::new(client))
+}
+
+fn azure<'a>(_config: &'a Config, target: &Target<'a>) -> Result<BoxedClient<'a>, Error> {
+    Ok(Box::new(AzureClient::from_token(target.token)?))
+}
+
+/// Repositories are looked up in the folder of the address, like local
+/// storage
+fn git<'a>(config: &'a Config, target: &Target<'a>) -> Result<BoxedClient<'a>, Error> {
+    let root = Path::new(&config.git_root).join(target.address_id.to_string());
+
+    Ok(Box::new(GitClient::from_token(
+        target.token,
+        root,
+        target.email,
+    )?))
+}
+
+fn imap<'a>(_config: &'a Config, target: &Target<'a>) -> Result<BoxedClient<'a>, Error> {
+    Ok(Box::new(ImapClient::from_token(target.token)?))
+}
+
+fn paperless<'a>(_config: &'a Config, target: &Target<'a>) -> Result<BoxedClient<'a>, Error> {
+    Ok(Box::new(PaperlessClient::from_token(
+        target.token,
+        target.email,
+    )?))
+}
+
+fn notion<'a>(config: &'a Config, target: &Target<'a>) -> Result<BoxedClient<'a>, Error> {
+    let mut client = NotionClient::from_token(target.token, target.email)?;
+
+    // The page for the email was created by an earlier client
+    if let Some(page_id) = target.state {
+        client = client.with_page_id(page_id);
+    }
+
+    if let Some(url) = &config.notion_base_url {
+        client = client.with_base_url(url)?;
+    }
+
+    Ok(Box::new(client))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::convert::TryFrom;
+
+    use bytes::Bytes;
+
+    use crate::storage::backends::BACKEND_NAMES;
+    use crate::storage::client::{ByteStream, ClientFuture, Metadata, StoredObject};
+
+    /// Accepts any upload without storing it
+    struct StubClient;
+
+    impl Client for StubClient {
+        fn upload_stream(
+            &self,
+            path: &str,
+            _data: ByteStream,
+            _metadata: &Metadata,
+        ) -> ClientFuture<'_, Option<StoredObject>> {
+            let object = StoredObject {
+                id: "stub".to_string(),
+                path: path.to_string(),
+                size: 0,
+                hash: None,
+            };
+
+            Box::pin(async move { Ok(Some(object)) })
+        }
+    }
+
+    fn stub<'a>(_config: &'a Config, _target: &Target<'a>) -> Result<BoxedClient<'a>, Error> {
+        Ok(Box::new(StubClient))
+    }
+
+    #[test]
+    fn test_display_name() {
+        let registry = Registry::new(Config::default());
+
+        for name in BACKEND_NAMES {
+            assert!(registry.contains(&Backend::try_from(*name).unwrap()));
+        }
+
+        let webdav = Backend::try_from("webdav").unwrap();
+        assert_eq!(registry.display_name(&webdav), "WebDAV");
+
+        // Backends the server does not know show up by name
+        let registry = Registry {
+            config: Config::default(),
+            entries: HashMap::new(),
+        };
+        assert_eq!(registry.display_name(&webdav), "webdav");
+    }
+
+    #[tokio::test]
+    async fn test_register() {
+        let mut registry = Registry {
+            config: Config::default(),
+            entries: HashMap::new(),
+        };
+        let email = Email::new();
+        let target = Target {
+            address_id: 1,
+            token: "abcd",
+            email: &email,
+            state: None,
+        };
+
+        // Backends are looked up by the name stored in the DB
+        let local = Backend::try_from("local").unwrap();
+        assert!(!registry.contains(&local));
+
+        match registry.client(&local, &target) {
+            Err(Error::UnknownBackend(name)) => assert_eq!(name, "local"),
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Unexpected client"),
+        }
+
+        registry.register("local", Entry::new("Stub", stub));
+        assert!(registry.contains(&local));
+        assert_eq!(registry.display_name(&local), "Stub");
+
+        let client = registry.client(&local, &target).unwrap();
+        let data = futures::stream::iter(vec![Ok(Bytes::from("Hello there!"))]);
+        let object = client
+            .upload_stream("/vaulty/test.txt", Box::pin(data), &Metadata::default())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(object.id, "stub");
+        assert_eq!(object.path, "/vaulty/test.txt");
+    }
+}
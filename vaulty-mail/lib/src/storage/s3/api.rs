@@ -0,0 +1,313 @@
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::StatusCode;
+use sha2::{Digest, Sha256};
+
+use crate::storage::client::Metadata;
+use crate::storage::xml::extract_tag;
+use crate::storage::Error;
+
+pub const S3_SERVICE: &str = "s3";
+pub const S3_SIGNING_ALGORITHM: &str = "AWS4-HMAC-SHA256";
+
+// Request timeout, in seconds
+pub(crate) const S3_REQUEST_TIMEOUT: u64 = 60;
+
+// Maximum length of an object tag value, in characters
+pub(crate) const S3_TAG_VALUE_LEN: usize = 256;
+
+// Max number of renamed candidates to probe on a name collision, before
+// falling back to a name made unique by the upload time
+pub(crate) const S3_MAX_RENAMES: usize = 10;
+
+// Size of each part in a multipart upload, in bytes
+// Streams smaller than this are uploaded with a single PUT.
+// S3 requires all parts except the last to be at least 5 MB.
+pub(crate) const S3_PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// Access key pair used to sign S3 requests
+pub struct Credentials {
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+impl Credentials {
+    /// Parse credentials stored as `ACCESS_KEY_ID:SECRET_ACCESS_KEY`
+    pub fn from_token(token: &str) -> Result<Self, Error> {
+        let mut split = token.splitn(2, ':');
+
+        match (split.next(), split.next()) {
+            (Some(access_key), Some(secret_key))
+                if !access_key.is_empty() && !secret_key.is_empty() =>
+            {
+                Ok(Self {
+                    access_key: access_key.to_string(),
+                    secret_key: secret_key.to_string(),
+                })
+            }
+            _ => Err(Error::BadInput("Invalid S3 credentials".to_string())),
+        }
+    }
+}
+
+/// Split a storage path of the form `/bucket/some/key` into bucket and key
+pub fn split_path(path: &str) -> Result<(&str, &str), Error> {
+    let mut split = path.trim_start_matches('/').splitn(2, '/');
+
+    match (split.next(), split.next()) {
+        (Some(bucket), Some(key)) if !bucket.is_empty() && !key.is_empty() => Ok((bucket, key)),
+        _ => Err(Error::BadInput(format!("Invalid S3 path: {}", path))),
+    }
+}
+
+/// URI-encode a string as required by SigV4.
+///
+/// Every byte except the unreserved characters is percent-encoded. Slashes
+/// are left as-is in object keys.
+pub fn uri_encode(s: &str, encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(s.len());
+
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(b as char)
+            }
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+
+    encoded
+}
+
+#[inline]
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).unwrap();
+    mac.input(data);
+    mac.result().code().to_vec()
+}
+
+/// Derive the SigV4 signing key for a given day, region, and service
+pub fn signing_key(secret_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac_sha256(format!("AWS4{}", secret_key).as_bytes(), date.as_bytes());
+    let key = hmac_sha256(&key, region.as_bytes());
+    let key = hmac_sha256(&key, service.as_bytes());
+    hmac_sha256(&key, b"aws4_request")
+}
+
+/// Sign a request using AWS Signature Version 4.
+///
+/// `headers` are extra `x-amz-*` headers sent with the request, which must
+/// be signed as well. Returns all headers that must be attached to the
+/// request. The URL path must already be URI-encoded (see `uri_encode`).
+pub fn sign(
+    credentials: &Credentials,
+    region: &str,
+    method: &str,
+    url: &reqwest::Url,
+    payload_hash: &str,
+    headers: &[(&'static str, String)],
+    now: &DateTime<Utc>,
+) -> Vec<(&'static str, String)> {
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = now.format("%Y%m%d").to_string();
+    let host = &url[url::Position::BeforeHost..url::Position::AfterPort];
+
+    let mut signed = vec![
+        ("host", host.to_string()),
+        ("x-amz-content-sha256", payload_hash.to_string()),
+        ("x-amz-date", amz_date.clone()),
+    ];
+    signed.extend(headers.iter().cloned());
+    signed.sort();
+
+    let canonical_headers = signed
+        .iter()
+        .map(|(k, v)| format!("{}:{}\n", k, v.trim()))
+        .collect::<String>();
+    let signed_headers = signed
+        .iter()
+        .map(|(k, _)| *k)
+        .collect::<Vec<&str>>()
+        .join(";");
+
+    let mut query = url
+        .query_pairs()
+        .map(|(k, v)| (uri_encode(&k, true), uri_encode(&v, true)))
+        .collect::<Vec<(String, String)>>();
+    query.sort();
+
+    let canonical_query = query
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<String>>()
+        .join("&");
+
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method,
+        url.path(),
+        canonical_query,
+        canonical_headers,
+        signed_headers,
+        payload_hash
+    );
+
+    let scope = format!("{}/{}/{}/aws4_request", date, region, S3_SERVICE);
+    let string_to_sign = format!(
+        "{}\n{}\n{}\n{}",
+        S3_SIGNING_ALGORITHM,
+        amz_date,
+        scope,
+        sha256_hex(canonical_request.as_bytes())
+    );
+
+    let key = signing_key(&credentials.secret_key, &date, region, S3_SERVICE);
+    let signature = hex::encode(hmac_sha256(&key, string_to_sign.as_bytes()));
+
+    let authorization = format!(
+        "{} Credential={}/{}, SignedHeaders={}, Signature={}",
+        S3_SIGNING_ALGORITHM, credentials.access_key, scope, signed_headers, signature
+    );
+
+    let mut all = vec![
+        ("x-amz-date", amz_date),
+        ("x-amz-content-sha256", payload_hash.to_string()),
+        ("authorization", authorization),
+    ];
+    all.extend(headers.iter().cloned());
+
+    all
+}
+
+/// Headers to store email metadata with a new object.
+///
+/// S3 has no settable modification time, so it is kept as `mtime` user
+/// metadata (in seconds, like rclone does). The email fields become object
+/// tags.
+pub fn metadata_headers(metadata: &Metadata) -> Vec<(&'static str, String)> {
+    let mut headers = Vec::new();
+
+    if let Some(modified) = metadata.modified {
+        headers.push(("x-amz-meta-mtime", modified.timestamp().to_string()));
+    }
+
+    let tags = metadata
+        .fields()
+        .into_iter()
+        .map(|(k, v)| format!("{}={}", k, uri_encode(&tag_value(v), true)))
+        .collect::<Vec<String>>();
+
+    if !tags.is_empty() {
+        headers.push(("x-amz-tagging", tags.join("&")));
+    }
+
+    headers
+}
+
+/// Replace characters not allowed in tag values, and truncate to the
+/// maximum length
+fn tag_value(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            c if c.is_alphanumeric() => c,
+            ' ' | '+' | '-' | '=' | '.' | '_' | ':' | '/' | '@' => c,
+            _ => '_',
+        })
+        .take(S3_TAG_VALUE_LEN)
+        .collect()
+}
+
+/// Map possible S3 API errors to generic storage backend error
+pub async fn map_status(resp: reqwest::Response) -> Result<reqwest::Response, Error> {
+    let status = resp.status();
+
+    if status.is_success() {
+        return Ok(resp);
+    }
+
+    // S3 returns an XML error document with a code and message
+    let body = resp.text().await.unwrap_or_default();
+    let code = extract_tag(&body, "Code").unwrap_or_else(|| status.to_string());
+    let msg = match extract_tag(&body, "Message") {
+        Some(m) => format!("{}: {}", code, m),
+        None => code.clone(),
+    };
+
+    if code == "SlowDown" {
+        return Err(Error::RateLimited(msg));
+    }
+
+    match status {
+        StatusCode::BAD_REQUEST => Err(Error::BadInput(msg)),
+        StatusCode::FORBIDDEN => Err(Error::TokenExpired(msg)),
+        StatusCode::NOT_FOUND => Err(Error::BadEndpoint(msg)),
+        // Conditional write lost to an object created in the meantime
+        StatusCode::PRECONDITION_FAILED | StatusCode::CONFLICT => Err(Error::PathConflict(msg)),
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => {
+            Err(Error::RateLimited(msg))
+        }
+        _ => Err(Error::Internal(msg)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signing_key() {
+        // Example from the AWS SigV4 documentation
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        );
+
+        assert_eq!(
+            hex::encode(key),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+
+    #[test]
+    fn test_metadata_headers() {
+        let metadata = Metadata {
+            sender: Some("a@b.com".to_string()),
+            subject: Some("Re: Invoice #1, paid".to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            metadata_headers(&metadata),
+            vec![(
+                "x-amz-tagging",
+                "sender=a%40b.com&subject=Re%3A%20Invoice%20_1_%20paid".to_string()
+            )]
+        );
+        assert!(metadata_headers(&Default::default()).is_empty());
+    }
+
+    #[test]
+    fn test_uri_encode() {
+        assert_eq!(uri_encode("a b/c~d.pdf", false), "a%20b/c~d.pdf");
+        assert_eq!(uri_encode("a b/c", true), "a%20b%2Fc");
+        assert_eq!(uri_encode("é", true), "%C3%A9");
+    }
+
+    #[test]
+    fn test_split_path() {
+        assert_eq!(
+            split_path("/vaulty/2020/a.pdf").unwrap(),
+            ("vaulty", "2020/a.pdf")
+        );
+        assert!(split_path("/vaulty").is_err());
+        assert!(split_path("").is_err());
+    }
+}
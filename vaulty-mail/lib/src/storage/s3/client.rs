@@ -0,0 +1,544 @@
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use chrono::Utc;
+use futures::stream::{Stream, StreamExt};
+use reqwest::header::ETAG;
+use reqwest::{Method, StatusCode};
+
+use super::api;
+
+use crate::storage::client::{ByteStream, Client, ClientFuture, Metadata, StoredObject};
+use crate::storage::util::{autorename, suffixed};
+use crate::storage::xml::extract_tag;
+use crate::storage::Error;
+
+/// Client for S3-compatible object stores.
+///
+/// Requests use path-style addressing (`<endpoint>/<bucket>/<key>`) so that
+/// the client works against MinIO and other self-hosted stores.
+pub struct S3Client {
+    credentials: api::Credentials,
+    endpoint: reqwest::Url,
+    region: String,
+    client: reqwest::Client,
+}
+
+impl S3Client {
+    /// Build a client from a token of the form `ACCESS_KEY_ID:SECRET_ACCESS_KEY`.
+    ///
+    /// If no endpoint is provided, the AWS endpoint for `region` is used.
+    pub fn new(token: &str, endpoint: Option<&str>, region: &str) -> Result<Self, Error> {
+        let credentials = api::Credentials::from_token(token)?;
+
+        let endpoint = match endpoint {
+            Some(e) => reqwest::Url::parse(e)?,
+            None => reqwest::Url::parse(&format!("https://s3.{}.amazonaws.com", region))?,
+        };
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(api::S3_REQUEST_TIMEOUT))
+            .build()
+            .unwrap();
+
+        Ok(Self {
+            credentials,
+            endpoint,
+            region: region.to_string(),
+            client,
+        })
+    }
+
+    /// Build a path-style URL for the object at `path` (`/bucket/key`)
+    fn object_url(&self, path: &str, query: Option<&str>) -> Result<reqwest::Url, Error> {
+        let (bucket, key) = api::split_path(path)?;
+
+        let mut url = self.endpoint.join(&format!(
+            "/{}/{}",
+            api::uri_encode(bucket, true),
+            api::uri_encode(key, false)
+        ))?;
+        url.set_query(query);
+
+        Ok(url)
+    }
+
+    /// Send a signed request, without mapping the response status
+    async fn send(
+        &self,
+        method: Method,
+        url: reqwest::Url,
+        body: Bytes,
+        headers: &[(&'static str, String)],
+    ) -> Result<reqwest::Response, Error> {
+        let payload_hash = api::sha256_hex(&body);
+        let headers = api::sign(
+            &self.credentials,
+            &self.region,
+            method.as_str(),
+            &url,
+            &payload_hash,
+            headers,
+            &Utc::now(),
+        );
+
+        let mut req = self.client.request(method, url).body(body);
+
+        for (k, v) in headers {
+            req = req.header(k, v);
+        }
+
+        Ok(req.send().await?)
+    }
+
+    #[inline]
+    async fn request(
+        &self,
+        method: Method,
+        url: reqwest::Url,
+        body: Bytes,
+        headers: &[(&'static str, String)],
+    ) -> Result<reqwest::Response, Error> {
+        // Map response into an error if applicable
+        api::map_status(self.send(method, url, body, headers).await?).await
+    }
+
+    /// Check whether an object exists at `path`
+    pub async fn head_object(&self, path: &str) -> Result<bool, Error> {
+        let url = self.object_url(path, None)?;
+        let resp = self.send(Method::HEAD, url, Bytes::new(), &[]).await?;
+
+        match resp.status() {
+            s if s.is_success() => Ok(true),
+            // Without the ListBucket permission, S3 answers 403 instead of
+            // 404 for a missing key. HEAD responses have no error document
+            // to tell either from a missing bucket or bad credentials; the
+            // upload itself will report those.
+            StatusCode::NOT_FOUND | StatusCode::FORBIDDEN => Ok(false),
+            _ => api::map_status(resp).await.map(|_| true),
+        }
+    }
+
+    /// Find a free key for `path`, renaming on collision.
+    /// Candidates before `start` are skipped.
+    ///
+    /// Only the first few candidates are probed; past those, the name is
+    /// made unique with the upload time instead. The conditional upload
+    /// still catches a collision on that name.
+    async fn free_path(&self, path: &str, start: usize) -> Result<(usize, String), Error> {
+        let (dir, name) = match path.rfind('/') {
+            Some(idx) => path.split_at(idx + 1),
+            None => ("", path),
+        };
+
+        for i in start..api::S3_MAX_RENAMES {
+            let candidate = format!("{}{}", dir, autorename(name, i));
+
+            if !self.head_object(&candidate).await? {
+                return Ok((i, candidate));
+            }
+        }
+
+        let suffix = Utc::now().format("%Y%m%d%H%M%S%f").to_string();
+        let i = std::cmp::max(start, api::S3_MAX_RENAMES);
+
+        Ok((i, format!("{}{}", dir, suffixed(name, &suffix))))
+    }
+
+    /// Upload an object with a single request.
+    ///
+    /// Fails with `PathConflict` if an object already exists at `path`.
+    pub async fn put_object(
+        &self,
+        path: &str,
+        data: Bytes,
+        metadata: &Metadata,
+    ) -> Result<(), Error> {
+        let url = self.object_url(path, None)?;
+        let mut headers = api::metadata_headers(metadata);
+        headers.push(("if-none-match", "*".to_string()));
+
+        let _resp = self.request(Method::PUT, url, data, &headers).await?;
+        Ok(())
+    }
+
+    /// Start a multipart upload and return its upload ID
+    async fn create_multipart_upload(
+        &self,
+        path: &str,
+        metadata: &Metadata,
+    ) -> Result<String, Error> {
+        let url = self.object_url(path, Some("uploads="))?;
+        let headers = api::metadata_headers(metadata);
+        let resp = self
+            .request(Method::POST, url, Bytes::new(), &headers)
+            .await?;
+        let body = resp.text().await?;
+
+        extract_tag(&body, "UploadId")
+            .ok_or_else(|| Error::Internal(format!("No UploadId in response: {}", body)))
+    }
+
+    /// Upload a single part and return its ETag
+    async fn upload_part(
+        &self,
+        path: &str,
+        upload_id: &str,
+        part_number: u32,
+        data: Bytes,
+    ) -> Result<String, Error> {
+        let query = format!(
+            "partNumber={}&uploadId={}",
+            part_number,
+            api::uri_encode(upload_id, true)
+        );
+        let url = self.object_url(path, Some(&query))?;
+        let resp = self.request(Method::PUT, url, data, &[]).await?;
+
+        resp.headers()
+            .get(ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from)
+            .ok_or_else(|| Error::Internal(format!("No ETag returned for part {}", part_number)))
+    }
+
+    async fn complete_multipart_upload(
+        &self,
+        path: &str,
+        upload_id: &str,
+        parts: &[(u32, String)],
+    ) -> Result<(), Error> {
+        let query = format!("uploadId={}", api::uri_encode(upload_id, true));
+        let url = self.object_url(path, Some(&query))?;
+
+        let parts = parts
+            .iter()
+            .map(|(n, etag)| {
+                format!(
+                    "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                    n, etag
+                )
+            })
+            .collect::<String>();
+        let body = format!(
+            "<CompleteMultipartUpload>{}</CompleteMultipartUpload>",
+            parts
+        );
+
+        // Never replace an object created while the parts were uploading
+        let headers = [("if-none-match", "*".to_string())];
+        let resp = self
+            .request(Method::POST, url, body.into(), &headers)
+            .await?;
+
+        // S3 can return a 200 with an error document for this call
+        let body = resp.text().await?;
+        if let Some(code) = extract_tag(&body, "Code") {
+            return Err(Error::Internal(code));
+        }
+
+        Ok(())
+    }
+
+    async fn abort_multipart_upload(&self, path: &str, upload_id: &str) -> Result<(), Error> {
+        let query = format!("uploadId={}", api::uri_encode(upload_id, true));
+        let url = self.object_url(path, Some(&query))?;
+        let _resp = self.request(Method::DELETE, url, Bytes::new(), &[]).await?;
+        Ok(())
+    }
+
+    /// Upload the rest of a stream as parts of an existing multipart upload.
+    /// Returns the size of the object.
+    ///
+    /// `buf` holds any data already read from the stream.
+    async fn upload_parts<S>(
+        &self,
+        path: &str,
+        upload_id: &str,
+        mut buf: BytesMut,
+        mut data: S,
+    ) -> Result<usize, Error>
+    where
+        S: Stream<Item = Result<Bytes, crate::Error>> + Send + Unpin,
+    {
+        let mut parts = Vec::new();
+        let mut size = 0;
+        let mut done = false;
+
+        loop {
+            // Fill the buffer up to a full part, unless the stream ends first
+            while buf.len() < api::S3_PART_SIZE && !done {
+                match data.next().await {
+                    Some(chunk) => buf.extend_from_slice(&chunk.map_err(map_stream_error)?),
+                    None => done = true,
+                }
+            }
+
+            if buf.is_empty() {
+                break;
+            }
+
+            let len = std::cmp::min(buf.len(), api::S3_PART_SIZE);
+            let part = buf.split_to(len).freeze();
+            let part_number = parts.len() as u32 + 1;
+            size += part.len();
+
+            let etag = self.upload_part(path, upload_id, part_number, part).await?;
+            parts.push((part_number, etag));
+        }
+
+        self.complete_multipart_upload(path, upload_id, &parts)
+            .await
+            .map(|_| size)
+    }
+}
+
+fn map_stream_error(err: crate::Error) -> Error {
+    Error::Internal(err.to_string())
+}
+
+fn stored_object(path: String, size: usize) -> StoredObject {
+    StoredObject {
+        id: path.clone(),
+        path,
+        size: size as u64,
+        hash: None,
+    }
+}
+
+impl Client for S3Client {
+    /// Upload a stream to an S3 bucket.
+    ///
+    /// Streams larger than a single part are sent as a multipart upload, so
+    /// at most one part is buffered in memory at a time. Existing objects are
+    /// never replaced: the file is renamed instead, like Dropbox does.
+    fn upload_stream(
+        &self,
+        path: &str,
+        data: ByteStream,
+        metadata: &Metadata,
+    ) -> ClientFuture<'_, Option<StoredObject>> {
+        let path = path.to_string();
+        let metadata = metadata.clone();
+
+        Box::pin(async move {
+            let mut data = Box::pin(data);
+            let mut buf = BytesMut::with_capacity(api::S3_PART_SIZE);
+
+            // Small attachments fit in a single part: just PUT them
+            while buf.len() < api::S3_PART_SIZE {
+                match data.next().await {
+                    Some(chunk) => buf.extend_from_slice(&chunk.map_err(map_stream_error)?),
+                    None => {
+                        let data = buf.freeze();
+                        let mut start = 0;
+
+                        // Another upload may take the free name before we do
+                        loop {
+                            let (i, path) = self.free_path(&path, start).await?;
+
+                            match self.put_object(&path, data.clone(), &metadata).await {
+                                Ok(_) => return Ok(Some(stored_object(path, data.len()))),
+                                Err(Error::PathConflict(_)) => start = i + 1,
+                                Err(e) => return Err(e),
+                            }
+                        }
+                    }
+                }
+            }
+
+            // The key of a multipart upload is fixed when it starts
+            let (_, path) = self.free_path(&path, 0).await?;
+
+            let upload_id = self.create_multipart_upload(&path, &metadata).await?;
+            let result = self.upload_parts(&path, &upload_id, buf, data).await;
+
+            // Cleanup the parts already uploaded so they are not billed
+            if result.is_err() {
+                if let Err(e) = self.abort_multipart_upload(&path, &upload_id).await {
+                    log::error!("Failed to abort S3 upload {}: {}", upload_id, e);
+                }
+            }
+
+            result.map(|size| Some(stored_object(path, size)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+
+    use warp::Filter;
+
+    /// State of a minimal S3 server, which only knows HEAD and PUT
+    #[derive(Default)]
+    struct MockState {
+        /// Encoded paths (`/bucket/key`) of stored objects
+        objects: HashSet<String>,
+        /// Answer 403 for missing objects, like S3 does without the
+        /// ListBucket permission
+        forbid_missing: bool,
+        heads: usize,
+    }
+
+    fn start_mock(state: Arc<Mutex<MockState>>) -> S3Client {
+        let routes = warp::method()
+            .and(warp::path::full())
+            .and(warp::header::optional::<String>("if-none-match"))
+            .map(
+                move |method, path: warp::path::FullPath, if_none_match: Option<String>| {
+                    let mut state = state.lock().unwrap();
+                    let exists = state.objects.contains(path.as_str());
+
+                    let status = match method {
+                        warp::http::Method::HEAD => {
+                            state.heads += 1;
+
+                            match (exists, state.forbid_missing) {
+                                (true, _) => 200,
+                                (false, true) => 403,
+                                (false, false) => 404,
+                            }
+                        }
+                        warp::http::Method::PUT if exists && if_none_match.is_some() => 412,
+                        warp::http::Method::PUT => {
+                            state.objects.insert(path.as_str().to_string());
+                            200
+                        }
+                        _ => 405,
+                    };
+
+                    warp::reply::with_status(
+                        warp::reply(),
+                        warp::http::StatusCode::from_u16(status).unwrap(),
+                    )
+                },
+            );
+
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let endpoint = format!("http://{}", addr);
+        S3Client::new("access:secret", Some(&endpoint), "us-east-1").unwrap()
+    }
+
+    fn encoded_path(path: &str) -> String {
+        let (bucket, key) = api::split_path(path).unwrap();
+        format!("/{}/{}", bucket, api::uri_encode(key, false))
+    }
+
+    async fn upload(client: &S3Client, path: &str) -> StoredObject {
+        let data = futures::stream::iter(vec![Ok(Bytes::from("Hello there!"))]);
+
+        client
+            .upload_stream(path, Box::pin(data), &Metadata::default())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_mock_upload_stream_collision() {
+        let state = Arc::new(Mutex::new(MockState::default()));
+        let client = start_mock(state.clone());
+
+        // Missing keys are reported as forbidden
+        state.lock().unwrap().forbid_missing = true;
+
+        let first = upload(&client, "/bucket/vaulty/test.txt").await;
+        let second = upload(&client, "/bucket/vaulty/test.txt").await;
+
+        assert_eq!(first.path, "/bucket/vaulty/test.txt");
+        assert_eq!(first.size, 12);
+        assert_eq!(second.path, "/bucket/vaulty/test (1).txt");
+        assert!(client.head_object(&second.path).await.unwrap());
+
+        let result = client
+            .put_object(&first.path, Bytes::from("Hello!"), &Metadata::default())
+            .await;
+
+        match result {
+            Err(Error::PathConflict(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mock_upload_stream_unique_name() {
+        let state = Arc::new(Mutex::new(MockState::default()));
+        let client = start_mock(state.clone());
+
+        for i in 0..api::S3_MAX_RENAMES {
+            let path = format!("/bucket/vaulty/{}", autorename("test.txt", i));
+            state.lock().unwrap().objects.insert(encoded_path(&path));
+        }
+
+        let object = upload(&client, "/bucket/vaulty/test.txt").await;
+
+        // Past the probed candidates, the upload time makes the name unique
+        let prefix = "/bucket/vaulty/test (";
+        assert!(object.path.starts_with(prefix));
+        assert!(object.path.ends_with(").txt"));
+
+        let suffix = &object.path[prefix.len()..object.path.len() - 5];
+        assert_eq!(suffix.len(), 23);
+        assert!(suffix.chars().all(|c| c.is_ascii_digit()));
+
+        assert_eq!(state.lock().unwrap().heads, api::S3_MAX_RENAMES);
+    }
+
+    fn get_client() -> S3Client {
+        let token = std::env::var("S3_TOKEN").expect("No S3 token found");
+        let endpoint = std::env::var("S3_ENDPOINT").ok();
+        let region = std::env::var("S3_REGION").unwrap_or("us-east-1".to_string());
+
+        S3Client::new(&token, endpoint.as_deref(), &region).unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_upload_stream_collision() {
+        let client = get_client();
+        let mut paths = Vec::new();
+
+        for _ in 0..2 {
+            let object = upload(&client, "/vaulty/vaulty_test.txt").await;
+
+            assert_eq!(object.size, 12);
+            paths.push(object.path);
+        }
+
+        // The second upload did not replace the first
+        assert_ne!(paths[0], paths[1]);
+        assert!(client.head_object(&paths[0]).await.unwrap());
+        assert!(client.head_object(&paths[1]).await.unwrap());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_upload_stream_multipart() {
+        let client = get_client();
+
+        // Two and a half parts
+        let chunk = Bytes::from(vec![0u8; api::S3_PART_SIZE / 2]);
+        let data = futures::stream::iter((0..5).map(move |_| Ok(chunk.clone())));
+
+        let object = client
+            .upload_stream(
+                "/vaulty/vaulty_multipart.bin",
+                Box::pin(data),
+                &Metadata::default(),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(object.path.starts_with("/vaulty/vaulty_multipart"));
+        assert_eq!(object.size as usize, api::S3_PART_SIZE * 5 / 2);
+        assert!(client.head_object(&object.path).await.unwrap());
+    }
+}
@@ -0,0 +1,68 @@
+use crate::storage::Error;
+
+use serde::Deserialize;
+
+pub(crate) const SFTP_DEFAULT_PORT: u16 = 22;
+
+// Session timeout, in milliseconds
+pub(crate) const SFTP_TIMEOUT: u32 = 30 * 1000;
+
+// Number of chunks buffered between the stream and the blocking writer
+pub(crate) const SFTP_CHANNEL_SIZE: usize = 4;
+
+// Permissions for created folders and files
+pub(crate) const SFTP_DIR_MODE: i32 = 0o755;
+pub(crate) const SFTP_FILE_MODE: i32 = 0o644;
+
+fn default_port() -> u16 {
+    SFTP_DEFAULT_PORT
+}
+
+/// SFTP server, credentials, and host key for an address.
+///
+/// Stored as JSON in the address storage token. Either a password or a
+/// private key (PEM) must be set. `known_hosts` holds one or more lines in
+/// OpenSSH `known_hosts` format; the server key must match one of them.
+#[derive(Clone, Deserialize, Debug)]
+pub struct Credentials {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub username: String,
+    pub password: Option<String>,
+    pub private_key: Option<String>,
+    pub passphrase: Option<String>,
+    pub known_hosts: String,
+}
+
+impl Credentials {
+    pub fn from_token(token: &str) -> Result<Self, Error> {
+        let credentials: Self = serde_json::from_str(token)
+            .map_err(|e| Error::BadInput(format!("Invalid SFTP credentials: {}", e)))?;
+
+        if credentials.password.is_none() && credentials.private_key.is_none() {
+            return Err(Error::BadInput(
+                "SFTP credentials require a password or private key".to_string(),
+            ));
+        }
+
+        Ok(credentials)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_credentials_from_token() {
+        let token = r#"{"host": "127.0.0.1", "username": "alice", "password": "secret",
+                        "known_hosts": "127.0.0.1 ssh-ed25519 AAAA"}"#;
+        let credentials = Credentials::from_token(token).unwrap();
+
+        assert_eq!(credentials.port, 22);
+
+        let token = r#"{"host": "127.0.0.1", "username": "alice", "known_hosts": ""}"#;
+        assert!(Credentials::from_token(token).is_err());
+    }
+}
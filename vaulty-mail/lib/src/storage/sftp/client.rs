@@ -0,0 +1,326 @@
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::sink::SinkExt;
+use futures::stream::StreamExt;
+use ssh2::{CheckResult, KnownHostFileKind, RenameFlags, Session, Sftp};
+
+use super::api;
+
+use crate::storage::client::{ByteStream, Client, ClientFuture, Metadata, StoredObject};
+use crate::storage::util::{autorename, temp_name};
+use crate::storage::Error;
+
+// Max number of renamed candidates to try on a name collision
+const SFTP_MAX_RENAMES: usize = 1000;
+
+/// Uploads files to a remote server over SFTP.
+///
+/// libssh2 is blocking, so all work happens on the blocking thread pool.
+pub struct SftpClient {
+    credentials: api::Credentials,
+}
+
+impl SftpClient {
+    /// Build a client from a JSON storage token (see `api::Credentials`)
+    pub fn from_token(token: &str) -> Result<Self, Error> {
+        Ok(Self {
+            credentials: api::Credentials::from_token(token)?,
+        })
+    }
+}
+
+/// Connect to the server, trying each of its addresses in turn
+fn connect_tcp(host: &str, port: u16) -> Result<TcpStream, Error> {
+    let timeout = Duration::from_millis(api::SFTP_TIMEOUT as u64);
+    let mut last_error = None;
+
+    let addrs = (host, port)
+        .to_socket_addrs()
+        .map_err(|e| Error::BadEndpoint(format!("Invalid SFTP host {}: {}", host, e)))?;
+
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(tcp) => return Ok(tcp),
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    Err(Error::BadEndpoint(match last_error {
+        Some(e) => format!("Failed to connect to {}: {}", host, e),
+        None => format!("No address found for {}", host),
+    }))
+}
+
+/// Open an authenticated SFTP session.
+///
+/// The server host key is checked against the known hosts in the token
+/// before any credentials are sent.
+fn connect(credentials: &api::Credentials) -> Result<Sftp, Error> {
+    let tcp = connect_tcp(&credentials.host, credentials.port)?;
+
+    let mut session = Session::new()?;
+    session.set_timeout(api::SFTP_TIMEOUT);
+    session.set_tcp_stream(tcp);
+    session.handshake()?;
+
+    let (key, _) = session
+        .host_key()
+        .ok_or_else(|| Error::Internal("No host key sent by server".to_string()))?;
+
+    let mut known_hosts = session.known_hosts()?;
+    known_hosts.read_str(&credentials.known_hosts, KnownHostFileKind::OpenSSH)?;
+
+    match known_hosts.check_port(&credentials.host, credentials.port, key) {
+        CheckResult::Match => (),
+        CheckResult::Mismatch => {
+            return Err(Error::BadEndpoint(format!(
+                "Host key mismatch for {}",
+                credentials.host
+            )))
+        }
+        _ => {
+            return Err(Error::BadEndpoint(format!(
+                "Unknown host key for {}",
+                credentials.host
+            )))
+        }
+    }
+
+    let auth = match (&credentials.private_key, &credentials.password) {
+        (Some(key), _) => session.userauth_pubkey_memory(
+            &credentials.username,
+            None,
+            key,
+            credentials.passphrase.as_deref(),
+        ),
+        (None, Some(password)) => session.userauth_password(&credentials.username, password),
+        (None, None) => {
+            return Err(Error::BadInput(
+                "SFTP credentials require a password or private key".to_string(),
+            ))
+        }
+    };
+
+    if auth.is_err() || !session.authenticated() {
+        return Err(Error::TokenExpired(format!(
+            "SFTP authentication failed for {}@{}",
+            credentials.username, credentials.host
+        )));
+    }
+
+    Ok(session.sftp()?)
+}
+
+/// Create a folder along with any missing parent folders
+fn create_dir_all(sftp: &Sftp, dir: &Path) -> Result<(), Error> {
+    let mut current = PathBuf::new();
+
+    for component in dir.components() {
+        current.push(component);
+
+        if sftp.stat(&current).is_err() {
+            sftp.mkdir(&current, api::SFTP_DIR_MODE)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Write the chunks to a temporary file, then rename it into place.
+///
+/// Renames never overwrite, so on a collision the next candidate name is
+/// tried. If `mtime` is set, it is used as the modification time.
+fn upload_blocking(
+    credentials: &api::Credentials,
+    path: &str,
+    data: impl Iterator<Item = Result<Bytes, crate::Error>>,
+    mtime: Option<u64>,
+) -> Result<StoredObject, Error> {
+    let path = Path::new(path);
+    let (dir, name) = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => (dir, name.to_string_lossy().to_string()),
+        _ => return Err(Error::BadInput(format!("Invalid path: {}", path.display()))),
+    };
+
+    let sftp = connect(credentials)?;
+    create_dir_all(&sftp, dir)?;
+
+    let tmp_path = dir.join(temp_name(&name));
+
+    let written: Result<u64, Error> = (|| {
+        let mut file = sftp.open_mode(
+            &tmp_path,
+            ssh2::OpenFlags::WRITE | ssh2::OpenFlags::CREATE | ssh2::OpenFlags::TRUNCATE,
+            api::SFTP_FILE_MODE,
+            ssh2::OpenType::File,
+        )?;
+        let mut size = 0;
+
+        for chunk in data {
+            let chunk = chunk.map_err(|e| Error::Internal(e.to_string()))?;
+            file.write_all(&chunk)?;
+            size += chunk.len() as u64;
+        }
+
+        // Close the file first so that no later write touches the times
+        drop(file);
+
+        if let Some(mtime) = mtime {
+            sftp.setstat(
+                &tmp_path,
+                ssh2::FileStat {
+                    size: None,
+                    uid: None,
+                    gid: None,
+                    perm: None,
+                    atime: Some(mtime),
+                    mtime: Some(mtime),
+                },
+            )?;
+        }
+
+        Ok(size)
+    })();
+
+    let size = match written {
+        Ok(size) => size,
+        Err(e) => {
+            let _ = sftp.unlink(&tmp_path);
+            return Err(e);
+        }
+    };
+
+    for i in 0..SFTP_MAX_RENAMES {
+        let dest = dir.join(autorename(&name, i));
+
+        match sftp.rename(
+            &tmp_path,
+            &dest,
+            Some(RenameFlags::ATOMIC | RenameFlags::NATIVE),
+        ) {
+            Ok(_) => {
+                log::debug!("Uploaded file to {}", dest.display());
+
+                let dest = dest.to_string_lossy().to_string();
+
+                return Ok(StoredObject {
+                    id: dest.clone(),
+                    path: dest,
+                    size,
+                    hash: None,
+                });
+            }
+            Err(e) => {
+                // Only move on to the next name if this one is taken
+                if sftp.stat(&dest).is_err() {
+                    let _ = sftp.unlink(&tmp_path);
+                    return Err(e.into());
+                }
+            }
+        }
+    }
+
+    let _ = sftp.unlink(&tmp_path);
+
+    Err(Error::BadEndpoint(format!(
+        "Too many files named {} in {}",
+        name,
+        dir.display()
+    )))
+}
+
+impl Client for SftpClient {
+    /// Upload a file to a remote server over SFTP.
+    ///
+    /// The stream is read here and its chunks are passed to the blocking
+    /// writer through a bounded channel, so at most a few chunks are held
+    /// in memory at a time.
+    fn upload_stream(
+        &self,
+        path: &str,
+        mut data: ByteStream,
+        metadata: &Metadata,
+    ) -> ClientFuture<'_, Option<StoredObject>> {
+        let credentials = self.credentials.clone();
+        let path = path.to_string();
+        let mtime = metadata.modified.map(|t| t.timestamp() as u64);
+
+        Box::pin(async move {
+            let (mut tx, rx) = futures::channel::mpsc::channel(api::SFTP_CHANNEL_SIZE);
+
+            let upload = tokio::task::spawn_blocking(move || {
+                let chunks = futures::executor::block_on_stream(rx);
+                upload_blocking(&credentials, &path, chunks, mtime)
+            });
+
+            while let Some(chunk) = data.next().await {
+                // The upload stopped early; its result tells why
+                if tx.send(chunk).await.is_err() {
+                    break;
+                }
+            }
+
+            drop(tx);
+
+            upload
+                .await
+                .map_err(|e| Error::Internal(e.to_string()))?
+                .map(Some)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_connect_unreachable() {
+        // Nothing listens on the discard port
+        let token = r#"{"host": "127.0.0.1", "port": 9, "username": "a", "password": "b", "known_hosts": ""}"#;
+        let client = SftpClient::from_token(token).unwrap();
+        let data = futures::stream::iter(vec![Ok(Bytes::from("Hello there!"))]);
+
+        let result = client
+            .upload_stream("vaulty/test.txt", Box::pin(data), &Metadata::default())
+            .await;
+
+        match result {
+            Err(Error::BadEndpoint(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_upload_stream() {
+        let token = std::env::var("SFTP_TOKEN").expect("No SFTP token found");
+        let client = SftpClient::from_token(&token).unwrap();
+        let mut paths = Vec::new();
+
+        for _ in 0..2 {
+            let data =
+                futures::stream::iter(vec![Ok(Bytes::from("Hello ")), Ok(Bytes::from("there!"))]);
+            let object = client
+                .upload_stream(
+                    "vaulty/sftp/vaulty_test.txt",
+                    Box::pin(data),
+                    &Metadata::default(),
+                )
+                .await
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(object.size, 12);
+            paths.push(object.path);
+        }
+
+        // The second upload was renamed instead of replacing the first
+        assert!(paths[0].ends_with("vaulty/sftp/vaulty_test.txt"));
+        assert!(paths[1].ends_with("vaulty/sftp/vaulty_test (1).txt"));
+    }
+}
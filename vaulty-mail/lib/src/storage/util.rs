@@ -0,0 +1,67 @@
+use chrono::Utc;
+
+// Max number of characters of the file name kept in a temporary file name,
+// leaving room for the suffix within NAME_MAX
+const TEMP_NAME_LEN: usize = 64;
+
+/// Build the `i`-th candidate name for a file, matching Dropbox's
+/// autorename behavior: `file.txt`, `file (1).txt`, `file (2).txt`, ...
+pub fn autorename(name: &str, i: usize) -> String {
+    if i == 0 {
+        return name.to_string();
+    }
+
+    suffixed(name, &i.to_string())
+}
+
+/// Add `suffix` to a file name, before its extension: `file (suffix).txt`
+pub fn suffixed(name: &str, suffix: &str) -> String {
+    match name.rfind('.') {
+        Some(idx) if idx > 0 => format!("{} ({}){}", &name[..idx], suffix, &name[idx..]),
+        _ => format!("{} ({})", name, suffix),
+    }
+}
+
+/// Build a hidden, unique name for a temporary file holding `name`.
+///
+/// Long names are truncated so that the result stays a valid file name.
+pub fn temp_name(name: &str) -> String {
+    let name = match name.char_indices().nth(TEMP_NAME_LEN) {
+        Some((idx, _)) => &name[..idx],
+        None => name,
+    };
+
+    let now = Utc::now();
+
+    format!(
+        ".{}.{}.{}.tmp",
+        name,
+        std::process::id(),
+        now.timestamp_nanos_opt()
+            .unwrap_or_else(|| now.timestamp_micros())
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_autorename() {
+        assert_eq!(autorename("a.pdf", 0), "a.pdf");
+        assert_eq!(autorename("a.tar.gz", 2), "a.tar (2).gz");
+        assert_eq!(autorename("README", 1), "README (1)");
+        assert_eq!(autorename(".bashrc", 1), ".bashrc (1)");
+        assert_eq!(suffixed("a.pdf", "abc"), "a (abc).pdf");
+    }
+
+    #[test]
+    fn test_temp_name() {
+        let name = "é".repeat(240);
+        let tmp = temp_name(&name);
+
+        assert!(tmp.starts_with(&format!(".{}.", "é".repeat(TEMP_NAME_LEN))));
+        assert!(tmp.ends_with(".tmp"));
+        assert!(tmp.len() < 255);
+    }
+}
@@ -0,0 +1,73 @@
+use crate::storage::Error;
+
+use reqwest::StatusCode;
+
+use serde::Deserialize;
+
+// Header used by Nextcloud and ownCloud to set the modification time of an
+// uploaded file, in seconds
+pub const WEBDAV_MTIME_HEADER: &str = "X-OC-Mtime";
+
+// Request timeout, in seconds
+pub(crate) const WEBDAV_REQUEST_TIMEOUT: u64 = 60;
+
+// Max number of renamed candidates to try on a name collision
+pub(crate) const WEBDAV_MAX_RENAMES: usize = 1000;
+
+/// WebDAV server and credentials for an address.
+///
+/// Stored as JSON in the address storage token. For Nextcloud, `url` is
+/// the user's files root (e.g., `https://cloud.example.com/remote.php/dav/files/alice/`)
+/// and `password` is an app password.
+#[derive(Deserialize, Debug)]
+pub struct Credentials {
+    pub url: String,
+    pub username: String,
+    pub password: String,
+}
+
+impl Credentials {
+    pub fn from_token(token: &str) -> Result<Self, Error> {
+        serde_json::from_str(token)
+            .map_err(|e| Error::BadInput(format!("Invalid WebDAV credentials: {}", e)))
+    }
+}
+
+/// Map possible WebDAV errors to generic storage backend error
+pub fn map_status(resp: reqwest::Response) -> Result<reqwest::Response, Error> {
+    let err = resp.error_for_status_ref();
+
+    if let Err(e) = err {
+        let status = e.status().unwrap();
+        let msg = e.to_string();
+
+        match status {
+            StatusCode::BAD_REQUEST => Err(Error::BadInput(msg)),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(Error::TokenExpired(msg)),
+            StatusCode::NOT_FOUND | StatusCode::CONFLICT => Err(Error::BadEndpoint(msg)),
+            // The target of a conditional PUT or MOVE already exists
+            StatusCode::PRECONDITION_FAILED => Err(Error::PathConflict(msg)),
+            StatusCode::LOCKED
+            | StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::SERVICE_UNAVAILABLE => Err(Error::RateLimited(msg)),
+            _ => Err(Error::Internal(msg)),
+        }
+    } else {
+        Ok(resp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_credentials_from_token() {
+        let token =
+            r#"{"url": "http://127.0.0.1/dav/", "username": "alice", "password": "secret"}"#;
+        let credentials = Credentials::from_token(token).unwrap();
+
+        assert_eq!(credentials.username, "alice");
+        assert!(Credentials::from_token("alice:secret").is_err());
+    }
+}
@@ -0,0 +1,318 @@
+use std::time::Duration;
+
+use reqwest::header::{CONTENT_TYPE, IF_NONE_MATCH};
+use reqwest::{Method, StatusCode};
+
+use super::api;
+
+use crate::storage::client::{ByteStream, Client, ClientFuture, Metadata, StoredObject};
+use crate::storage::util::{autorename, temp_name};
+use crate::storage::Error;
+
+pub struct WebdavClient {
+    credentials: api::Credentials,
+    base_url: reqwest::Url,
+    client: reqwest::Client,
+}
+
+impl WebdavClient {
+    /// Build a client from a JSON storage token (see `api::Credentials`)
+    pub fn from_token(token: &str) -> Result<Self, Error> {
+        let credentials = api::Credentials::from_token(token)?;
+        let base_url = reqwest::Url::parse(&credentials.url)?;
+
+        if base_url.cannot_be_a_base() {
+            return Err(Error::BadInput(format!(
+                "Invalid WebDAV URL: {}",
+                credentials.url
+            )));
+        }
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(api::WEBDAV_REQUEST_TIMEOUT))
+            .build()
+            .unwrap();
+
+        Ok(Self {
+            credentials,
+            base_url,
+            client,
+        })
+    }
+
+    /// Build the URL for a path relative to the WebDAV root.
+    /// Each path segment is percent-encoded.
+    fn build_url(&self, path: &str) -> reqwest::Url {
+        let mut url = self.base_url.clone();
+
+        // Checked in `from_token`
+        url.path_segments_mut()
+            .unwrap()
+            .pop_if_empty()
+            .extend(path.split('/').filter(|s| !s.is_empty()));
+
+        url
+    }
+
+    #[inline]
+    fn request(&self, method: Method, path: &str) -> reqwest::RequestBuilder {
+        self.client
+            .request(method, self.build_url(path))
+            .basic_auth(&self.credentials.username, Some(&self.credentials.password))
+    }
+
+    /// Create a single collection (folder).
+    /// Returns `false` if the collection already exists.
+    pub async fn mkcol(&self, path: &str) -> Result<bool, Error> {
+        let method = Method::from_bytes(b"MKCOL").unwrap();
+        let resp = self.request(method, path).send().await?;
+
+        // 405 is returned when the collection already exists
+        if resp.status() == StatusCode::METHOD_NOT_ALLOWED {
+            return Ok(false);
+        }
+
+        let _resp = api::map_status(resp)?;
+
+        Ok(true)
+    }
+
+    /// Create a folder along with any missing parent folders
+    pub async fn create_folder(&self, path: &str) -> Result<(), Error> {
+        let mut current = String::new();
+
+        for part in path.split('/').filter(|s| !s.is_empty()) {
+            current.push('/');
+            current.push_str(part);
+
+            self.mkcol(&current).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Upload a file to the server in a single request.
+    /// Fails with `PathConflict` if a file already exists at `path`.
+    pub async fn upload(&self, path: &str, data: Vec<u8>) -> Result<(), Error> {
+        let req = self
+            .request(Method::PUT, path)
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .header(IF_NONE_MATCH, "*")
+            .body(data);
+
+        let _resp = api::map_status(req.send().await?)?;
+
+        Ok(())
+    }
+
+    /// Move a file without replacing an existing one.
+    /// Fails with `PathConflict` if a file already exists at `to`.
+    pub async fn move_file(&self, from: &str, to: &str) -> Result<(), Error> {
+        let method = Method::from_bytes(b"MOVE").unwrap();
+        let req = self
+            .request(method, from)
+            .header("Destination", self.build_url(to).as_str())
+            .header("Overwrite", "F");
+
+        let _resp = api::map_status(req.send().await?)?;
+
+        Ok(())
+    }
+
+    pub async fn delete_file(&self, path: &str) -> Result<(), Error> {
+        let resp = self.request(Method::DELETE, path).send().await?;
+        let _resp = api::map_status(resp)?;
+
+        Ok(())
+    }
+
+    /// Move an uploaded temporary file to `name` in `dir`, renaming on
+    /// collision. Returns the final path.
+    async fn persist(&self, tmp_path: &str, dir: &str, name: &str) -> Result<String, Error> {
+        for i in 0..api::WEBDAV_MAX_RENAMES {
+            let dest = format!("{}/{}", dir, autorename(name, i));
+
+            match self.move_file(tmp_path, &dest).await {
+                Ok(_) => return Ok(dest),
+                Err(Error::PathConflict(_)) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(Error::PathConflict(format!(
+            "Too many files named {} in {}",
+            name, dir
+        )))
+    }
+}
+
+impl Client for WebdavClient {
+    /// Upload a file to a WebDAV server.
+    ///
+    /// Missing folders are created first. The data is sent as a single PUT
+    /// using chunked transfer encoding, so it is never buffered in full.
+    ///
+    /// A stream cannot be sent twice, so the file is first written to a
+    /// temporary name and then moved into place without overwriting,
+    /// renaming on collision like Dropbox does.
+    fn upload_stream(
+        &self,
+        path: &str,
+        data: ByteStream,
+        metadata: &Metadata,
+    ) -> ClientFuture<'_, Option<StoredObject>> {
+        let path = path.to_string();
+        let modified = metadata.modified;
+
+        Box::pin(async move {
+            let (dir, name) = match path.rfind('/') {
+                Some(i) => (&path[..i], &path[i + 1..]),
+                None => ("", path.as_str()),
+            };
+
+            if name.is_empty() {
+                return Err(Error::BadInput(format!("Invalid path: {}", path)));
+            }
+
+            self.create_folder(dir).await?;
+
+            let tmp_path = format!("{}/{}", dir, temp_name(name));
+
+            let mut req = self
+                .request(Method::PUT, &tmp_path)
+                .header(CONTENT_TYPE, "application/octet-stream")
+                .header(IF_NONE_MATCH, "*")
+                .body(reqwest::Body::wrap_stream(data));
+
+            // Nextcloud and ownCloud set the modification time from this
+            // header; other servers ignore it
+            if let Some(modified) = modified {
+                req = req.header(api::WEBDAV_MTIME_HEADER, modified.timestamp());
+            }
+
+            let _resp = api::map_status(req.send().await?)?;
+
+            match self.persist(&tmp_path, dir, name).await {
+                Ok(dest) => {
+                    log::debug!("Uploaded file to {}", dest);
+                    Ok(None)
+                }
+                Err(e) => {
+                    if let Err(e) = self.delete_file(&tmp_path).await {
+                        log::error!("Failed to delete WebDAV file {}: {}", tmp_path, e);
+                    }
+
+                    Err(e)
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::Bytes;
+
+    #[test]
+    fn test_build_url() {
+        let token = r#"{"url": "http://127.0.0.1/dav/files/alice/", "username": "alice", "password": "secret"}"#;
+        let client = WebdavClient::from_token(token).unwrap();
+
+        assert_eq!(
+            client.build_url("/vaulty/my receipt.pdf").as_str(),
+            "http://127.0.0.1/dav/files/alice/vaulty/my%20receipt.pdf"
+        );
+    }
+
+    fn get_client() -> WebdavClient {
+        let token = std::env::var("WEBDAV_TOKEN").expect("No WebDAV token found");
+        WebdavClient::from_token(&token).unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_create_folder() {
+        let client = get_client();
+
+        client.create_folder("/vaulty/abcde").await.unwrap();
+
+        // The folder and its parent already exist
+        assert!(!client.mkcol("/vaulty").await.unwrap());
+        assert!(!client.mkcol("/vaulty/abcde").await.unwrap());
+
+        client.delete_file("/vaulty/abcde").await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_upload_stream() {
+        let client = get_client();
+        let data = futures::stream::iter(vec![Ok(Bytes::from("Hello there!"))]);
+
+        client
+            .upload_stream(
+                "/vaulty/stream/vaulty_test.txt",
+                Box::pin(data),
+                &Metadata::default(),
+            )
+            .await
+            .unwrap();
+
+        // The file was moved into place from its temporary name
+        match client
+            .upload("/vaulty/stream/vaulty_test.txt", b"Hello!".to_vec())
+            .await
+        {
+            Err(Error::PathConflict(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+
+        client.delete_file("/vaulty/stream").await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_upload_stream_collision() {
+        let client = get_client();
+
+        for _ in 0..2 {
+            let data = futures::stream::iter(vec![Ok(Bytes::from("Hello there!"))]);
+            client
+                .upload_stream(
+                    "/vaulty/collision/vaulty_test.txt",
+                    Box::pin(data),
+                    &Metadata::default(),
+                )
+                .await
+                .unwrap();
+        }
+
+        // The first file was kept, so it cannot be written again
+        let result = client
+            .upload("/vaulty/collision/vaulty_test.txt", b"Hello!".to_vec())
+            .await;
+
+        match result {
+            Err(Error::PathConflict(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+
+        // The second one was renamed, and nothing else was stored
+        for path in &["vaulty_test.txt", "vaulty_test (1).txt"] {
+            client
+                .delete_file(&format!("/vaulty/collision/{}", path))
+                .await
+                .unwrap();
+        }
+
+        match client
+            .delete_file("/vaulty/collision/vaulty_test (2).txt")
+            .await
+        {
+            Err(Error::BadEndpoint(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+}
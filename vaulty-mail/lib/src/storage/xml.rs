@@ -0,0 +1,26 @@
+/// Extract the text of the first `<tag>` element in an XML response.
+///
+/// The XML responses we care about are flat enough that a full parser is
+/// not needed.
+pub fn extract_tag(body: &str, tag: &str) -> Option<String> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+
+    let start = body.find(&open)? + open.len();
+    let end = body[start..].find(&close)? + start;
+
+    Some(body[start..end].to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_tag() {
+        let body = "<Error><Code>NoSuchBucket</Code><Message>Oops</Message></Error>";
+
+        assert_eq!(extract_tag(body, "Code").unwrap(), "NoSuchBucket");
+        assert!(extract_tag(body, "UploadId").is_none());
+    }
+}
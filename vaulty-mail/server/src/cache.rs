@@ -24,6 +24,19 @@ pub struct CacheEntry {
     // for this email
     pub attachments_processed: Vec<u16>,
 
+    // Index and size of the attachments staged to be stored along with the
+    // last one. They are only recorded once the last one is stored.
+    pub attachments_staged: Vec<(u16, usize)>,
+
+    // Whether the original message is sent after the attachments, for
+    // destinations that store whole messages
+    pub wants_message: bool,
+
+    // State kept by the storage clients of each destination between
+    // attachments of this email (e.g., the Notion page, or attachments
+    // staged to be stored together), by destination ID
+    pub email_states: Vec<(Option<i32>, String)>,
+
     pub insertion_time: Option<DateTime<Local>>,
     pub last_updated: Option<DateTime<Local>>,
 }
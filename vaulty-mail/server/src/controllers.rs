@@ -1,3 +1,6 @@
+use std::convert::TryFrom;
+use std::sync::Arc;
+
 use bytes::{buf::Buf, Bytes};
 use futures::stream::{self, FuturesUnordered, Stream, StreamExt, TryStreamExt};
 use lazy_static::lazy_static;
@@ -5,7 +8,7 @@ use serde::Serialize;
 use tokio::sync::RwLock;
 use warp::{self, reply::Reply, Rejection};
 
-use vaulty::{db::LogLevel, email, mailgun};
+use vaulty::{db::LogLevel, email, mailgun, storage::Registry};
 
 use super::cache::{Cache, CacheEntry};
 use super::error::Error;
@@ -18,9 +21,44 @@ lazy_static! {
 pub mod postfix {
     use super::*;
 
+    /// Persist storage tokens that were renewed while handling an email.
+    ///
+    /// The cached address is updated as well, so that later attachments of
+    /// the same email use the new tokens, along with the email state kept by
+    /// the storage clients.
+    async fn save_client_states(
+        handler: &vaulty::EmailHandler<'_>,
+        mail_id: &str,
+        address: &str,
+        db_client: &mut vaulty::db::Client<'_>,
+    ) {
+        for (id, token) in handler.take_refreshed_tokens() {
+            if let Err(e) = db_client
+                .update_destination_token(address, id, &token)
+                .await
+            {
+                log::error!("Failed to save renewed token for {}: {}", address, e);
+            }
+
+            if let Some(entry) = MAIL_CACHE.write().await.get_mut(mail_id) {
+                entry.address.set_storage_token(id, token);
+            }
+        }
+
+        let states = handler.take_email_states();
+
+        if let Some(entry) = MAIL_CACHE.write().await.get_mut(mail_id) {
+            for (id, state) in states {
+                entry.email_states.retain(|(i, _)| *i != id);
+                entry.email_states.push((id, state));
+            }
+        }
+    }
+
     pub async fn email(
         mut email: email::Email,
         mut db: sqlx::PgPool,
+        registry: Arc<Registry>,
     ) -> Result<impl Reply, Rejection> {
         let mut db_client = vaulty::db::Client::new(&mut db);
         let uuid = email.uuid.to_string();
@@ -59,7 +97,7 @@ pub mod postfix {
         // with a unique status code.
         // NOTE: This case should never be hit as Postfix is looking at the
         // same DB.
-        let address = match address {
+        let mut address = match address {
             None => {
                 // We do not use internal UUID here b/c there really is no
                 // history maintained for this email.  Using Message-ID will
@@ -84,8 +122,8 @@ pub mod postfix {
 
         // Update the email to just have the valid recipient address
         // found above
-        let recipient = &address.address;
-        email.recipients.retain(|r| r == recipient);
+        let recipient = address.address.clone();
+        email.recipients.retain(|r| r == &recipient);
 
         // Ensure that sender address is whitelisted
         let valid = address.validate_sender(&email, &mut db_client).await;
@@ -109,6 +147,24 @@ pub mod postfix {
             return Err(warp::reject::custom(err));
         }
 
+        // Backend names come from the DB, so make sure the server knows all
+        // of them before accepting the email
+        let unknown = address
+            .all_destinations()
+            .into_iter()
+            .find(|d| !registry.contains(&d.storage_backend));
+
+        if let Some(destination) = unknown {
+            let name = destination.storage_backend.name().to_string();
+            let e = vaulty::Error::from(vaulty::storage::Error::UnknownBackend(name));
+            let msg = format!("Address {}: {}", recipient, e);
+
+            log::error!("{}", msg);
+            db_client.log(&msg, None, LogLevel::Error).await;
+
+            return Err(warp::reject::custom(Error::from(e)));
+        }
+
         // Insert this email into DB
         if let Err(e) = db_client.insert_email(&email).await {
             let msg = e.to_string();
@@ -157,6 +213,58 @@ pub mod postfix {
             return Err(warp::reject::custom(err));
         }
 
+        // Make sure the storage account itself has room for the attachments,
+        // rather than failing partway through an upload
+        if email.num_attachments > 0 {
+            let (usage, refreshed_tokens) = {
+                let destinations = address.all_destinations();
+                let handler =
+                    vaulty::EmailHandler::new(&registry, &destinations, address.delivery_policy);
+
+                let usage = handler.space_usage(&email).await;
+                (usage, handler.take_refreshed_tokens())
+            };
+
+            // The address is cached for the attachments below, so it must
+            // carry the renewed token
+            for (id, token) in refreshed_tokens {
+                if let Err(e) = db_client
+                    .update_destination_token(&recipient, id, &token)
+                    .await
+                {
+                    log::error!("Failed to save renewed token for {}: {}", recipient, e);
+                }
+
+                address.set_storage_token(id, token);
+            }
+
+            match usage {
+                Ok(Some(usage)) if usage.available().is_some_and(|a| a < email.size as u64) => {
+                    let msg = format!(
+                        "The {} account for address {} is full: {} MB of {} MB used.",
+                        registry.display_name(&address.storage_backend),
+                        recipient,
+                        (usage.used / 1_000_000),
+                        (usage.allocated.unwrap_or(0) / 1_000_000)
+                    );
+
+                    log::warn!("{}", msg);
+
+                    db_client
+                        .log(&msg, Some(&email.uuid), LogLevel::Warning)
+                        .await;
+
+                    db_client.update_email(&email, false, Some(&msg)).await;
+
+                    let err = Error(vaulty::Error::QuotaExceeded(msg));
+                    return Err(warp::reject::custom(err));
+                }
+                Ok(_) => (),
+                // Not being able to check should not block the email
+                Err(e) => log::warn!("Failed to get space usage for {}: {}", recipient, e),
+            }
+        }
+
         // Increment received storage for the email body
         // If this fails, do not proceed with processing this email
         // TODO: Can we do this in a single transaction (merge with above)?
@@ -176,18 +284,49 @@ pub mod postfix {
 
         log::info!("{}, {}", email.sender, uuid);
 
+        // Backends that keep more than attachments store the email itself
+        // (e.g., as a Notion page); attachments are added as they arrive
+        let destinations = address.all_destinations();
+        let handler = vaulty::EmailHandler::new(&registry, &destinations, address.delivery_policy);
+
+        let h = handler.handle_email(&email).await;
+        result.destinations = Some(handler.take_destination_results());
+
+        // The page created for the email is kept for its attachments
+        let email_states = handler.take_email_states();
+
+        // Destinations that store whole messages (e.g., IMAP) get the
+        // original message, which the filter sends after the attachments
+        let wants_message = handler.wants_message();
+        let wants_attachments = email.num_attachments > 0 && handler.wants_attachments();
+
+        if let Err(e) = h {
+            let msg = e.to_string();
+            db_client.update_email(&email, false, Some(&msg)).await;
+
+            return Err(warp::reject::custom(Error::from(e)));
+        }
+
         // Send back a JSON result to the client containing all info
         result.storage_backend = Some(address.storage_backend.clone());
+        result.storage_backend_name =
+            Some(registry.display_name(&address.storage_backend).to_string());
         result.num_attachments = Some(email.num_attachments as i32);
+        result.wants_message = Some(wants_message);
+        result.wants_attachments = Some(wants_attachments);
 
-        // Create a cache entry if email has attachments
-        if email.num_attachments > 0 {
+        // Create a cache entry if we are waiting on attachments, or on the
+        // original message
+        if wants_attachments || wants_message {
             log::info!("Creating cache entry for {}", email.uuid);
 
             let entry = CacheEntry {
                 email,
                 address,
                 attachments_processed: Vec::new(),
+                attachments_staged: Vec::new(),
+                wants_message,
+                email_states,
                 insertion_time: None,
                 last_updated: None,
             };
@@ -206,6 +345,7 @@ pub mod postfix {
         index: u16,
         body: impl Stream<Item = Result<impl Buf, warp::Error>> + Send + Sync + 'static,
         mut db: sqlx::PgPool,
+        registry: Arc<Registry>,
     ) -> Result<impl Reply, Rejection> {
         let mut result = vaulty::api::ServerResult {
             success: true,
@@ -270,8 +410,15 @@ pub mod postfix {
 
         // Check if processing this attachment will result in the user exceeding
         // their quota. We need to check again here because another email may have been
-        // processed in between (e.g., this email has been retried).
-        let is_quota_exceeded = (address.storage_used + size as i64) > address.storage_quota;
+        // processed in between (e.g., this email has been retried). Staged
+        // attachments are not counted in the used storage yet.
+        let staged_size = entry
+            .attachments_staged
+            .iter()
+            .map(|(_, s)| *s as i64)
+            .sum::<i64>();
+        let is_quota_exceeded =
+            (address.storage_used + staged_size + size as i64) > address.storage_quota;
         if is_quota_exceeded {
             let msg = format!(
                 "Address {} has hit its quota of {} MB for this period.",
@@ -291,17 +438,30 @@ pub mod postfix {
             return Err(warp::reject::custom(err));
         }
 
-        let handler = vaulty::EmailHandler::new(
-            &address.storage_token,
-            &address.storage_backend,
-            &address.storage_path,
-        );
+        let destinations = address.all_destinations();
+        let handler = vaulty::EmailHandler::new(&registry, &destinations, address.delivery_policy)
+            .with_email_states(&entry.email_states);
 
         let attachment = body
             .map_ok(|mut b| b.to_bytes())
             .map_err(|e| vaulty::Error::Generic(e.to_string()));
 
-        let h = handler.handle(email, Some(attachment), name, size).await;
+        let is_last = entry.attachments_processed.len() + 1 >= email.num_attachments as usize;
+
+        // Attachments of an email with several of them are staged, and only
+        // stored once the last one is in
+        let is_staged = email.num_attachments > 1 && !is_last;
+
+        let h = handler
+            .handle_attachment(email, attachment, name, size, is_last)
+            .await;
+
+        save_client_states(&handler, &mail_id, &address.address, &mut db_client).await;
+
+        let destination_results = handler.take_destination_results();
+        if !destination_results.is_empty() {
+            result.destinations = Some(destination_results);
+        }
 
         // If an error occurred while processing this attachment,
         // mark the email as failed
@@ -313,39 +473,94 @@ pub mod postfix {
                 .insert_attachment(&email, index, size, false, Some(&msg))
                 .await;
 
+            // The staged attachments were not stored either
+            if is_last {
+                for (i, staged_size) in &entry.attachments_staged {
+                    db_client
+                        .insert_attachment(email, *i, *staged_size, false, Some(&msg))
+                        .await;
+                }
+            }
+
             db_client.update_email(&email, false, Some(&msg)).await;
         }
 
-        let resp = h
-            .map(|_| warp::reply::json(&result))
-            .map_err(|e| warp::reject::custom(Error::from(e)));
-
         // Bail out early if we failed
-        if resp.is_err() {
-            return resp;
+        if let Err(e) = h {
+            return Err(warp::reject::custom(Error::from(e)));
         }
 
-        // Insert successful attachment into DB
-        db_client
-            .insert_attachment(&email, index, size, true, None)
-            .await;
+        // Insert successful attachments into DB, along with the staged ones
+        // this one stored
+        let mut stored = if is_last {
+            entry.attachments_staged.clone()
+        } else {
+            Vec::new()
+        };
 
-        // Update used storage for this attachment on success
-        if let Err(e) = address
-            .update_storage_used(size, false, &mut db_client)
-            .await
-        {
-            let msg = e.to_string();
-            log::error!("{}", msg);
-            return Err(warp::reject::custom(Error::from(e)));
+        if !is_staged {
+            stored.push((index, size));
         }
 
-        // Finally, update the cache
-        if entry.attachments_processed.len() + 1 < email.num_attachments as usize {
+        for (i, stored_size) in &stored {
+            db_client
+                .insert_attachment(email, *i, *stored_size, true, None)
+                .await;
+        }
+
+        // Update used storage for these attachments on success
+        if !stored.is_empty() {
+            let stored_size = stored.iter().map(|(_, s)| s).sum();
+
+            if let Err(e) = address
+                .update_storage_used(stored_size, false, &mut db_client)
+                .await
+            {
+                let msg = e.to_string();
+                log::error!("{}", msg);
+                return Err(warp::reject::custom(Error::from(e)));
+            }
+        }
+
+        // Record shared links, if the address has them enabled. Storing
+        // staged attachments creates links for the earlier attachments of the
+        // email as well, so links are matched up from this one.
+        let links = handler.take_shared_links();
+
+        if links.iter().any(Option::is_some) {
+            let mut indices = entry.attachments_processed.clone();
+            indices.push(index);
+
+            let indices = indices.split_off(indices.len().saturating_sub(links.len()));
+            let mut shared_links = Vec::new();
+
+            for (i, link) in indices.into_iter().zip(links) {
+                if let Some(link) = link {
+                    if let Err(e) = db_client
+                        .update_attachment_shared_link(email, i, &link)
+                        .await
+                    {
+                        log::error!("Failed to save shared link: {}", e);
+                    }
+
+                    shared_links.push(link);
+                }
+            }
+
+            result.shared_links = Some(shared_links);
+        }
+
+        // Finally, update the cache. The entry is kept for the original
+        // message if it is still to come.
+        if !is_last || entry.wants_message {
             // Update the cache entry
             let mut lock = MAIL_CACHE.write().await;
             let entry = lock.get_mut(&mail_id).unwrap();
             entry.attachments_processed.push(index);
+
+            if is_staged {
+                entry.attachments_staged.push((index, size));
+            }
         } else {
             // If this is the last attachment for this email, cleanup the cache
             // entry.
@@ -354,10 +569,116 @@ pub mod postfix {
 
             // Send back a JSON result to the client containing all info
             result.storage_backend = Some(address.storage_backend.clone());
+            result.storage_backend_name =
+                Some(registry.display_name(&address.storage_backend).to_string());
             result.num_attachments = Some(email.num_attachments as i32);
         }
 
-        resp
+        Ok(warp::reply::json(&result))
+    }
+
+    /// Handles the original RFC 822 message for destinations that store
+    /// whole messages (e.g., IMAP). Attachments are part of the message, so
+    /// these destinations are not sent them separately. The message comes
+    /// after the attachments for the other destinations, if any.
+    pub async fn message(
+        size: usize,
+        mail_id: String,
+        body: impl Stream<Item = Result<impl Buf, warp::Error>> + Send + Sync + 'static,
+        mut db: sqlx::PgPool,
+        registry: Arc<Registry>,
+    ) -> Result<impl Reply, Rejection> {
+        let mut result = vaulty::api::ServerResult {
+            success: true,
+            ..Default::default()
+        };
+
+        let mut db_client = vaulty::db::Client::new(&mut db);
+
+        let entry = MAIL_CACHE.read().await.get(&mail_id).cloned();
+
+        // Either the message was already processed, or we never saw the email
+        let entry = match entry {
+            Some(entry) => entry,
+            None => {
+                let msg = format!("No entry found for message (mail_id: {})", mail_id);
+                let err = Error(vaulty::Error::Generic(msg));
+                return Err(warp::reject::custom(err));
+            }
+        };
+
+        let email = &entry.email;
+        let address = &entry.address;
+        let recipient = &email.recipients[0];
+
+        log::info!(
+            "Got message for recipient: {}, Size: {}, UUID: {}",
+            recipient,
+            size,
+            mail_id
+        );
+
+        // Only the body was counted against the quota so far
+        let is_quota_exceeded = (address.storage_used + size as i64) > address.storage_quota;
+        if is_quota_exceeded {
+            let msg = format!(
+                "Address {} has hit its quota of {} MB for this period.",
+                recipient,
+                (address.storage_quota / 1_000_000)
+            );
+
+            log::warn!("{}", msg);
+
+            db_client
+                .log(&msg, Some(&email.uuid), LogLevel::Warning)
+                .await;
+
+            db_client.update_email(email, false, Some(&msg)).await;
+
+            let err = Error(vaulty::Error::QuotaExceeded(msg));
+            return Err(warp::reject::custom(err));
+        }
+
+        let destinations = address.all_destinations();
+        let handler = vaulty::EmailHandler::new(&registry, &destinations, address.delivery_policy)
+            .with_email_states(&entry.email_states);
+
+        let message = body
+            .map_ok(|mut b| b.to_bytes())
+            .map_err(|e| vaulty::Error::Generic(e.to_string()));
+
+        let name = format!("{}.eml", mail_id);
+
+        let h = handler.handle_message(email, message, name, size).await;
+
+        save_client_states(&handler, &mail_id, &address.address, &mut db_client).await;
+        result.destinations = Some(handler.take_destination_results());
+
+        if let Err(e) = h {
+            let msg = e.to_string();
+            db_client.update_email(email, false, Some(&msg)).await;
+
+            return Err(warp::reject::custom(Error::from(e)));
+        }
+
+        if let Err(e) = address
+            .update_storage_used(size, false, &mut db_client)
+            .await
+        {
+            let msg = e.to_string();
+            log::error!("{}", msg);
+            return Err(warp::reject::custom(Error::from(e)));
+        }
+
+        log::info!("Removing {} from cache", mail_id);
+        MAIL_CACHE.write().await.remove(&mail_id);
+
+        result.storage_backend = Some(address.storage_backend.clone());
+        result.storage_backend_name =
+            Some(registry.display_name(&address.storage_backend).to_string());
+        result.num_attachments = Some(email.num_attachments as i32);
+
+        Ok(warp::reply::json(&result))
     }
 }
 
@@ -389,8 +710,10 @@ pub mod monitor {
 pub async fn mailgun(
     content_type: Option<String>,
     body: String,
-    api_key: Option<String>,
+    registry: Arc<Registry>,
 ) -> Result<impl Reply, Rejection> {
+    let api_key = registry.config().mailgun_key.as_ref();
+
     if let None = content_type {
         return Err(warp::reject::not_found());
     }
@@ -437,13 +760,19 @@ pub async fn mailgun(
     }
 
     let mail: email::Email = mail.into();
-    let storage_backend: vaulty::storage::Backend = "dropbox".into();
+    let destinations = vec![vaulty::db::Destination {
+        id: None,
+        address_id: 0,
+        storage_token: "test123".to_string(),
+        storage_backend: vaulty::storage::Backend::try_from("dropbox").unwrap(),
+        storage_path: "/vaulty".to_string(),
+    }];
 
-    let handler = vaulty::EmailHandler::new("test123", &storage_backend, "/vaulty");
+    let handler = vaulty::EmailHandler::new(&registry, &destinations, Default::default());
 
     let attachment_tasks = attachments
         .into_iter()
-        .map(|a| a.fetch(api_key.as_ref()))
+        .map(|a| a.fetch(api_key))
         .collect::<FuturesUnordered<_>>()
         .map_ok(|a| email::Attachment::from(a))
         .map_err(|e| vaulty::Error::Generic(e.to_string()))
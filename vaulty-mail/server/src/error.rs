@@ -30,6 +30,12 @@ pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible>
             vaulty::Error::Database(_) => {
                 status_code = StatusCode::INTERNAL_SERVER_ERROR;
             }
+            // Storage errors the user can act on are reported back to them
+            vaulty::Error::Storage(vaulty::storage::Error::InsufficientSpace(_))
+            | vaulty::Error::Storage(vaulty::storage::Error::PathConflict(_))
+            | vaulty::Error::Storage(vaulty::storage::Error::MalformedPath(_)) => {
+                status_code = StatusCode::UNPROCESSABLE_ENTITY;
+            }
             vaulty::Error::Storage(_) => {
                 status_code = StatusCode::INTERNAL_SERVER_ERROR;
             }
@@ -6,6 +6,7 @@ use super::error;
 use super::routes;
 
 use vaulty::config::Config;
+use vaulty::storage::Registry;
 
 pub async fn get_db_pool(config: &Config) -> sqlx::PgPool {
     let db_host = &config.db_host;
@@ -29,11 +30,14 @@ pub async fn run(arg: Config) {
     let pool = get_db_pool(&arg).await;
     log::info!("Connected to Postgres DB: {}/{}", arg.db_host, arg.db_name);
 
+    // Storage clients are built from the config by the registry
+    let registry = Arc::new(Registry::new(arg.clone()));
+
     // Use Arc to share config across threads on server
     let config = Arc::new(arg);
 
-    let mailgun = routes::mailgun(config.clone());
-    let postfix = routes::postfix(pool.clone(), config.clone());
+    let mailgun = routes::mailgun(registry.clone());
+    let postfix = routes::postfix(pool.clone(), config.clone(), registry.clone());
     let monitor = routes::monitor(pool.clone(), config.clone());
     let index = routes::index();
 
@@ -6,6 +6,7 @@ use super::controllers;
 use super::filters;
 
 use vaulty::config::Config;
+use vaulty::storage::Registry;
 
 pub fn index() -> impl Filter<Extract = (&'static str,), Error = Rejection> + Clone {
     // GET /hello/warp => 200 OK with body "Hello, warp!"
@@ -16,8 +17,11 @@ pub fn index() -> impl Filter<Extract = (&'static str,), Error = Rejection> + Cl
 pub fn postfix(
     db: sqlx::PgPool,
     config: Arc<Config>,
+    registry: Arc<Registry>,
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
-    email(db.clone(), config.clone()).or(attachment(db.clone(), config.clone()))
+    email(db.clone(), config.clone(), registry.clone())
+        .or(attachment(db.clone(), config.clone(), registry.clone()))
+        .or(message(db.clone(), config.clone(), registry.clone()))
 }
 
 /// Route for /postfix/email
@@ -25,13 +29,14 @@ pub fn postfix(
 pub fn email(
     db: sqlx::PgPool,
     config: Arc<Config>,
+    registry: Arc<Registry>,
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::path!("postfix" / "email")
         .and(warp::path::end())
         .and(warp::body::content_length_limit(config.max_email_size))
-        .and(filters::basic_auth(config))
+        .and(filters::basic_auth(config.clone()))
         .and(warp::body::json())
-        .and_then(move |email| controllers::postfix::email(email, db.clone()))
+        .and_then(move |email| controllers::postfix::email(email, db.clone(), registry.clone()))
 }
 
 /// Route for /postfix/attachment
@@ -39,11 +44,12 @@ pub fn email(
 pub fn attachment(
     db: sqlx::PgPool,
     config: Arc<Config>,
+    registry: Arc<Registry>,
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::path!("postfix" / "attachment")
         .and(warp::path::end())
         .and(warp::body::content_length_limit(config.max_attachment_size))
-        .and(filters::basic_auth(config))
+        .and(filters::basic_auth(config.clone()))
         .and(warp::filters::header::header::<usize>(
             header::CONTENT_LENGTH.as_str(),
         ))
@@ -69,10 +75,34 @@ pub fn attachment(
                 index,
                 body,
                 db.clone(),
+                registry.clone(),
             )
         })
 }
 
+/// Route for /postfix/message
+/// Handles the original message for addresses that archive whole messages
+pub fn message(
+    db: sqlx::PgPool,
+    config: Arc<Config>,
+    registry: Arc<Registry>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("postfix" / "message")
+        .and(warp::path::end())
+        .and(warp::body::content_length_limit(config.max_email_size))
+        .and(filters::basic_auth(config.clone()))
+        .and(warp::filters::header::header::<usize>(
+            header::CONTENT_LENGTH.as_str(),
+        ))
+        .and(warp::filters::header::header::<String>(
+            vaulty::constants::VAULTY_EMAIL_ID,
+        ))
+        .and(warp::filters::body::stream())
+        .and_then(move |size, mail_id, body| {
+            controllers::postfix::message(size, mail_id, body, db.clone(), registry.clone())
+        })
+}
+
 /// Route for /monitor
 pub fn monitor(
     db: sqlx::PgPool,
@@ -93,7 +123,7 @@ pub fn cache(
 
 /// Handles mail notifications from Mailgun
 pub fn mailgun(
-    config: Arc<Config>,
+    registry: Arc<Registry>,
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::path("mailgun")
         .and(warp::path::end())
@@ -109,6 +139,6 @@ pub fn mailgun(
             }),
         )
         .and_then(move |content_type, body| {
-            controllers::mailgun(content_type, body, config.mailgun_key.clone())
+            controllers::mailgun(content_type, body, registry.clone())
         })
 }
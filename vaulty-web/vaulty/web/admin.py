@@ -1,7 +1,9 @@
 from django.contrib import admin
 from django.contrib.auth.admin import UserAdmin
 
-from .models import Address, Alias, Attachment, Mail, User, LaunchMailingList
+from .models import (
+    Address, Alias, Attachment, Destination, Mail, User, LaunchMailingList,
+)
 
 
 class AddressAdmin(admin.ModelAdmin):
@@ -14,6 +16,12 @@ class AddressAdmin(admin.ModelAdmin):
     list_filter = ("is_active", "is_whitelist_enabled")
 
 
+class DestinationAdmin(admin.ModelAdmin):
+    list_display = (
+        "address", "storage_backend", "storage_path", "creation_time",
+    )
+
+
 class MailAdmin(admin.ModelAdmin):
     list_display = (
         "user", "address", "message_id", "num_attachments",
@@ -46,6 +54,7 @@ class LaunchMailingListAdmin(admin.ModelAdmin):
 # Register models in admin
 admin.site.register(User, UserAdmin)
 admin.site.register(Address, AddressAdmin)
+admin.site.register(Destination, DestinationAdmin)
 admin.site.register(Mail, MailAdmin)
 admin.site.register(Attachment, AttachmentAdmin)
 admin.site.register(Alias, AliasAdmin)
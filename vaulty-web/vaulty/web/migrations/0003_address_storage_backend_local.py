@@ -0,0 +1,18 @@
+# Generated by Django 3.0.3 on 2020-06-14 18:21
+
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0002_create_superuser'),
+    ]
+
+    operations = [
+        migrations.AlterField(
+            model_name='address',
+            name='storage_backend',
+            field=models.CharField(choices=[('dropbox', 'Dropbox'), ('gdrive', 'Gdrive'), ('s3', 'S3'), ('local', 'Local')], max_length=30),
+        ),
+    ]
@@ -0,0 +1,18 @@
+# Generated by Django 3.0.3 on 2020-06-20 15:02
+
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0003_address_storage_backend_local'),
+    ]
+
+    operations = [
+        migrations.AlterField(
+            model_name='address',
+            name='storage_backend',
+            field=models.CharField(choices=[('dropbox', 'Dropbox'), ('gdrive', 'Gdrive'), ('s3', 'S3'), ('local', 'Local'), ('webdav', 'Webdav')], max_length=30),
+        ),
+    ]
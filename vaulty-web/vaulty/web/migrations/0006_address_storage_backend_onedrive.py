@@ -0,0 +1,18 @@
+# Generated by Django 3.0.3 on 2020-07-04 09:12
+
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0005_address_storage_backend_sftp'),
+    ]
+
+    operations = [
+        migrations.AlterField(
+            model_name='address',
+            name='storage_backend',
+            field=models.CharField(choices=[('dropbox', 'Dropbox'), ('gdrive', 'Gdrive'), ('s3', 'S3'), ('local', 'Local'), ('webdav', 'Webdav'), ('sftp', 'Sftp'), ('onedrive', 'Onedrive')], max_length=30),
+        ),
+    ]
@@ -0,0 +1,18 @@
+# Generated by Django 3.0.3 on 2020-07-25 14:37
+
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0008_address_storage_backend_git'),
+    ]
+
+    operations = [
+        migrations.AlterField(
+            model_name='address',
+            name='storage_backend',
+            field=models.CharField(choices=[('dropbox', 'Dropbox'), ('gdrive', 'Gdrive'), ('s3', 'S3'), ('local', 'Local'), ('webdav', 'Webdav'), ('sftp', 'Sftp'), ('onedrive', 'Onedrive'), ('azure', 'Azure'), ('git', 'Git'), ('imap', 'Imap')], max_length=30),
+        ),
+    ]
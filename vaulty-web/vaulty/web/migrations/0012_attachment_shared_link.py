@@ -0,0 +1,18 @@
+# Generated by Django 3.0.3 on 2020-08-09 11:05
+
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0011_address_storage_backend_notion'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='attachment',
+            name='shared_link',
+            field=models.URLField(max_length=500, null=True),
+        ),
+    ]
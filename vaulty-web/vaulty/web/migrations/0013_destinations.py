@@ -0,0 +1,34 @@
+# Generated by Django 3.0.3 on 2020-08-16 10:42
+
+from django.db import migrations, models
+import django.db.models.deletion
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0012_attachment_shared_link'),
+    ]
+
+    operations = [
+        migrations.AddField(
+            model_name='address',
+            name='delivery_policy',
+            field=models.CharField(choices=[('all', 'All'), ('any', 'Any'), ('primary', 'Primary')], default='all', max_length=10),
+        ),
+        migrations.CreateModel(
+            name='Destination',
+            fields=[
+                ('id', models.AutoField(auto_created=True, primary_key=True, serialize=False, verbose_name='ID')),
+                ('storage_backend', models.CharField(choices=[('dropbox', 'Dropbox'), ('gdrive', 'Gdrive'), ('s3', 'S3'), ('local', 'Local'), ('webdav', 'Webdav'), ('sftp', 'Sftp'), ('onedrive', 'Onedrive'), ('azure', 'Azure'), ('git', 'Git'), ('imap', 'Imap'), ('paperless', 'Paperless'), ('notion', 'Notion')], max_length=30)),
+                ('storage_token', models.TextField()),
+                ('storage_path', models.CharField(max_length=1000)),
+                ('last_update_time', models.DateTimeField(auto_now=True)),
+                ('creation_time', models.DateTimeField(auto_now_add=True)),
+                ('address', models.ForeignKey(on_delete=django.db.models.deletion.CASCADE, to='web.Address')),
+            ],
+            options={
+                'db_table': 'vaulty_destinations',
+            },
+        ),
+    ]
@@ -0,0 +1,18 @@
+# Generated by Django 3.0.3 on 2020-08-18 09:27
+
+from django.db import migrations, models
+
+
+class Migration(migrations.Migration):
+
+    dependencies = [
+        ('web', '0013_destinations'),
+    ]
+
+    operations = [
+        migrations.AlterField(
+            model_name='address',
+            name='storage_token',
+            field=models.TextField(),
+        ),
+    ]
@@ -28,6 +28,15 @@ class Address(models.Model):
         DROPBOX = 'dropbox'
         GDRIVE = 'gdrive'
         S3 = 's3'
+        LOCAL = 'local'
+        WEBDAV = 'webdav'
+        SFTP = 'sftp'
+        ONEDRIVE = 'onedrive'
+        AZURE = 'azure'
+        GIT = 'git'
+        IMAP = 'imap'
+        PAPERLESS = 'paperless'
+        NOTION = 'notion'
 
     # TODO: Do we want this to cascade instead?
     user = models.ForeignKey(User, models.SET_NULL, null=True)
@@ -50,7 +59,8 @@ class Address(models.Model):
     storage_used = models.BigIntegerField(default=0)
     last_renewal_time = models.DateTimeField()
     storage_backend = models.CharField(max_length=30, choices=StorageBackend.choices)
-    storage_token = models.CharField(max_length=1000)
+    # Can hold a JSON document with keys (e.g., SFTP), so no length limit
+    storage_token = models.TextField()
 
     # Path to store data (in valid backend format)
     storage_path = models.CharField(max_length=1000)
@@ -59,6 +69,40 @@ class Address(models.Model):
     is_whitelist_enabled = models.BooleanField()
     whitelist = ArrayField(models.CharField(max_length=512))
 
+    class DeliveryPolicy(models.TextChoices):
+        # Every destination must succeed
+        ALL = 'all'
+        # At least one destination must succeed
+        ANY = 'any'
+        # Only the storage of the address itself must succeed
+        PRIMARY = 'primary'
+
+    # When an email counts as delivered, if it has other destinations
+    delivery_policy = models.CharField(
+        max_length=10,
+        choices=DeliveryPolicy.choices,
+        default=DeliveryPolicy.ALL,
+    )
+
+    last_update_time = models.DateTimeField(auto_now=True)
+    creation_time = models.DateTimeField(auto_now_add=True)
+
+
+class Destination(models.Model):
+    """Other storage that receives the attachments of an address."""
+    class Meta:
+        db_table = "vaulty_destinations"
+
+    address = models.ForeignKey(Address, models.CASCADE)
+    storage_backend = models.CharField(
+        max_length=30,
+        choices=Address.StorageBackend.choices,
+    )
+    storage_token = models.TextField()
+
+    # Path to store data (in valid backend format)
+    storage_path = models.CharField(max_length=1000)
+
     last_update_time = models.DateTimeField(auto_now=True)
     creation_time = models.DateTimeField(auto_now_add=True)
 
@@ -90,6 +134,7 @@ class Attachment(models.Model):
     size = models.IntegerField()
     status = models.BooleanField(default=True)
     error_msg = models.TextField(null=True)
+    shared_link = models.URLField(max_length=500, null=True)
     creation_time = models.DateTimeField(auto_now_add=True)
 
 